@@ -0,0 +1,30 @@
+querying = Querying { $title }
+found = Found { $season }
+unmatched = Unmatched { $title } ({ $reason })
+applying = Applying { $title }
+elements-read = { $count } elements read
+run-summary = { $matched } matched, { $marked } episodes marked, { $unmatched } unmatched, { $failures } failures
+retry-nothing = Nothing to retry
+retry-attempt = Retrying { $content } of { $title } (attempt { $attempt })
+retry-known-missing = Skipping { $title }, known to be missing from Crunchyroll
+retry-summary = { $retried } marks retried, { $failing } still failing
+mappings-imported = { $count } mappings imported
+env-missing = '{ $name }' environment variable not found
+offline-unsupported = This command can't run offline
+anonymous-unsupported = This command needs a Crunchyroll login
+anonymous-session = Browsing Crunchyroll anonymously, without logging in
+credential-prompt = { $name } isn't set, enter it
+credential-save = Save it to the keyring? [y/N]
+history-empty = No run recorded yet
+diff-newly-matched = Newly matched
+diff-changed = Matched with another season
+diff-newly-marked = Newly marked
+diff-regressed = Regressed
+diff-none = No difference
+exclude-added = { $id } excluded in { $path }
+exclude-present = { $id } is already excluded
+exclude-removed = { $id } no longer excluded
+exclude-absent = { $id } wasn't excluded
+watch-started = Watching the MAL list, polled every { $interval }s
+watch-scheduled = Watching the MAL list, synced on the schedule { $schedule }
+phase-times = Time spent: { $times }
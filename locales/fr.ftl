@@ -0,0 +1,30 @@
+querying = Recherche de { $title }
+found = Trouvé : { $season }
+unmatched = Sans correspondance : { $title } ({ $reason })
+applying = Application de { $title }
+elements-read = { $count } éléments lus
+run-summary = { $matched } trouvés, { $marked } épisodes marqués, { $unmatched } sans correspondance, { $failures } échecs
+retry-nothing = Rien à réessayer
+retry-attempt = Nouvel essai de { $content } de { $title } (tentative { $attempt })
+retry-known-missing = { $title } ignoré, absent de Crunchyroll
+retry-summary = { $retried } marquages réessayés, { $failing } toujours en échec
+mappings-imported = { $count } correspondances importées
+env-missing = La variable d'environnement '{ $name }' est absente
+offline-unsupported = Cette commande ne fonctionne pas hors ligne
+anonymous-unsupported = Cette commande nécessite une connexion à Crunchyroll
+anonymous-session = Navigation anonyme sur Crunchyroll, sans connexion
+credential-prompt = { $name } n'est pas définie, saisissez-la
+credential-save = L'enregistrer dans le trousseau ? [y/N]
+history-empty = Aucune exécution enregistrée
+diff-newly-matched = Nouvelles correspondances
+diff-changed = Associés à une autre saison
+diff-newly-marked = Nouveaux marquages
+diff-regressed = Régressions
+diff-none = Aucune différence
+exclude-added = { $id } exclu dans { $path }
+exclude-present = { $id } est déjà exclu
+exclude-removed = { $id } n'est plus exclu
+exclude-absent = { $id } n'était pas exclu
+watch-started = Surveillance de la liste MAL, interrogée toutes les { $interval } s
+watch-scheduled = Surveillance de la liste MAL, synchronisée selon la planification { $schedule }
+phase-times = Temps passé : { $times }
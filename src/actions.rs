@@ -0,0 +1,85 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::state::state_dir;
+use crate::warn;
+
+const ACTIONS_FILE: &str = "actions.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    Mark,
+    WatchlistAdd,
+    WatchlistRemove,
+}
+
+/// One line of the action log
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ActionRecord {
+    pub timestamp: DateTime<Utc>,
+    pub action: ActionKind,
+    pub content_id: String,
+    pub mal_id: Option<u32>,
+    pub success: bool,
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// HTTP status carried by an error, when it comes from a request
+pub fn http_status(e: &anyhow::Error) -> Option<u16> {
+    e.chain()
+        .find_map(|x| x.downcast_ref::<reqwest::Error>())
+        .and_then(|x| x.status())
+        .map(|x| x.as_u16())
+}
+
+/// Append-only JSONL log of every change made to the account
+pub struct ActionLog {
+    file: Mutex<File>,
+}
+
+impl ActionLog {
+    pub fn open() -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(state_dir()?.join(ACTIONS_FILE))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record<T>(
+        &self,
+        action: ActionKind,
+        content_id: &str,
+        mal_id: Option<u32>,
+        res: &Result<T>,
+        success_status: Option<u16>,
+    ) {
+        let record = ActionRecord {
+            timestamp: Utc::now(),
+            action,
+            content_id: content_id.to_string(),
+            mal_id,
+            success: res.is_ok(),
+            http_status: match res {
+                Ok(_) => success_status,
+                Err(e) => http_status(e),
+            },
+            error: res.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+
+        let res = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.file.lock().unwrap(), "{}", line)?));
+        if let Err(e) = res {
+            warn!("Could not write the action log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::mal::{self, read_mal_entries};
+use crate::matcher::{FailureReason, MatchOutcome, Matcher};
+use crate::{error, info};
+
+/// Lists the entries of the MAL list missing from Crunchyroll
+pub async fn report(catalog: &Catalog<'_>, config: &Config) -> Result<String> {
+    let mut matcher = Matcher::new(catalog, config).await?;
+    let mut treated_ids = HashSet::<String>::new();
+
+    let mut missing = Vec::<(String, FailureReason)>::new();
+    let mut known_missing = Vec::<String>::new();
+    let mut failed = Vec::<(String, FailureReason, String)>::new();
+
+    for elt in read_mal_entries(true, config.filters.nsfw).await? {
+        let nsfw = mal::is_nsfw(&elt.node);
+        let mal_entry = matcher.entry(elt.node);
+        let title = mal_entry.title.clone();
+
+        // Tagged so that the reports can be shared without them
+        let tagged = match nsfw {
+            true => format!("{} [NSFW]", title),
+            false => title.clone(),
+        };
+        if matcher.is_known_missing(&mal_entry) {
+            known_missing.push(tagged);
+            continue;
+        }
+
+        info!("Querying {}", &title);
+        let reason = match matcher.find(&mal_entry, &treated_ids).await {
+            Ok(MatchOutcome::Matched { season, .. }) => {
+                treated_ids.insert(season.id);
+                continue;
+            }
+            Ok(MatchOutcome::Unmatched { reason, .. }) => reason,
+            Err(e) => {
+                error!("{}: {:#}", &title, e);
+                FailureReason::ApiError
+            }
+        };
+
+        let title = tagged;
+        match matcher.plausible_series(&mal_entry.title).await {
+            Ok(Some(series)) => failed.push((title, reason, series.title)),
+            Ok(None) => missing.push((title, reason)),
+            Err(_) => missing.push((title, FailureReason::ApiError)),
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "# Not on Crunchyroll ({})", missing.len())?;
+    for (title, reason) in missing {
+        writeln!(out, "{} [{}]", title, reason.name())?;
+    }
+    writeln!(out)?;
+    writeln!(out, "# Matching failed ({})", failed.len())?;
+    for (title, reason, candidate) in failed {
+        writeln!(
+            out,
+            "{} [{}] (closest: {})",
+            title,
+            reason.name(),
+            candidate
+        )?;
+    }
+    writeln!(out)?;
+    writeln!(
+        out,
+        "# Known missing, not searched again ({})",
+        known_missing.len()
+    )?;
+    for title in known_missing {
+        writeln!(out, "{}", title)?;
+    }
+    matcher.save_mappings()?;
+
+    Ok(out)
+}
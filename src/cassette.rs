@@ -0,0 +1,34 @@
+use anyhow::Result;
+use mal_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::catalog::CatalogCache;
+use crate::http::Exchange;
+use crate::info;
+
+/// Every MAL and Crunchyroll answer a run relied on, so the
+/// matching can be replayed later (e.g. from a user's bug report)
+#[derive(Serialize, Deserialize, Default)]
+pub struct Cassette {
+    pub mal_list: Vec<AnimeListNode>,
+    /// What crunchyroll-rs fetched, its requests bypass `http::send`
+    pub catalog: CatalogCache,
+    /// Every request sent through `http::send`: the MAL reads, the
+    /// relations, the marks...
+    #[serde(default)]
+    pub requests: Vec<Exchange>,
+}
+
+impl Cassette {
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        info!("Run recorded to {}", path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
+use crunchyroll_rs::common::StreamExt;
+use crunchyroll_rs::search::BrowseOptions;
+use crunchyroll_rs::{Crunchyroll, Episode, MediaCollection, Season, Series};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::profile;
+use crate::state::{self, Persisted};
+use crate::{info, warn};
+
+/// Number of series kept from every search
+const SEARCH_RESULTS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeriesInfo {
+    pub id: String,
+    pub title: String,
+    /// Title of the URL, often the plain romaji of stylized titles,
+    /// e.g. "kono-subarashii-sekai-ni-shukufuku-wo"
+    #[serde(default)]
+    pub slug: String,
+}
+
+impl SeriesInfo {
+    /// Display title and slug words, as compared with the MAL titles
+    pub fn titles(&self) -> Vec<String> {
+        let mut output = vec![self.title.clone()];
+        if !self.slug.is_empty() {
+            output.push(self.slug.replace('-', " "));
+        }
+        output
+    }
+}
+
+impl From<&Series> for SeriesInfo {
+    fn from(x: &Series) -> Self {
+        Self {
+            id: x.id.clone(),
+            title: x.title.clone(),
+            slug: x.slug_title.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeasonInfo {
+    pub id: String,
+    pub series_id: String,
+    pub title: String,
+    pub season_number: u32,
+    pub number_of_episodes: u32,
+    #[serde(default)]
+    pub audio_locales: Vec<String>,
+    /// The same season in the other audio languages
+    #[serde(default)]
+    pub versions: Vec<SeasonVersionInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeasonVersionInfo {
+    /// Season ID of the version
+    pub id: String,
+    pub audio_locale: String,
+    pub original: bool,
+}
+
+impl From<&Season> for SeasonInfo {
+    fn from(x: &Season) -> Self {
+        Self {
+            id: x.id.clone(),
+            series_id: x.series_id.clone(),
+            title: x.title.clone(),
+            season_number: x.season_number,
+            number_of_episodes: x.number_of_episodes,
+            audio_locales: x.audio_locales.iter().map(|x| x.to_string()).collect(),
+            versions: x
+                .versions
+                .iter()
+                .map(|x| SeasonVersionInfo {
+                    id: x.id.clone(),
+                    audio_locale: x.audio_locale.to_string(),
+                    original: x.original,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EpisodeInfo {
+    pub id: String,
+    pub title: String,
+    pub episode_number: Option<u32>,
+    /// When premium members could first watch it, the original air
+    /// date when Crunchyroll doesn't tell
+    pub air_date: DateTime<Utc>,
+    /// In seconds, 0 in the caches predating it
+    #[serde(default)]
+    pub duration: u32,
+}
+
+/// Crunchyroll leaves the dates it doesn't know at the epoch, or
+/// far in the future
+fn known_date(date: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    (1970 < date.year() && date.year() < 9000).then_some(date)
+}
+
+impl From<&Episode> for EpisodeInfo {
+    fn from(x: &Episode) -> Self {
+        Self {
+            id: x.id.clone(),
+            title: x.title.clone(),
+            episode_number: x.episode_number,
+            air_date: known_date(x.premium_available_date).unwrap_or(x.episode_air_date),
+            duration: x.duration.num_seconds() as u32,
+        }
+    }
+}
+
+/// Everything fetched from Crunchyroll during the previous runs
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct CatalogCache {
+    searches: BTreeMap<String, Vec<SeriesInfo>>,
+    series: BTreeMap<String, SeriesInfo>,
+    seasons: BTreeMap<String, SeasonInfo>,
+    series_seasons: BTreeMap<String, Vec<String>>,
+    episodes: BTreeMap<String, Vec<EpisodeInfo>>,
+    /// Series of the simulcast seasons, e.g. "fall-2023"
+    #[serde(default)]
+    simulcasts: BTreeMap<String, Vec<SeriesInfo>>,
+    /// End of the last `catalog refresh`
+    #[serde(default)]
+    refreshed_at: Option<DateTime<Utc>>,
+}
+
+impl Persisted for CatalogCache {
+    const FILE_NAME: &'static str = "catalog.json";
+    const CACHE: bool = true;
+}
+
+/// Crunchyroll metadata access. Online, every answer is fetched
+/// and written to the cache; offline, only the cache is read.
+pub struct Catalog<'a> {
+    crunchyroll: Option<&'a Crunchyroll>,
+    cache: Mutex<CatalogCache>,
+    // Recording and replaying runs leave the state untouched
+    persist: bool,
+    // Objects fetched during this run, needed to query their children
+    live_series: Mutex<HashMap<String, Series>>,
+    live_seasons: Mutex<HashMap<String, Season>>,
+    // Searches, series and seasons whose children were fetched during
+    // this run (or are in a fresh snapshot), answered from the cache
+    fetched_searches: Mutex<HashSet<String>>,
+    fetched_seasons: Mutex<HashSet<String>>,
+    fetched_episodes: Mutex<HashSet<String>>,
+    fetched_simulcasts: Mutex<HashSet<String>>,
+    use_snapshot: bool,
+}
+
+impl<'a> Catalog<'a> {
+    /// Online, matching against the snapshot of the last `catalog refresh`
+    /// when it is younger than `max_age`
+    pub fn online(crunchyroll: &'a Crunchyroll, max_age: TimeDelta) -> Result<Self> {
+        let mut output = Self::new(Some(crunchyroll), state::load()?, true);
+        let refreshed_at = output.cache.lock().unwrap().refreshed_at;
+        if let Some(x) = refreshed_at.filter(|x| Utc::now() - *x < max_age) {
+            info!(
+                "Using the catalog snapshot of {}",
+                x.format("%Y-%m-%d %H:%M")
+            );
+            output.use_snapshot();
+        }
+        Ok(output)
+    }
+
+    /// Online, ignoring the snapshot to fetch everything again
+    pub fn refreshing(crunchyroll: &'a Crunchyroll) -> Result<Self> {
+        Ok(Self::new(Some(crunchyroll), state::load()?, true))
+    }
+
+    pub fn offline() -> Result<Self> {
+        Ok(Self::new(None, state::load()?, true))
+    }
+
+    /// Online, starting from an empty cache to capture what the run uses
+    pub fn recording(crunchyroll: &'a Crunchyroll) -> Self {
+        Self::new(Some(crunchyroll), CatalogCache::default(), false)
+    }
+
+    /// Offline, answering from a recorded cache
+    pub fn replaying(cache: CatalogCache) -> Self {
+        Self::new(None, cache, false)
+    }
+
+    fn new(crunchyroll: Option<&'a Crunchyroll>, cache: CatalogCache, persist: bool) -> Self {
+        Self {
+            crunchyroll,
+            cache: Mutex::new(cache),
+            persist,
+            live_series: Mutex::new(HashMap::new()),
+            live_seasons: Mutex::new(HashMap::new()),
+            fetched_searches: Mutex::new(HashSet::new()),
+            fetched_seasons: Mutex::new(HashSet::new()),
+            fetched_episodes: Mutex::new(HashSet::new()),
+            fetched_simulcasts: Mutex::new(HashSet::new()),
+            use_snapshot: false,
+        }
+    }
+
+    fn use_snapshot(&mut self) {
+        let cache = self.cache.get_mut().unwrap();
+        self.fetched_searches
+            .get_mut()
+            .unwrap()
+            .extend(cache.searches.keys().cloned());
+        self.fetched_seasons
+            .get_mut()
+            .unwrap()
+            .extend(cache.series_seasons.keys().cloned());
+        self.fetched_episodes
+            .get_mut()
+            .unwrap()
+            .extend(cache.episodes.keys().cloned());
+        self.fetched_simulcasts
+            .get_mut()
+            .unwrap()
+            .extend(cache.simulcasts.keys().cloned());
+        self.use_snapshot = true;
+    }
+
+    /// Records the end of a `catalog refresh`
+    pub fn mark_refreshed(&self) {
+        self.cache.lock().unwrap().refreshed_at = Some(Utc::now());
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if !self.persist {
+            return Ok(());
+        }
+        state::save(&*self.cache.lock().unwrap())
+    }
+
+    pub fn snapshot(&self) -> CatalogCache {
+        self.cache.lock().unwrap().clone()
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.crunchyroll.is_none()
+    }
+
+    pub fn crunchyroll(&self) -> Result<&'a Crunchyroll> {
+        self.crunchyroll
+            .ok_or_else(|| anyhow!("Crunchyroll can't be reached in offline mode"))
+    }
+
+    pub async fn search(&self, title: &str) -> Result<Vec<SeriesInfo>> {
+        if self.fetched_searches.lock().unwrap().contains(title) {
+            if let Some(x) = self.cache.lock().unwrap().searches.get(title) {
+                return Ok(x.clone());
+            }
+        }
+        let Some(crunchyroll) = self.crunchyroll else {
+            let cache = self.cache.lock().unwrap();
+            return Ok(match cache.searches.get(title) {
+                Some(x) => x.clone(),
+                None => {
+                    warn!("'{}' has never been searched", title);
+                    vec![]
+                }
+            });
+        };
+
+        let mut query_result = crunchyroll.query(title);
+        let mut output = vec![];
+        while output.len() < SEARCH_RESULTS {
+            let Some(s) = profile::timed("search", query_result.series.next()).await else {
+                break;
+            };
+            let series = s?;
+            output.push(SeriesInfo::from(&series));
+            self.remember_series(series);
+        }
+
+        self.cache
+            .lock()
+            .unwrap()
+            .searches
+            .insert(title.to_string(), output.clone());
+        self.fetched_searches
+            .lock()
+            .unwrap()
+            .insert(title.to_string());
+        Ok(output)
+    }
+
+    /// Series of the first episode found for `title`,
+    /// nothing offline since the episodes searches aren't kept
+    pub async fn search_episode(&self, title: &str) -> Result<Option<SeriesInfo>> {
+        let Some(crunchyroll) = self.crunchyroll else {
+            return Ok(None);
+        };
+        let mut query_result = crunchyroll.query(title);
+        let Some(episode) = profile::timed("search", query_result.episode.next()).await else {
+            return Ok(None);
+        };
+        Ok(Some(self.series(&episode?.series_id).await?))
+    }
+
+    /// Series of a simulcast season, e.g. "fall-2023"
+    pub async fn simulcast(&self, id: &str) -> Result<Vec<SeriesInfo>> {
+        if self.fetched_simulcasts.lock().unwrap().contains(id) {
+            if let Some(x) = self.cache.lock().unwrap().simulcasts.get(id) {
+                return Ok(x.clone());
+            }
+        }
+        let Some(crunchyroll) = self.crunchyroll else {
+            let cache = self.cache.lock().unwrap();
+            return Ok(cache.simulcasts.get(id).cloned().unwrap_or_default());
+        };
+
+        let mut browse_result = crunchyroll.browse(BrowseOptions::default().seasonal_tag(id));
+        let mut output = vec![];
+        while let Some(x) = profile::timed("browse", browse_result.next()).await {
+            if let MediaCollection::Series(series) = x? {
+                output.push(SeriesInfo::from(&series));
+                self.remember_series(series);
+            }
+        }
+
+        self.cache
+            .lock()
+            .unwrap()
+            .simulcasts
+            .insert(id.to_string(), output.clone());
+        self.fetched_simulcasts
+            .lock()
+            .unwrap()
+            .insert(id.to_string());
+        Ok(output)
+    }
+
+    fn remember_series(&self, series: Series) {
+        self.cache
+            .lock()
+            .unwrap()
+            .series
+            .insert(series.id.clone(), SeriesInfo::from(&series));
+        self.live_series
+            .lock()
+            .unwrap()
+            .insert(series.id.clone(), series);
+    }
+
+    fn remember_season(&self, season: Season) {
+        self.cache
+            .lock()
+            .unwrap()
+            .seasons
+            .insert(season.id.clone(), SeasonInfo::from(&season));
+        self.live_seasons
+            .lock()
+            .unwrap()
+            .insert(season.id.clone(), season);
+    }
+
+    /// The actual Crunchyroll object, for the calls modifying the account
+    pub async fn live_series(&self, id: &str) -> Result<Series> {
+        if let Some(x) = self.live_series.lock().unwrap().get(id) {
+            return Ok(x.clone());
+        }
+        let series: Series =
+            profile::timed("series", self.crunchyroll()?.media_from_id(id)).await?;
+        self.remember_series(series.clone());
+        Ok(series)
+    }
+
+    async fn live_season(&self, id: &str) -> Result<Season> {
+        if let Some(x) = self.live_seasons.lock().unwrap().get(id) {
+            return Ok(x.clone());
+        }
+        let season: Season =
+            profile::timed("season", self.crunchyroll()?.media_from_id(id)).await?;
+        self.remember_season(season.clone());
+        Ok(season)
+    }
+
+    pub async fn series(&self, id: &str) -> Result<SeriesInfo> {
+        if self.use_snapshot {
+            if let Some(x) = self.cache.lock().unwrap().series.get(id) {
+                return Ok(x.clone());
+            }
+        }
+        if self.crunchyroll.is_none() {
+            return match self.cache.lock().unwrap().series.get(id) {
+                Some(x) => Ok(x.clone()),
+                None => bail!("Series {} isn't in the catalog cache", id),
+            };
+        }
+        Ok(SeriesInfo::from(&self.live_series(id).await?))
+    }
+
+    pub async fn season(&self, id: &str) -> Result<SeasonInfo> {
+        if self.use_snapshot {
+            if let Some(x) = self.cache.lock().unwrap().seasons.get(id) {
+                return Ok(x.clone());
+            }
+        }
+        if self.crunchyroll.is_none() {
+            return match self.cache.lock().unwrap().seasons.get(id) {
+                Some(x) => Ok(x.clone()),
+                None => bail!("Season {} isn't in the catalog cache", id),
+            };
+        }
+        Ok(SeasonInfo::from(&self.live_season(id).await?))
+    }
+
+    pub async fn seasons(&self, series_id: &str) -> Result<Vec<SeasonInfo>> {
+        if self.crunchyroll.is_none() || self.fetched_seasons.lock().unwrap().contains(series_id) {
+            let cache = self.cache.lock().unwrap();
+            let Some(ids) = cache.series_seasons.get(series_id) else {
+                bail!("The seasons of {} aren't in the catalog cache", series_id);
+            };
+            return Ok(ids
+                .iter()
+                .filter_map(|x| cache.seasons.get(x).cloned())
+                .collect());
+        }
+
+        let series = self.live_series(series_id).await?;
+        let seasons = profile::timed("seasons", series.seasons()).await?;
+        let output: Vec<SeasonInfo> = seasons.iter().map(SeasonInfo::from).collect();
+        self.cache.lock().unwrap().series_seasons.insert(
+            series_id.to_string(),
+            output.iter().map(|x| x.id.clone()).collect(),
+        );
+        for season in seasons {
+            self.remember_season(season);
+        }
+        self.fetched_seasons
+            .lock()
+            .unwrap()
+            .insert(series_id.to_string());
+        Ok(output)
+    }
+
+    pub async fn episodes(&self, season_id: &str) -> Result<Vec<EpisodeInfo>> {
+        if self.crunchyroll.is_none() || self.fetched_episodes.lock().unwrap().contains(season_id) {
+            return match self.cache.lock().unwrap().episodes.get(season_id) {
+                Some(x) => Ok(x.clone()),
+                None => bail!("The episodes of {} aren't in the catalog cache", season_id),
+            };
+        }
+
+        let season = self.live_season(season_id).await?;
+        let episodes = profile::timed("episodes", season.episodes()).await?;
+        let output: Vec<EpisodeInfo> = episodes.iter().map(EpisodeInfo::from).collect();
+        self.cache
+            .lock()
+            .unwrap()
+            .episodes
+            .insert(season_id.to_string(), output.clone());
+        self.fetched_episodes
+            .lock()
+            .unwrap()
+            .insert(season_id.to_string());
+        Ok(output)
+    }
+}
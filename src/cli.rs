@@ -0,0 +1,460 @@
+use anyhow::Result;
+use clap::builder::PossibleValuesParser;
+use clap::{Parser, Subcommand, ValueHint};
+use clap_complete::Shell;
+use crunchyroll_rs::Locale;
+use mal_2_crunchyroll::config::Config;
+use mal_2_crunchyroll::exit::EXIT_CODES_HELP;
+use mal_2_crunchyroll::history::Against;
+use mal_2_crunchyroll::import::RatingRounding;
+use mal_2_crunchyroll::mal::AiringStatus;
+use mal_2_crunchyroll::mapping::ExportFormat;
+use mal_2_crunchyroll::netflix;
+use mal_2_crunchyroll::source::{ColumnMap, WatchEntry};
+use mal_2_crunchyroll::sync::{Order, Since, SyncOptions};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::terminal::OutputFormat;
+
+/// Locales known to Crunchyroll, offered by the shell completions
+fn locales() -> PossibleValuesParser {
+    PossibleValuesParser::new(Locale::all().into_iter().map(|x| x.to_string()))
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Mirror your MyAnimeList progress onto Crunchyroll",
+    after_help = EXIT_CODES_HELP
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Run even if another instance currently holds the lock
+    #[arg(long)]
+    pub force: bool,
+
+    /// Only print the final summary
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Also print the details, such as the time taken by each entry
+    #[arg(short, long, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Print the logs without colors (also set by NO_COLOR)
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// What to print on stdout, the logs always going to stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// config.toml to read, overriding CONFIG_FILE and the
+    /// platform config directory
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+
+    /// Where the mappings, queues and tokens are kept,
+    /// overriding STATE_DIR
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub state_dir: Option<PathBuf>,
+
+    /// Where the catalog and list caches are kept,
+    /// overriding CACHE_DIR
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Also write every message to this file, overriding the configuration
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub log_file: Option<PathBuf>,
+
+    /// Print the requests made per endpoint and the time they took
+    #[arg(long)]
+    pub timings: bool,
+
+    /// Crunchyroll locale, overriding CLOCALE and the configuration
+    #[arg(long, value_parser = locales())]
+    pub locale: Option<String>,
+
+    /// Audio locales by order of preference, overriding
+    /// PREFERRED_AUDIO and the configuration
+    #[arg(long, value_parser = locales(), value_delimiter = ',')]
+    pub audio: Vec<String>,
+
+    /// Settings of config.toml to use, `default` when omitted
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Match against the cached catalog and list without any
+    /// network access, writing the marks to a plan file
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Browse Crunchyroll without logging in, for `match`, `plan`,
+    /// `availability` and `search`. The default for them when EMAIL
+    /// or PASSWORD is missing.
+    #[arg(long, conflicts_with = "offline")]
+    pub anonymous: bool,
+
+    /// Record the MAL and Crunchyroll answers of the run to a cassette
+    #[arg(long, value_hint = ValueHint::FilePath, conflicts_with_all = ["offline", "replay"])]
+    pub record: Option<PathBuf>,
+
+    /// Replay a recorded cassette offline instead of using the network
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    pub replay: Option<PathBuf>,
+
+    /// Plan file written by `plan` and the offline runs
+    #[arg(long, value_hint = ValueHint::FilePath, default_value = "plan.json")]
+    pub plan: PathBuf,
+
+    /// Put the closest Crunchyroll candidates of unmatched entries
+    /// in a "MAL unmatched" Crunchylist
+    #[arg(long)]
+    pub unmatched_crunchylist: bool,
+
+    /// Add every matched series to the Crunchyroll watchlist
+    #[arg(long)]
+    pub add_to_watchlist: bool,
+
+    /// Remove series from the Crunchyroll watchlist once they are
+    /// completed on MAL and every episode has been marked
+    #[arg(long)]
+    pub prune_watchlist: bool,
+
+    /// Leave out the first K entries of the list
+    #[arg(long, value_name = "K", default_value_t = 0)]
+    pub skip: usize,
+
+    /// Process at most N entries
+    #[arg(long, value_name = "N")]
+    pub limit: Option<usize>,
+
+    /// Only process the entries updated on MAL after a date
+    /// (YYYY-MM-DD) or after the last sync ("last-run")
+    #[arg(long, value_name = "DATE")]
+    pub since: Option<Since>,
+
+    /// Only process the entries tagged so on MAL (repeatable)
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+
+    /// Leave out the entries tagged so on MAL (repeatable)
+    #[arg(long = "exclude-tag", value_name = "TAG")]
+    pub exclude_tags: Vec<String>,
+
+    /// Only process the shows with this MAL genre or theme,
+    /// e.g. "Isekai" (repeatable)
+    #[arg(long = "genre", value_name = "GENRE")]
+    pub genres: Vec<String>,
+
+    /// Leave out the shows with this MAL genre or theme,
+    /// e.g. "Kids" (repeatable)
+    #[arg(long = "exclude-genre", value_name = "GENRE")]
+    pub exclude_genres: Vec<String>,
+
+    /// Only process the entries scored at least SCORE on MAL
+    #[arg(long, value_name = "SCORE", value_parser = clap::value_parser!(u8).range(1..=10))]
+    pub min_score: Option<u8>,
+
+    /// Only process the entries scored at most SCORE on MAL
+    #[arg(long, value_name = "SCORE", value_parser = clap::value_parser!(u8).range(0..=10))]
+    pub max_score: Option<u8>,
+
+    /// Leave out the entries MAL rates NSFW
+    #[arg(long)]
+    pub no_nsfw: bool,
+
+    /// Only process the shows currently airing on MAL, and the
+    /// ones that started last season
+    #[arg(long)]
+    pub airing_only: bool,
+
+    /// Only process the shows with these MAL airing statuses
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub airing_status: Vec<AiringStatus>,
+
+    /// Order in which the entries are processed
+    #[arg(long, value_enum, default_value_t = Order::StartDate)]
+    pub order: Order,
+
+    /// Seed of the shuffle order
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// Mark the viewing history exported from Netflix
+    /// instead of the MAL progress
+    #[arg(long, value_name = "CSV", value_hint = ValueHint::FilePath, conflicts_with_all = ["from_csv", "from_json"])]
+    pub from_netflix: Option<PathBuf>,
+
+    /// Mark the progress listed in a CSV file, see --map
+    #[arg(long, value_name = "CSV", value_hint = ValueHint::FilePath, conflicts_with = "from_json")]
+    pub from_csv: Option<PathBuf>,
+
+    /// Mark the progress listed in a JSON array of objects, see --map
+    #[arg(long, value_name = "JSON", value_hint = ValueHint::FilePath)]
+    pub from_json: Option<PathBuf>,
+
+    /// Columns of --from-csv/--from-json holding each field, e.g.
+    /// "title=Name,episodes=Progress,season=Season,date=Updated"
+    #[arg(long, default_value = "title=title,episodes=episodes")]
+    pub map: ColumnMap,
+
+    /// Fetch the MAL prequels of every entry, matching the Nth TV
+    /// series of a franchise with the Nth Crunchyroll season
+    #[arg(long)]
+    pub use_relations: bool,
+
+    /// Days tolerated between the MAL start date and the episode
+    /// air dates, overriding the configuration
+    #[arg(long, value_name = "DAYS")]
+    pub date_tolerance: Option<i64>,
+
+    /// Abort the sync once N marks or lookups failed, keeping
+    /// the matches found so far
+    #[arg(long, value_name = "N")]
+    pub max_errors: Option<usize>,
+
+    /// Abort on the first failed mark or MAL update, for the
+    /// scripts preferring no run to a partial one
+    #[arg(long, conflicts_with = "max_errors")]
+    pub fail_fast: bool,
+
+    /// Mark every watched episode again, rather than the ones
+    /// watched since the last successful sync
+    #[arg(long)]
+    pub no_delta: bool,
+
+    /// Stop the sync once N requests were made, the next runs with
+    /// this option carrying on where it stopped
+    #[arg(long, value_name = "N")]
+    pub api_budget: Option<usize>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Mirror MAL progress onto Crunchyroll (default)
+    Sync {
+        /// Only sync this MAL entry, whatever the filters (repeatable)
+        #[arg(long = "id", value_name = "MAL_ID")]
+        ids: Vec<u32>,
+    },
+    /// Keep polling the MAL list, syncing the entries as soon
+    /// as they are updated
+    Watch {
+        /// Seconds between two polls
+        #[arg(long, value_name = "SECS", default_value_t = 300)]
+        interval: u64,
+        /// Also sync when receiving `POST /trigger` on this address,
+        /// authenticated by TRIGGER_TOKEN
+        #[arg(long, value_name = "ADDR")]
+        listen: Option<SocketAddr>,
+    },
+    /// Bump MAL progress from the Crunchyroll watch history
+    ImportPlayheads,
+    /// Copy Crunchyroll star ratings to MAL scores
+    ImportRatings {
+        /// How a 5-star rating is converted to a 10-point score
+        #[arg(long, value_enum, default_value_t = RatingRounding::Up)]
+        rounding: RatingRounding,
+    },
+    /// Notify about Crunchyroll episodes released beyond the MAL
+    /// progress of the entries being watched
+    NotifyNew,
+    /// List the unmatched entries, separating the ones absent
+    /// from Crunchyroll from the ones the matcher missed
+    Availability,
+    /// Explain how a single entry gets matched
+    Match {
+        /// MAL ID or title
+        query: String,
+    },
+    /// Print the raw Crunchyroll search results
+    Search {
+        query: String,
+        /// Output JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Decide the marks of a sync without performing them,
+    /// writing them to a plan file for `apply`
+    Plan {
+        /// Where to write the plan, --plan by default
+        #[arg(value_hint = ValueHint::FilePath)]
+        output: Option<PathBuf>,
+    },
+    /// Go through a plan file, dropping entries or episodes and
+    /// fixing matches, then save it and optionally apply it
+    Review { path: PathBuf },
+    /// Perform the marks of a plan file, see plan.schema.json
+    Apply {
+        path: PathBuf,
+        /// Apply the contents that are no longer (or never were)
+        /// part of their season on Crunchyroll
+        #[arg(long)]
+        allow_stale: bool,
+    },
+    /// Mark again the contents whose mark failed in previous runs
+    Retry,
+    /// List the previous runs and their outcome
+    History {
+        /// Number of runs shown
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Compare the entries matched and marked by two runs,
+    /// the last two syncs by default
+    Diff {
+        /// Run number in `history`, "last" or "previous" sync
+        #[arg(long = "run", value_name = "RUN")]
+        runs: Vec<String>,
+        /// Compare the run with the current mappings and retry queue
+        #[arg(long, value_enum)]
+        against: Option<Against>,
+    },
+    /// Share the MAL => Crunchyroll mapping store
+    Mappings {
+        #[command(subcommand)]
+        action: MappingsCommand,
+    },
+    /// Manage the entries never synced
+    Exclude {
+        #[command(subcommand)]
+        action: ExcludeCommand,
+    },
+    /// Inspect the settings
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// Print the completion script of a shell, e.g.
+    /// `mal-2-crunchyroll completions bash > /etc/bash_completion.d/mal-2-crunchyroll`
+    Completions { shell: Shell },
+    /// Manage the stored Crunchyroll catalog
+    Catalog {
+        #[command(subcommand)]
+        action: CatalogCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CatalogCommand {
+    /// Crawl everything the list touches, for the next
+    /// syncs to match against
+    Refresh,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ExcludeCommand {
+    /// Never sync an entry again
+    Add {
+        mal_id: u32,
+        /// Why, kept next to the ID
+        #[arg(long)]
+        comment: Option<String>,
+    },
+    /// Sync an excluded entry again
+    Remove { mal_id: u32 },
+    /// Print the excluded entries
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print config.toml
+    Show {
+        /// Print the settings in effect instead: the defaults, replaced
+        /// by config.toml and its profile, the environment, then the
+        /// flags, with the secrets redacted
+        #[arg(long)]
+        resolved: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MappingsCommand {
+    /// Write the mappings as portable JSON
+    Export {
+        /// Output file, stdout when omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// The anime-lists one carries the provenance of every mapping,
+        /// for contributing the corrections upstream. Both are read
+        /// back by `mappings import`.
+        #[arg(long, value_enum, default_value_t = ExportFormat::Native)]
+        format: ExportFormat,
+    },
+    /// Merge mappings exported by someone else
+    Import {
+        path: PathBuf,
+        /// Replace the mappings found by the matcher
+        #[arg(long)]
+        overwrite: bool,
+    },
+}
+
+impl Cli {
+    /// Entries of the source replacing MAL, if any
+    pub fn source_entries(&self) -> Result<Option<Vec<WatchEntry>>> {
+        Ok(
+            match (&self.from_netflix, &self.from_csv, &self.from_json) {
+                (Some(path), _, _) => Some(netflix::read_history(path)?),
+                (_, Some(path), _) => Some(self.map.read_csv(path)?),
+                (_, _, Some(path)) => Some(self.map.read_json(path)?),
+                _ => None,
+            },
+        )
+    }
+
+    /// The filters of the configuration add to the ones given here
+    pub fn sync_options(&self, config: &Config) -> SyncOptions {
+        let airing_status = match self.airing_status.is_empty() {
+            true => config.filters.airing_status.clone(),
+            false => self.airing_status.clone(),
+        };
+        SyncOptions {
+            add_to_watchlist: self.add_to_watchlist,
+            prune_watchlist: self.prune_watchlist,
+            unmatched_crunchylist: self.unmatched_crunchylist,
+            plan: match &self.command {
+                Some(Command::Plan {
+                    output: Some(output),
+                }) => output.clone(),
+                _ => self.plan.clone(),
+            },
+            replay: self.replay.clone(),
+            skip: self.skip,
+            limit: self.limit,
+            since: self.since.clone(),
+            no_nsfw: self.no_nsfw || !config.filters.nsfw,
+            tags: match self.tags.is_empty() {
+                true => config.filters.tags.clone(),
+                false => self.tags.clone(),
+            },
+            exclude_tags: [&self.exclude_tags[..], &config.filters.exclude_tags[..]].concat(),
+            genres: match self.genres.is_empty() {
+                true => config.filters.genres.clone(),
+                false => self.genres.clone(),
+            },
+            exclude_genres: [&self.exclude_genres[..], &config.filters.exclude_genres[..]].concat(),
+            min_score: self.min_score.or(config.filters.min_score),
+            max_score: self.max_score.or(config.filters.max_score),
+            airing_only: self.airing_only || config.filters.airing_only,
+            airing_status,
+            order: self.order,
+            seed: self.seed,
+            use_relations: self.use_relations,
+            ids: match &self.command {
+                Some(Command::Sync { ids }) => ids.clone(),
+                _ => vec![],
+            },
+            max_errors: self.max_errors,
+            fail_fast: self.fail_fast,
+            no_delta: self.no_delta,
+            api_budget: self.api_budget,
+        }
+    }
+}
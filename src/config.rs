@@ -0,0 +1,477 @@
+use anyhow::Result;
+use regex::{Captures, Regex};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use std::{env, fs, io::ErrorKind, path::PathBuf};
+
+use crate::exit::ConfigError;
+use crate::mal::AiringStatus;
+use crate::mapping::MatcherOverride;
+use crate::state;
+
+/// Optional settings read from `config.toml`, see `state::config_file`.
+/// Credentials stay in the environment, see `ENV_VARS`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// HTTPS URLs of shared mapping files, merged below the local mappings
+    pub mapping_urls: Vec<String>,
+    /// Applied in order to the MAL titles before searching Crunchyroll
+    pub rewrite: Vec<RewriteRule>,
+    /// Rhai script able to veto or rescore matches
+    /// (needs the `scripting` feature)
+    pub match_script: Option<PathBuf>,
+    /// Crunchyroll locale, replaced by CLOCALE
+    pub locale: Option<String>,
+    /// Audio locales by order of preference, replaced by PREFERRED_AUDIO.
+    /// Seasons missing the first one fall back on the next ones.
+    pub preferred_audio: Vec<String>,
+    /// Language of the messages ("en", "fr"), the one of the
+    /// Crunchyroll locale by default
+    pub language: Option<String>,
+    pub matcher: MatcherConfig,
+    pub filters: FilterConfig,
+    pub catalog: CatalogConfig,
+    pub hooks: HooksConfig,
+    pub http: HttpConfig,
+    pub max_concurrency: ConcurrencyConfig,
+    pub request_delay_ms: RequestDelayConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    /// Cron expression of the syncs of `watch`, replacing its interval
+    pub schedule: Option<String>,
+    /// Healthchecks.io-style URL pinged when a run starts (`/start`),
+    /// succeeds, or fails (`/fail`, with the error)
+    pub ping_url: Option<String>,
+    /// Named sets of settings replacing the ones above, picked
+    /// with --profile, `default` otherwise
+    #[serde(skip_serializing)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+/// Entries processed by the syncs, on top of the command line filters
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Include the entries MAL rates NSFW
+    pub nsfw: bool,
+    /// Only the entries with one of these MAL tags, when not empty
+    pub tags: Vec<String>,
+    /// Never the entries with one of these MAL tags
+    pub exclude_tags: Vec<String>,
+    /// Only the shows with one of these genres or themes, when not empty
+    pub genres: Vec<String>,
+    /// Never the shows with one of these genres or themes
+    pub exclude_genres: Vec<String>,
+    /// MAL score range, unscored entries have a score of 0
+    pub min_score: Option<u8>,
+    pub max_score: Option<u8>,
+    pub airing_only: bool,
+    pub airing_status: Vec<AiringStatus>,
+    /// List of MAL IDs never synced, exclude.txt of the
+    /// state directory by default
+    pub exclude_file: Option<PathBuf>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            nsfw: true,
+            tags: vec![],
+            exclude_tags: vec![],
+            genres: vec![],
+            exclude_genres: vec![],
+            min_score: None,
+            max_score: None,
+            airing_only: false,
+            airing_status: vec![],
+            exclude_file: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Profile {
+    pub locale: Option<String>,
+    pub preferred_audio: Option<Vec<String>>,
+    /// Same keys as the per-entry overrides of mappings.json
+    pub matcher: MatcherOverride,
+    pub filters: Option<FilterConfig>,
+}
+
+/// Network settings shared by the MAL and Crunchyroll clients
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout_secs: Option<u64>,
+    pub read_timeout_secs: Option<u64>,
+    /// Reuse the connections between requests
+    pub keep_alive: bool,
+    /// Speak HTTP/2 directly instead of negotiating it
+    pub http2: bool,
+    pub user_agent: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: None,
+            read_timeout_secs: None,
+            keep_alive: true,
+            http2: false,
+            user_agent: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CatalogConfig {
+    /// Age until which the snapshot of `catalog refresh` is
+    /// used instead of fetching the catalog again
+    pub max_age_days: i64,
+    /// Days during which the titles Crunchyroll had nothing for
+    /// aren't searched again, 0 to always search them
+    pub missing_ttl_days: i64,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        Self {
+            max_age_days: 7,
+            missing_ttl_days: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MatcherConfig {
+    /// How far the first episodes of a season may have aired from
+    /// the MAL start date
+    pub date_tolerance_days: i64,
+    /// Normalized edit distance accepted between the titles
+    pub similarity_threshold: f32,
+    pub similarity_curve: SimilarityCurve,
+    pub comparison: Comparison,
+    /// Token distance accepted by the `tokens` comparison
+    pub token_threshold: f32,
+    /// Whether differently titled seasons have to air around the
+    /// MAL start date, mostly disabled for single titles
+    pub check_dates: bool,
+    /// Search the title of the first episode (read from Jikan)
+    /// when the series search finds nothing acceptable
+    pub episode_fallback: bool,
+    /// Look for the series among the simulcasts of the MAL start
+    /// season when nothing else is found
+    pub simulcast_fallback: bool,
+    /// UTC offset of the MAL start dates, Japan by default
+    pub start_date_offset: String,
+}
+
+impl Default for MatcherConfig {
+    fn default() -> Self {
+        Self {
+            date_tolerance_days: 2 * 30,
+            similarity_threshold: 0.125,
+            similarity_curve: SimilarityCurve::Fixed,
+            comparison: Comparison::Prefix,
+            token_threshold: 0.35,
+            check_dates: true,
+            episode_fallback: false,
+            simulcast_fallback: true,
+            start_date_offset: "+09:00".to_string(),
+        }
+    }
+}
+
+/// How the similarity threshold depends on the title length
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SimilarityCurve {
+    /// Same threshold whatever the length
+    Fixed,
+    /// Stricter below 20 characters, looser above, and short
+    /// titles have to match the whole MAL title
+    Scaled,
+}
+
+/// How the titles are compared
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    /// Edit distance with the beginning of the Crunchyroll title
+    Prefix,
+    /// Also accept the titles sharing most of their words, whatever
+    /// their order, for swapped or translated subtitles
+    Tokens,
+}
+
+/// Requests allowed in flight at once, per service
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    pub mal: usize,
+    pub crunchyroll: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            mal: 1,
+            crunchyroll: 4,
+        }
+    }
+}
+
+/// Minimal time between the starts of two requests, per service
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RequestDelayConfig {
+    pub mal: u64,
+    pub crunchyroll: u64,
+}
+
+impl Default for RequestDelayConfig {
+    fn default() -> Self {
+        Self {
+            mal: 1000,
+            crunchyroll: 0,
+        }
+    }
+}
+
+/// Log file written at full verbosity, see src/logfile.rs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LogConfig {
+    pub file: Option<PathBuf>,
+    /// Size from which the file is rotated, 0 for no limit
+    pub max_size_mb: u64,
+    /// Also rotate the file every day
+    pub daily: bool,
+    /// Number of rotated files kept
+    pub keep: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_size_mb: 10,
+            daily: false,
+            keep: 5,
+        }
+    }
+}
+
+/// Sink receiving the counters and durations of every run,
+/// see src/metrics.rs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub format: MetricsFormat,
+    /// InfluxDB write URL, or `host:port` of the Graphite plaintext
+    /// listener. Nothing is pushed without it.
+    pub url: Option<String>,
+    /// Measurement name for InfluxDB, path prefix for Graphite
+    pub prefix: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            format: MetricsFormat::Influx,
+            url: None,
+            prefix: "mal_2_crunchyroll".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsFormat {
+    /// Line protocol POSTed over HTTP
+    Influx,
+    /// Plaintext protocol over TCP
+    Graphite,
+}
+
+/// Shell commands run around the sync, see src/hooks.rs
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub run_start: Option<String>,
+    pub run_end: Option<String>,
+    pub pre_mark: Option<String>,
+    pub post_mark: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RewriteRule {
+    pub pattern: String,
+    /// May reference capture groups (`$1`, `${name}`)
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// Variables read from the environment or the .env files, and
+/// whether they hold secrets
+pub const ENV_VARS: [(&str, bool); 14] = [
+    ("MAL_CLIENT_ID", true),
+    ("MAL_CLIENT_SECRET", true),
+    ("MAL_REDIRECT_URL", false),
+    ("MAL_USERNAME", false),
+    ("EMAIL", true),
+    ("PASSWORD", true),
+    ("PREFERRED_AUDIO", false),
+    ("CLOCALE", false),
+    ("STATE_DIR", false),
+    ("CACHE_DIR", false),
+    ("CONFIG_FILE", false),
+    ("NOTIFY_URL", true),
+    ("TRIGGER_TOKEN", true),
+    ("INFLUX_TOKEN", true),
+];
+
+const REDACTED: &str = "<redacted>";
+
+static URL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"[a-zA-Z][a-zA-Z0-9+.-]*://[^\s"'<>]+"#).unwrap());
+
+static PING_URL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?m)^(\s*ping_url\s*=\s*)\S.*$"#).unwrap());
+
+/// The URL without the credentials it may hold: its user info
+/// and its query
+fn redact_url(url: &str) -> String {
+    let Ok(url) = Url::parse(url) else {
+        return REDACTED.to_string();
+    };
+    let mut output = format!("{}://", url.scheme());
+    if !url.username().is_empty() || url.password().is_some() {
+        output += &format!("{}@", REDACTED);
+    }
+    output += url.host_str().unwrap_or_default();
+    if let Some(x) = url.port() {
+        output += &format!(":{}", x);
+    }
+    output += url.path();
+    if url.query().is_some() {
+        output += &format!("?{}", REDACTED);
+    }
+    output
+}
+
+/// Redacts every URL of a text, e.g. a hook command
+pub fn redact_urls(text: &str) -> String {
+    URL.replace_all(text, |x: &Captures| redact_url(&x[0]))
+        .into_owned()
+}
+
+/// The configuration file as written, with the same secrets hidden
+/// as by `Config::redact`
+pub fn redact_file(content: &str) -> String {
+    let content = PING_URL.replace_all(content, format!("${{1}}\"{}\"", REDACTED));
+    redact_urls(&content)
+}
+
+/// Value of every variable of `ENV_VARS`, the secrets redacted
+pub fn environment() -> Vec<(&'static str, Option<String>)> {
+    ENV_VARS
+        .iter()
+        .map(|(name, secret)| {
+            let value = env::var(name).ok().filter(|x| !x.is_empty());
+            (
+                *name,
+                value.map(|x| if *secret { REDACTED.to_string() } else { x }),
+            )
+        })
+        .collect()
+}
+
+impl Config {
+    /// Resolves the settings once, from the lowest precedence: the
+    /// defaults, config.toml, its profile, then the environment. The
+    /// command line flags are applied on top by the caller.
+    pub fn resolve(profile: Option<&str>) -> Result<Self> {
+        let mut config = Self::load()?;
+        config.select_profile(profile)?;
+        if let Ok(x) = env::var("CLOCALE")
+            && !x.is_empty()
+        {
+            config.locale = Some(x);
+        }
+        // A comma-separated list
+        if let Ok(x) = env::var("PREFERRED_AUDIO")
+            && !x.is_empty()
+        {
+            config.preferred_audio = x.split(',').map(|x| x.trim().to_string()).collect();
+        }
+        Ok(config)
+    }
+
+    /// Hides the settings holding secrets, before printing them
+    pub fn redact(&mut self) {
+        if self.ping_url.is_some() {
+            // The URL is all it takes to ping
+            self.ping_url = Some(REDACTED.to_string());
+        }
+        for x in &mut self.mapping_urls {
+            *x = redact_urls(x);
+        }
+        if let Some(x) = &mut self.metrics.url {
+            *x = redact_urls(x);
+        }
+        let hooks = &mut self.hooks;
+        for x in [
+            &mut hooks.run_start,
+            &mut hooks.run_end,
+            &mut hooks.pre_mark,
+            &mut hooks.post_mark,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *x = redact_urls(x);
+        }
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = state::config_file();
+        let content = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&content)
+            .map_err(|e| ConfigError(format!("Invalid {}: {}", path.display(), e)).into())
+    }
+
+    /// Applies a profile on top of the global settings. Without a
+    /// name, the `default` profile is used when there is one.
+    pub fn select_profile(&mut self, name: Option<&str>) -> Result<()> {
+        let profile = match name {
+            Some(x) => match self.profile.get(x) {
+                Some(profile) => profile.clone(),
+                None => return Err(ConfigError(format!("No profile named '{}'", x)).into()),
+            },
+            None => self.profile.get("default").cloned().unwrap_or_default(),
+        };
+
+        if profile.locale.is_some() {
+            self.locale = profile.locale;
+        }
+        if let Some(x) = profile.preferred_audio {
+            self.preferred_audio = x;
+        }
+        self.matcher = profile.matcher.apply(&self.matcher);
+        if let Some(x) = profile.filters {
+            self.filters = x;
+        }
+        Ok(())
+    }
+}
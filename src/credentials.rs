@@ -0,0 +1,79 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use crate::exit::ConfigError;
+use crate::{output, t};
+
+/// Entered during the run, not asked twice
+static ENTERED: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+#[cfg(feature = "keyring")]
+mod store {
+    use anyhow::Result;
+    use keyring::Entry;
+
+    const SERVICE: &str = "mal-2-crunchyroll";
+
+    pub fn get(name: &str) -> Option<String> {
+        Entry::new(SERVICE, name).ok()?.get_password().ok()
+    }
+
+    fn set(name: &str, value: &str) -> Result<()> {
+        Ok(Entry::new(SERVICE, name)?.set_password(value)?)
+    }
+
+    /// Saves what was just entered when the user wants to
+    pub fn offer(name: &str, value: &str) -> Result<()> {
+        if crate::output::ask(&crate::t!("credential-save"), false)?.eq_ignore_ascii_case("y")
+            && let Err(e) = set(name, value)
+        {
+            crate::warn!("Could not save {} to the keyring: {:#}", name, e);
+        }
+        Ok(())
+    }
+}
+
+/// The credential from the environment or the keyring, without asking
+pub fn stored(name: &str) -> Option<String> {
+    if let Ok(x) = env::var(name)
+        && !x.is_empty()
+    {
+        return Some(x);
+    }
+    if let Some(x) = ENTERED.lock().unwrap().as_ref().and_then(|x| x.get(name)) {
+        return Some(x.clone());
+    }
+    #[cfg(feature = "keyring")]
+    return store::get(name);
+    #[cfg(not(feature = "keyring"))]
+    None
+}
+
+/// The credential from the environment or the keyring, asked to the
+/// user otherwise, `hidden` ones without echo. What gets entered
+/// may be saved to the keyring (with the `keyring` feature).
+pub fn get(name: &str, hidden: bool) -> Result<String> {
+    if let Some(x) = stored(name) {
+        return Ok(x);
+    }
+    let missing = || ConfigError(t!("env-missing", name = name));
+    let value = output::ask(
+        &format!("{}:", t!("credential-prompt", name = name)),
+        hidden,
+    )
+    .map_err(|_| missing())?;
+    if value.is_empty() {
+        return Err(missing().into());
+    }
+
+    #[cfg(feature = "keyring")]
+    store::offer(name, &value)?;
+    ENTERED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(name.to_string(), value.clone());
+    Ok(value)
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use crunchyroll_rs::{MediaCollection, Series};
+use std::collections::HashSet;
+
+use crate::catalog::{Catalog, SeriesInfo};
+use crate::{info, profile};
+
+const UNMATCHED_LIST_NAME: &str = "MAL unmatched";
+// Crunchylists are capped server-side
+const MAX_LIST_SIZE: usize = 100;
+
+pub fn media_id(media: &MediaCollection) -> Option<&String> {
+    match media {
+        MediaCollection::Series(x) => Some(&x.id),
+        MediaCollection::Season(x) => Some(&x.id),
+        MediaCollection::Episode(x) => Some(&x.id),
+        MediaCollection::MovieListing(x) => Some(&x.id),
+        MediaCollection::Movie(x) => Some(&x.id),
+        _ => None,
+    }
+}
+
+/// Replaces the content of the "MAL unmatched" Crunchylist with
+/// the closest candidates of the entries that failed matching.
+pub async fn update_unmatched(catalog: &Catalog<'_>, candidates: Vec<SeriesInfo>) -> Result<()> {
+    let lists = profile::timed("crunchylist", catalog.crunchyroll()?.crunchylists()).await?;
+    let preview = match lists.items.iter().find(|x| x.title == UNMATCHED_LIST_NAME) {
+        Some(x) => x.clone(),
+        None => {
+            let name = UNMATCHED_LIST_NAME.to_string();
+            profile::timed("crunchylist", lists.create(name)).await?
+        }
+    };
+    let list = profile::timed("crunchylist", preview.crunchylist()).await?;
+
+    let mut seen = HashSet::<String>::new();
+    let candidates: Vec<SeriesInfo> = candidates
+        .into_iter()
+        .filter(|x| seen.insert(x.id.clone()))
+        .take(MAX_LIST_SIZE)
+        .collect();
+
+    let mut present = HashSet::<String>::new();
+    for entry in list.items.clone() {
+        match media_id(&entry.panel) {
+            Some(id) if seen.contains(id) => {
+                present.insert(id.clone());
+            }
+            _ => profile::timed("crunchylist", entry.delete()).await?,
+        }
+    }
+
+    for series in candidates {
+        if present.contains(&series.id) {
+            continue;
+        }
+        info!("Adding {} to '{}'", &series.title, UNMATCHED_LIST_NAME);
+        let series: Series = catalog.live_series(&series.id).await?;
+        profile::timed("crunchylist", list.add(MediaCollection::Series(series))).await?;
+    }
+
+    Ok(())
+}
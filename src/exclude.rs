@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::state;
+
+const FILE_NAME: &str = "exclude.txt";
+
+/// MAL IDs every sync skips, one per line. Anything after a `#`
+/// is a comment, so the file can be edited by hand:
+///
+/// ```text
+/// # Recaps
+/// 52991  # Sousou no Frieren: ~Marumaru no Mahou~
+/// ```
+pub struct ExcludeList {
+    path: PathBuf,
+    /// Kept as read, to preserve the comments when rewriting
+    lines: Vec<String>,
+    ids: BTreeSet<u32>,
+}
+
+fn parse_line(line: &str) -> Result<Option<u32>, std::num::ParseIntError> {
+    let id = line.split('#').next().unwrap_or_default().trim();
+    match id.is_empty() {
+        true => Ok(None),
+        false => id.parse().map(Some),
+    }
+}
+
+impl ExcludeList {
+    /// Reads `path`, exclude.txt of the state directory by default
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(x) => x.to_path_buf(),
+            None => state::state_dir()?.join(FILE_NAME),
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let lines: Vec<String> = content.lines().map(String::from).collect();
+        let mut ids = BTreeSet::new();
+        for (i, line) in lines.iter().enumerate() {
+            let id = parse_line(line).with_context(|| {
+                format!("Invalid MAL ID on line {} of {}", i + 1, path.display())
+            })?;
+            ids.extend(id);
+        }
+        Ok(Self { path, lines, ids })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut content = self.lines.join("\n");
+        content.push('\n');
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, mal_id: u32) -> bool {
+        self.ids.contains(&mal_id)
+    }
+
+    /// Returns whether the entry wasn't excluded yet
+    pub fn add(&mut self, mal_id: u32, comment: Option<&str>) -> bool {
+        if !self.ids.insert(mal_id) {
+            return false;
+        }
+        self.lines.push(match comment {
+            Some(x) => format!("{}  # {}", mal_id, x),
+            None => mal_id.to_string(),
+        });
+        true
+    }
+
+    /// Returns whether the entry was excluded
+    pub fn remove(&mut self, mal_id: u32) -> bool {
+        if !self.ids.remove(&mal_id) {
+            return false;
+        }
+        self.lines
+            .retain(|x| parse_line(x).ok().flatten() != Some(mal_id));
+        true
+    }
+
+    /// The lines of the excluded entries, with their comments
+    pub fn entries(&self) -> impl Iterator<Item = &str> {
+        self.lines
+            .iter()
+            .filter(|x| matches!(parse_line(x), Ok(Some(_))))
+            .map(String::as_str)
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::process::ExitCode;
+
+use crate::output;
+
+/// Listed at the end of `--help`
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Every entry has been synced
+  1  The run has been aborted by an error
+  2  The run has completed, but some entries or marks failed
+  3  MAL or Crunchyroll rejected the credentials
+  4  The configuration or the environment is invalid";
+
+/// The credentials have been rejected
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+/// Invalid configuration file or missing environment variable
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitStatus {
+    Success = 0,
+    Failure = 1,
+    Partial = 2,
+    Auth = 3,
+    Config = 4,
+}
+
+impl ExitStatus {
+    /// Status of a run ending with `res`, the errors logged along
+    /// the way making it partial
+    pub fn of<T>(res: &anyhow::Result<T>) -> Self {
+        match res {
+            Ok(_) if output::error_count() > 0 => Self::Partial,
+            Ok(_) => Self::Success,
+            Err(e) if e.chain().any(|x| x.is::<AuthError>()) => Self::Auth,
+            Err(e) if e.chain().any(|x| x.is::<ConfigError>()) => Self::Config,
+            Err(_) => Self::Failure,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(x: ExitStatus) -> Self {
+        ExitCode::from(x as u8)
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use mal_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::mal;
+use crate::ratelimit::{self, Service};
+use crate::state::{self, Persisted};
+use crate::warn;
+
+/// Prequel chains longer than that are assumed to loop
+const MAX_CHAIN_LENGTH: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RelationInfo {
+    /// Only TV series count as a Crunchyroll season
+    tv: bool,
+    prequels: Vec<u32>,
+}
+
+/// MAL relations fetched so far, they hardly ever change
+#[derive(Serialize, Deserialize, Default)]
+struct RelationCache {
+    entries: BTreeMap<u32, RelationInfo>,
+}
+
+impl Persisted for RelationCache {
+    const FILE_NAME: &'static str = "mal-relations.json";
+    const CACHE: bool = true;
+}
+
+/// Where a TV series stands in its franchise
+#[derive(Clone, Copy, Debug)]
+pub struct Chain {
+    /// First entry of the prequel chain
+    pub root: u32,
+    /// 1-based, among the TV series of the chain
+    pub position: usize,
+}
+
+async fn fetch_relations(anime_id: u32) -> Result<RelationInfo> {
+    let details = mal::with_retries("Reading the relations", || {
+        mal::anime_details("mal relations", anime_id, "media_type,related_anime", None)
+    })
+    .await?;
+
+    Ok(RelationInfo {
+        tv: details.shown.media_type == Some(AnimeMediaType::Tv),
+        prequels: details
+            .related_anime
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| x.relation_type == RelationType::Prequel)
+            .map(|x| x.node.id)
+            .collect(),
+    })
+}
+
+/// Position of the MAL entries within their franchise, following
+/// the prequel relations. The Nth TV series of a chain is expected
+/// to be the Nth season on Crunchyroll.
+pub struct Franchises {
+    cache: RelationCache,
+    offline: bool,
+    chains: HashMap<u32, Option<Chain>>,
+}
+
+impl Franchises {
+    pub fn load(offline: bool) -> Result<Self> {
+        Ok(Self {
+            cache: state::load()?,
+            offline,
+            chains: HashMap::new(),
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        state::save(&self.cache)
+    }
+
+    async fn relations(&mut self, anime_id: u32) -> Result<Option<RelationInfo>> {
+        if let Some(x) = self.cache.entries.get(&anime_id) {
+            return Ok(Some(x.clone()));
+        }
+        if self.offline {
+            return Ok(None);
+        }
+
+        mal::client_id()?;
+        let info = fetch_relations(anime_id).await?;
+        self.cache.entries.insert(anime_id, info.clone());
+        Ok(Some(info))
+    }
+
+    /// Reads the relations of the entries not known yet, as many at
+    /// once as MAL allows, for `chain` to mostly find them cached
+    pub async fn prefetch(&mut self, anime_ids: &[u32]) -> Result<()> {
+        if self.offline {
+            return Ok(());
+        }
+        let missing: Vec<u32> = anime_ids
+            .iter()
+            .copied()
+            .filter(|x| !self.cache.entries.contains_key(x))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        mal::client_id()?;
+        let concurrency = ratelimit::concurrency(Service::Mal);
+        let fetched = ratelimit::lookup_all(concurrency, missing.clone(), fetch_relations).await;
+        for (anime_id, info) in missing.into_iter().zip(fetched) {
+            self.cache.entries.insert(anime_id, info?);
+        }
+        Ok(())
+    }
+
+    /// Chain of a TV series, None for other media or unknown relations
+    pub async fn chain(&mut self, anime_id: u32) -> Result<Option<Chain>> {
+        if let Some(x) = self.chains.get(&anime_id) {
+            return Ok(*x);
+        }
+
+        let mut chain = None;
+        if let Some(info) = self.relations(anime_id).await? {
+            if info.tv {
+                let mut root = anime_id;
+                let mut count = 1;
+                let mut current = info.prequels.first().copied();
+                let mut length = 0;
+                while let Some(id) = current {
+                    length += 1;
+                    if length > MAX_CHAIN_LENGTH {
+                        warn!("Prequel loop around {}", anime_id);
+                        break;
+                    }
+                    let Some(prequel) = self.relations(id).await? else {
+                        break;
+                    };
+                    root = id;
+                    if prequel.tv {
+                        count += 1;
+                    }
+                    current = prequel.prequels.first().copied();
+                }
+                chain = Some(Chain {
+                    root,
+                    position: count,
+                });
+            }
+        }
+
+        self.chains.insert(anime_id, chain);
+        Ok(chain)
+    }
+}
@@ -0,0 +1,391 @@
+use anyhow::{Result, bail};
+use chrono::{DateTime, Local, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::config::Config;
+use crate::exit::ExitStatus;
+use crate::mapping::MappingStore;
+use crate::matcher::FailureReason;
+use crate::retry::RetryQueue;
+use crate::state::{self, Persisted};
+use crate::sync::SyncEvent;
+use crate::t;
+
+/// Runs kept in the history, the oldest are dropped first
+const MAX_RUNS: usize = 500;
+
+/// Runs keeping the outcome of every entry, the older ones
+/// only keep their counts
+const MAX_RUNS_WITH_ENTRIES: usize = 20;
+
+/// Counts of a run, printed as its summary
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct RunStats {
+    pub matched: usize,
+    pub marked: usize,
+    pub unmatched: usize,
+    pub failures: usize,
+}
+
+/// What happened to a MAL entry during a sync
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EntryOutcome {
+    pub title: String,
+    pub season_id: Option<String>,
+    pub season_title: Option<String>,
+    /// Episodes marked during the run
+    pub marked: usize,
+    pub failed: bool,
+    /// Why the entry was left unmatched or unmarked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<FailureReason>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunRecord {
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    /// Command line arguments
+    pub arguments: String,
+    pub stats: RunStats,
+    pub exit_code: u8,
+    /// Changes whenever the settings of config.toml change
+    pub config_hash: Option<String>,
+    /// Whether the run synced the list, the other commands being
+    /// left out of `diff`
+    #[serde(default)]
+    pub sync: bool,
+    /// Outcome of every entry processed by a sync
+    #[serde(default)]
+    pub entries: BTreeMap<u32, EntryOutcome>,
+}
+
+impl RunRecord {
+    pub fn start(arguments: String) -> Self {
+        Self {
+            started_at: Utc::now(),
+            duration_secs: 0.,
+            arguments,
+            stats: RunStats::default(),
+            exit_code: 0,
+            config_hash: None,
+            sync: false,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a sync event
+    pub fn observe(&mut self, event: &SyncEvent) {
+        match event {
+            SyncEvent::EntryStarted { mal_id, title, .. } => {
+                self.entries.entry(*mal_id).or_default().title = title.clone();
+            }
+            SyncEvent::Matched {
+                mal_id,
+                season_id,
+                season_title,
+                ..
+            } => {
+                self.stats.matched += 1;
+                let entry = self.entries.entry(*mal_id).or_default();
+                entry.season_id = Some(season_id.clone());
+                entry.season_title = Some(season_title.clone());
+            }
+            SyncEvent::Marked { mal_id, .. } => {
+                self.stats.marked += 1;
+                self.entries.entry(*mal_id).or_default().marked += 1;
+            }
+            SyncEvent::Skipped { mal_id, reason, .. } => {
+                self.stats.unmatched += 1;
+                self.entries.entry(*mal_id).or_default().reason = Some(*reason);
+            }
+            SyncEvent::Failed {
+                mal_id: Some(mal_id),
+                ..
+            } => {
+                self.stats.failures += 1;
+                let entry = self.entries.entry(*mal_id).or_default();
+                entry.failed = true;
+                entry.reason = Some(FailureReason::ApiError);
+            }
+            SyncEvent::Failed { mal_id: None, .. } => (),
+        }
+    }
+
+    pub fn finish(&mut self, status: ExitStatus) {
+        self.duration_secs = (Utc::now() - self.started_at).as_seconds_f64();
+        self.exit_code = status as u8;
+    }
+}
+
+/// 64-bit FNV-1a, the same across builds unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, x| {
+        (hash ^ *x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hash of the settings as serialized, their order being fixed
+pub fn config_hash(config: &Config) -> Result<String> {
+    let serialized = serde_json::to_vec(config)?;
+    Ok(format!("{:016x}", fnv1a(&serialized)))
+}
+
+/// Summary of the previous runs
+#[derive(Serialize, Deserialize, Default)]
+pub struct RunHistory {
+    runs: Vec<RunRecord>,
+}
+
+impl Persisted for RunHistory {
+    const FILE_NAME: &'static str = "run-history.json";
+}
+
+impl RunHistory {
+    pub fn load() -> Result<Self> {
+        state::load()
+    }
+
+    pub fn runs(&self) -> &[RunRecord] {
+        &self.runs
+    }
+
+    /// Run given as its number in `history`, or as "last" or
+    /// "previous" among the syncs
+    pub fn find(&self, spec: &str) -> Result<&RunRecord> {
+        // The records older than the flag only have entries when syncing
+        let mut syncs = (0..self.runs.len())
+            .rev()
+            .filter(|&x| self.runs[x].sync || !self.runs[x].entries.is_empty());
+        let index = match spec {
+            "last" => syncs.next(),
+            "previous" => syncs.nth(1),
+            x => match x.parse::<usize>() {
+                Ok(n) => n.checked_sub(1),
+                Err(_) => bail!(
+                    "Expected a run number, \"last\" or \"previous\", got '{}'",
+                    x
+                ),
+            },
+        };
+        match index.and_then(|x| self.runs.get(x)) {
+            Some(x) => Ok(x),
+            None => bail!("No run {} in the history", spec),
+        }
+    }
+
+    /// Appends a run to the stored history
+    pub fn record(run: RunRecord) -> Result<()> {
+        let mut history = Self::load()?;
+        history.runs.push(run);
+        if history.runs.len() > MAX_RUNS {
+            history.runs.drain(..history.runs.len() - MAX_RUNS);
+        }
+        let keep_from = history.runs.len().saturating_sub(MAX_RUNS_WITH_ENTRIES);
+        for run in &mut history.runs[..keep_from] {
+            run.entries.clear();
+        }
+        state::save(&history)
+    }
+
+    /// The last `limit` runs as a table, the most recent last
+    pub fn render(&self, limit: usize) -> Result<String> {
+        let mut out = String::new();
+        let runs = &self.runs[self.runs.len().saturating_sub(limit)..];
+        if runs.is_empty() {
+            writeln!(out, "{}", t!("history-empty"))?;
+            return Ok(out);
+        }
+
+        let first = self.runs.len() - runs.len();
+        writeln!(
+            out,
+            "{:>4} {:<16} {:>8} {:>7} {:>6} {:>9} {:>8}  {:<8} {}",
+            "#",
+            "Started",
+            "Duration",
+            "Matched",
+            "Marked",
+            "Unmatched",
+            "Failures",
+            "Outcome",
+            "Arguments"
+        )?;
+        for (i, run) in runs.iter().enumerate() {
+            let outcome = match run.exit_code {
+                0 => "ok",
+                2 => "partial",
+                3 => "auth",
+                4 => "config",
+                _ => "failed",
+            };
+            writeln!(
+                out,
+                "{:>4} {:<16} {:>7.0}s {:>7} {:>6} {:>9} {:>8}  {:<8} {}",
+                first + i + 1,
+                run.started_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M"),
+                run.duration_secs,
+                run.stats.matched,
+                run.stats.marked,
+                run.stats.unmatched,
+                run.stats.failures,
+                outcome,
+                run.arguments
+            )?;
+        }
+        Ok(out)
+    }
+}
+
+/// What a run is compared with, besides another run
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Against {
+    /// The current mappings and retry queue
+    Live,
+}
+
+/// Current state of the entries of `base`, as far as the
+/// mappings and the retry queue tell
+fn live_entries(base: &RunRecord) -> Result<BTreeMap<u32, EntryOutcome>> {
+    let mappings = MappingStore::load()?;
+    let retry_queue = RetryQueue::load()?;
+
+    let mut output = BTreeMap::new();
+    for (mal_id, outcome) in &base.entries {
+        let mapping = mappings.get(*mal_id);
+        output.insert(
+            *mal_id,
+            EntryOutcome {
+                title: outcome.title.clone(),
+                season_id: mapping.map(|x| x.season_id.clone()),
+                season_title: None,
+                marked: 0,
+                failed: retry_queue.entries().iter().any(|x| x.mal_id == *mal_id),
+                reason: None,
+            },
+        );
+    }
+    Ok(output)
+}
+
+fn season_name(outcome: &EntryOutcome) -> &str {
+    outcome
+        .season_title
+        .as_deref()
+        .or(outcome.season_id.as_deref())
+        .unwrap_or_default()
+}
+
+fn write_section(out: &mut String, name: &str, lines: &[String]) -> Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "{} ({}):", name, lines.len())?;
+    for line in lines {
+        writeln!(out, "  {}", line)?;
+    }
+    Ok(())
+}
+
+/// Lists the entries newly matched, newly marked or regressed
+/// between two runs, or between a run and the current state
+pub fn diff(runs: &[String], against: Option<Against>) -> Result<String> {
+    let history = RunHistory::load()?;
+    let (old, new) = match (runs, against) {
+        ([], None) => (
+            history.find("previous")?.entries.clone(),
+            history.find("last")?.entries.clone(),
+        ),
+        ([a, b], None) => (
+            history.find(a)?.entries.clone(),
+            history.find(b)?.entries.clone(),
+        ),
+        (_, Some(Against::Live)) if runs.len() <= 1 => {
+            let base = history.find(runs.first().map_or("last", |x| x.as_str()))?;
+            (base.entries.clone(), live_entries(base)?)
+        }
+        _ => bail!("Expected two runs, or one run and --against"),
+    };
+
+    let (mut matched, mut changed, mut marked, mut regressed) = (vec![], vec![], vec![], vec![]);
+    for (mal_id, outcome) in &new {
+        let before = old.get(mal_id);
+        let title = &outcome.title;
+        match (
+            before.and_then(|x| x.season_id.as_ref()),
+            &outcome.season_id,
+        ) {
+            (None, Some(_)) => {
+                matched.push(format!("{} {} => {}", mal_id, title, season_name(outcome)))
+            }
+            (Some(_), None) => regressed.push(match outcome.reason {
+                Some(x) => format!("{} {}: no longer matched ({})", mal_id, title, x.name()),
+                None => format!("{} {}: no longer matched", mal_id, title),
+            }),
+            (Some(a), Some(b)) if a != b => changed.push(format!(
+                "{} {}: {} => {}",
+                mal_id,
+                title,
+                season_name(before.unwrap()),
+                season_name(outcome)
+            )),
+            _ => (),
+        }
+        if outcome.failed && !before.is_some_and(|x| x.failed) {
+            regressed.push(format!("{} {}: marks failing", mal_id, title));
+        }
+        if outcome.marked > 0 {
+            marked.push(format!("{} {}: {} episodes", mal_id, title, outcome.marked));
+        }
+    }
+
+    let mut out = String::new();
+    write_section(&mut out, &t!("diff-newly-matched"), &matched)?;
+    write_section(&mut out, &t!("diff-changed"), &changed)?;
+    write_section(&mut out, &t!("diff-newly-marked"), &marked)?;
+    write_section(&mut out, &t!("diff-regressed"), &regressed)?;
+    if matched.is_empty() && changed.is_empty() && marked.is_empty() && regressed.is_empty() {
+        writeln!(out, "{}", t!("diff-none"))?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(arguments: &str, sync: bool) -> RunRecord {
+        RunRecord {
+            sync,
+            ..RunRecord::start(arguments.to_string())
+        }
+    }
+
+    #[test]
+    fn hashes_with_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn compares_the_last_syncs() {
+        let history = RunHistory {
+            runs: vec![
+                run("sync", true),
+                run("sync --limit 5", true),
+                run("mappings export", false),
+                run("catalog refresh", false),
+            ],
+        };
+        assert_eq!(history.find("last").unwrap().arguments, "sync --limit 5");
+        assert_eq!(history.find("previous").unwrap().arguments, "sync");
+        assert_eq!(history.find("4").unwrap().arguments, "catalog refresh");
+        assert!(history.find("5").is_err());
+    }
+}
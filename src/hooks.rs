@@ -0,0 +1,132 @@
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::HooksConfig;
+
+/// Entry being synced, passed to the hooks
+#[derive(Serialize)]
+pub struct HookEntry<'a> {
+    pub mal_id: u32,
+    pub title: &'a str,
+}
+
+#[derive(Serialize)]
+struct HookPayload<'a> {
+    event: &'a str,
+    #[serde(flatten)]
+    entry: Option<&'a HookEntry<'a>>,
+    content_id: Option<&'a str>,
+    outcome: Option<&'a str>,
+}
+
+/// Runs the user commands configured under `[hooks]`. Every command
+/// gets the event as `MAL2CR_*` environment variables and as JSON on stdin.
+pub struct Hooks {
+    config: HooksConfig,
+}
+
+impl Hooks {
+    pub fn new(config: &HooksConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    async fn run(&self, command: &Option<String>, payload: HookPayload) -> Result<()> {
+        let Some(command) = command else {
+            return Ok(());
+        };
+
+        let mut child = Command::new("sh");
+        child
+            .arg("-c")
+            .arg(command)
+            .env("MAL2CR_EVENT", payload.event)
+            .stdin(Stdio::piped());
+        if let Some(entry) = payload.entry {
+            child
+                .env("MAL2CR_MAL_ID", entry.mal_id.to_string())
+                .env("MAL2CR_TITLE", entry.title);
+        }
+        if let Some(content_id) = payload.content_id {
+            child.env("MAL2CR_CONTENT_ID", content_id);
+        }
+        if let Some(outcome) = payload.outcome {
+            child.env("MAL2CR_OUTCOME", outcome);
+        }
+
+        let mut child = child.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // The hook may not read its stdin
+            let _ = stdin
+                .write_all(serde_json::to_string(&payload)?.as_bytes())
+                .await;
+        }
+        let status = child.wait().await?;
+        if !status.success() {
+            bail!("{} hook exited with {}", payload.event, status);
+        }
+        Ok(())
+    }
+
+    pub async fn run_start(&self) -> Result<()> {
+        self.run(
+            &self.config.run_start,
+            HookPayload {
+                event: "run_start",
+                entry: None,
+                content_id: None,
+                outcome: None,
+            },
+        )
+        .await
+    }
+
+    pub async fn run_end(&self, success: bool) -> Result<()> {
+        self.run(
+            &self.config.run_end,
+            HookPayload {
+                event: "run_end",
+                entry: None,
+                content_id: None,
+                outcome: Some(if success { "success" } else { "failure" }),
+            },
+        )
+        .await
+    }
+
+    /// A failing pre-mark hook cancels the mark
+    pub async fn pre_mark(&self, entry: &HookEntry, content_id: &str) -> Result<()> {
+        self.run(
+            &self.config.pre_mark,
+            HookPayload {
+                event: "pre_mark",
+                entry: Some(entry),
+                content_id: Some(content_id),
+                outcome: None,
+            },
+        )
+        .await
+    }
+
+    pub async fn post_mark(
+        &self,
+        entry: &HookEntry,
+        content_id: &str,
+        success: bool,
+    ) -> Result<()> {
+        self.run(
+            &self.config.post_mark,
+            HookPayload {
+                event: "post_mark",
+                entry: Some(entry),
+                content_id: Some(content_id),
+                outcome: Some(if success { "success" } else { "failure" }),
+            },
+        )
+        .await
+    }
+}
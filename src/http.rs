@@ -0,0 +1,254 @@
+use anyhow::{Result, bail};
+use crunchyroll_rs::crunchyroll::CrunchyrollBuilder;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::config::HttpConfig;
+use crate::profile;
+use crate::ratelimit::{self, Service};
+use crate::{debug, warn};
+
+/// Attempts of a request throttled or failing on the server side
+const MAX_ATTEMPTS: u32 = 5;
+
+static CONFIG: OnceLock<HttpConfig> = OnceLock::new();
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+static TAPE: Mutex<Option<Tape>> = Mutex::new(None);
+
+/// A request sent through `send_authed` and its final answer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Exchange {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub body: String,
+}
+
+enum Tape {
+    Recording(Vec<Exchange>),
+    Replaying(VecDeque<Exchange>),
+}
+
+/// Keeps every exchange from now on, see `recorded`
+pub fn record() {
+    *TAPE.lock().unwrap() = Some(Tape::Recording(vec![]));
+}
+
+/// Answers the requests from `exchanges` instead of the network,
+/// in the order they were recorded
+pub fn replay(exchanges: Vec<Exchange>) {
+    *TAPE.lock().unwrap() = Some(Tape::Replaying(exchanges.into()));
+}
+
+pub fn is_replaying() -> bool {
+    matches!(*TAPE.lock().unwrap(), Some(Tape::Replaying(_)))
+}
+
+/// The exchanges recorded so far
+pub fn recorded() -> Vec<Exchange> {
+    match &*TAPE.lock().unwrap() {
+        Some(Tape::Recording(x)) => x.clone(),
+        _ => vec![],
+    }
+}
+
+/// The recorded answer to a request, the first one left
+fn replayed(method: &str, url: &str) -> Option<Response> {
+    let mut tape = TAPE.lock().unwrap();
+    let Some(Tape::Replaying(exchanges)) = tape.as_mut() else {
+        return None;
+    };
+    let i = exchanges
+        .iter()
+        .position(|x| x.method == method && x.url == url)?;
+    let exchange = exchanges.remove(i)?;
+    let response = ::http::Response::builder()
+        .status(exchange.status)
+        .body(exchange.body)
+        .ok()?;
+    Some(response.into())
+}
+
+/// Keeps the answer when recording. Its body is read to be stored,
+/// the caller gets an identical response.
+async fn recorded_answer(method: &str, res: Response) -> Result<Response> {
+    if !matches!(*TAPE.lock().unwrap(), Some(Tape::Recording(_))) {
+        return Ok(res);
+    }
+    let (status, url, headers) = (res.status(), res.url().to_string(), res.headers().clone());
+    let body = res.text().await?;
+    if let Some(Tape::Recording(x)) = TAPE.lock().unwrap().as_mut() {
+        x.push(Exchange {
+            method: method.to_string(),
+            url,
+            status: status.as_u16(),
+            body: body.clone(),
+        });
+    }
+    let mut response = ::http::Response::builder().status(status).body(body)?;
+    *response.headers_mut() = headers;
+    Ok(response.into())
+}
+
+/// Sets the settings of the shared client, only the first call has
+/// an effect, before the client is first used
+pub fn configure(config: &HttpConfig) {
+    let _ = CONFIG.set(config.clone());
+}
+
+/// Applies the configured timeouts, keep-alive, HTTP version and
+/// User-Agent to a client builder
+pub fn apply(mut builder: ClientBuilder) -> ClientBuilder {
+    let Some(config) = CONFIG.get() else {
+        return builder;
+    };
+
+    if let Some(x) = config.connect_timeout_secs {
+        builder = builder.connect_timeout(Duration::from_secs(x));
+    }
+    if let Some(x) = config.read_timeout_secs {
+        builder = builder.read_timeout(Duration::from_secs(x));
+    }
+    if config.keep_alive {
+        builder = builder.tcp_keepalive(Duration::from_secs(60));
+    } else {
+        builder = builder.pool_max_idle_per_host(0);
+    }
+    if config.http2 {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(x) = &config.user_agent {
+        builder = builder.user_agent(x);
+    }
+    builder
+}
+
+/// Client shared by every request: Crunchyroll, the MAL API, the
+/// mapping lists and the notifications. Built on the first call with
+/// the headers Crunchyroll expects.
+pub fn client() -> Result<Client> {
+    if let Some(x) = CLIENT.get() {
+        return Ok(x.clone());
+    }
+    let client = apply(CrunchyrollBuilder::predefined_client_builder()).build()?;
+    Ok(CLIENT.get_or_init(|| client).clone())
+}
+
+/// Source of the bearer token of authenticated requests
+pub trait Auth {
+    /// The current token, or a new one when the last was refused
+    fn bearer_token(&mut self, refresh: bool) -> impl Future<Output = Result<String>> + Send;
+}
+
+/// Requests sent without a token
+pub struct NoAuth;
+
+impl Auth for NoAuth {
+    async fn bearer_token(&mut self, _refresh: bool) -> Result<String> {
+        Ok(String::new())
+    }
+}
+
+/// Worth trying again after a while, MAL regularly answering
+/// 500/504 under load
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::INTERNAL_SERVER_ERROR
+        || status == StatusCode::BAD_GATEWAY
+        || status == StatusCode::SERVICE_UNAVAILABLE
+        || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Sends a request, see `send_authed`
+pub async fn send(endpoint: &'static str, request: RequestBuilder) -> Result<Response> {
+    send_authed(endpoint, request, &mut NoAuth).await
+}
+
+/// Sends a request through the layers shared by every call site:
+/// the pacing and timing of `endpoint` (see `profile::timed`), the
+/// bearer token of `auth` renewed once when refused, the retries of
+/// the throttled and unavailable requests, the debug logs, and the
+/// cassette being recorded or replayed. The status of the final
+/// answer is left to the caller.
+pub async fn send_authed(
+    endpoint: &'static str,
+    request: RequestBuilder,
+    auth: &mut impl Auth,
+) -> Result<Response> {
+    let Some(built) = request.try_clone().and_then(|x| x.build().ok()) else {
+        bail!("The {} request can't be sent", endpoint);
+    };
+    let (method, url) = (built.method().to_string(), built.url().to_string());
+    if is_replaying() {
+        return match replayed(&method, &url) {
+            Some(x) => Ok(x),
+            None => bail!(
+                "The {} request isn't in the cassette: {} {}",
+                endpoint,
+                method,
+                url
+            ),
+        };
+    }
+
+    let service = Service::of(endpoint);
+    let mut attempt = 1;
+    let mut refresh = false;
+    loop {
+        let Some(mut attempt_request) = request.try_clone() else {
+            bail!("The {} request can't be sent", endpoint);
+        };
+        let token = auth.bearer_token(refresh).await?;
+        if !token.is_empty() {
+            attempt_request = attempt_request.bearer_auth(token);
+        }
+        let res = profile::timed(endpoint, attempt_request.send()).await;
+
+        let delay = match &res {
+            Ok(x) => {
+                debug!("{} answered {}", endpoint, x.status());
+                if x.status() == StatusCode::UNAUTHORIZED && !refresh {
+                    refresh = true;
+                    continue;
+                }
+                if !is_transient(x.status()) || attempt >= MAX_ATTEMPTS {
+                    return recorded_answer(&method, res?).await;
+                }
+                if x.status() == StatusCode::TOO_MANY_REQUESTS {
+                    ratelimit::throttled(service);
+                }
+                ratelimit::backoff(attempt, retry_after(x))
+            }
+            Err(e) if (e.is_timeout() || e.is_connect()) && attempt < MAX_ATTEMPTS => {
+                debug!("{} failed: {}", endpoint, e);
+                ratelimit::backoff(attempt, None)
+            }
+            Err(_) => return Ok(res?),
+        };
+        warn!(
+            "The {} request failed ({}), retrying in {}s",
+            endpoint,
+            match &res {
+                Ok(x) => x.status().to_string(),
+                Err(e) => e.to_string(),
+            },
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
@@ -0,0 +1,82 @@
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use std::sync::OnceLock;
+use unic_langid::LanguageIdentifier;
+
+pub use fluent_bundle::FluentValue;
+
+/// Message catalogs, English being the fallback of the others
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.ftl")),
+    ("fr", include_str!("../locales/fr.ftl")),
+];
+
+struct Bundles {
+    selected: Option<FluentBundle<FluentResource>>,
+    fallback: FluentBundle<FluentResource>,
+}
+
+static BUNDLES: OnceLock<Bundles> = OnceLock::new();
+
+fn bundle(language: &str) -> Option<FluentBundle<FluentResource>> {
+    let (_, source) = CATALOGS.iter().find(|(x, _)| *x == language)?;
+    let id: LanguageIdentifier = language.parse().ok()?;
+    let mut bundle = FluentBundle::new_concurrent(vec![id]);
+    // No bidi isolation marks around the arguments on a terminal
+    bundle.set_use_isolating(false);
+    bundle
+        .add_resource(FluentResource::try_new(source.to_string()).ok()?)
+        .ok()?;
+    Some(bundle)
+}
+
+/// Picks the catalog of a locale such as "fr-FR", English when there
+/// is none. Only the first call has an effect.
+pub fn init(locale: &str) {
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+    let _ = BUNDLES.set(Bundles {
+        selected: bundle(&language.to_lowercase()),
+        fallback: bundle("en").expect("the English catalog is invalid"),
+    });
+}
+
+fn bundles() -> &'static Bundles {
+    BUNDLES.get_or_init(|| Bundles {
+        selected: None,
+        fallback: bundle("en").expect("the English catalog is invalid"),
+    })
+}
+
+fn format(bundle: &FluentBundle<FluentResource>, id: &str, args: &FluentArgs) -> Option<String> {
+    let pattern = bundle.get_message(id)?.value()?;
+    let mut errors = vec![];
+    Some(
+        bundle
+            .format_pattern(pattern, Some(args), &mut errors)
+            .into_owned(),
+    )
+}
+
+/// Message `id` of the selected catalog, the message ID itself when
+/// no catalog has it
+pub fn tr(id: &str, args: Vec<(&str, FluentValue)>) -> String {
+    let args = FluentArgs::from_iter(args);
+    let bundles = bundles();
+    bundles
+        .selected
+        .as_ref()
+        .and_then(|x| format(x, id, &args))
+        .or_else(|| format(&bundles.fallback, id, &args))
+        .unwrap_or_else(|| id.to_string())
+}
+
+/// `t!("found", season = x)` formats the message "found" of the catalog
+#[macro_export]
+macro_rules! t {
+    ($id:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::i18n::tr(
+            $id,
+            vec![$((stringify!($name), $crate::i18n::FluentValue::from($value))),*],
+        )
+    };
+}
@@ -0,0 +1,236 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use crunchyroll_rs::common::StreamExt;
+use crunchyroll_rs::rating::RatingStar;
+use crunchyroll_rs::{Crunchyroll, MediaCollection};
+use mal_api::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+use crate::catalog::{Catalog, SeasonInfo};
+use crate::config::Config;
+use crate::mal::{MalWriter, read_mal_entries};
+use crate::matcher::{MatchOutcome, Matcher};
+use crate::sync::numbering_offset;
+use crate::{error, info, profile};
+
+/// Highest fully watched episode number of every season in the watch
+/// history, as numbered by Crunchyroll
+async fn watched_per_season(crunchyroll: &Crunchyroll) -> Result<HashMap<String, u32>> {
+    let mut output = HashMap::<String, u32>::new();
+    let mut history = crunchyroll.watch_history();
+
+    while let Some(entry) = profile::timed("history", history.next()).await {
+        let entry = entry?;
+        if !entry.fully_watched {
+            continue;
+        }
+        if let MediaCollection::Episode(episode) = entry.panel {
+            if let Some(episode_number) = episode.episode_number {
+                let watched = output.entry(episode.season_id).or_default();
+                *watched = (*watched).max(episode_number);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Episodes of the season watched, counted from its first one, with
+/// the episodes watched in any of its versions (dubs, subs)
+fn season_progress(watched: &HashMap<String, u32>, season: &SeasonInfo, offset: u32) -> u32 {
+    iter::once(&season.id)
+        .chain(season.versions.iter().map(|x| &x.id))
+        .filter_map(|x| watched.get(x))
+        .max()
+        .map_or(0, |x| x.saturating_sub(offset))
+}
+
+/// Whether the progress within the season covers all its episodes
+fn is_season_watched(season: &SeasonInfo, progress: u32) -> bool {
+    season.number_of_episodes > 0 && progress >= season.number_of_episodes
+}
+
+/// Logs a failed MAL update, or returns it with `fail_fast`
+fn failed(title: &str, e: anyhow::Error, fail_fast: bool) -> Result<()> {
+    if fail_fast {
+        return Err(e.context(title.to_string()));
+    }
+    error!("{}: {:#}", title, e);
+    Ok(())
+}
+
+/// Bumps the MAL progress of every entry whose matching
+/// Crunchyroll season has been watched further.
+pub async fn import_playheads(
+    catalog: &Catalog<'_>,
+    config: &Config,
+    fail_fast: bool,
+) -> Result<()> {
+    let watched = watched_per_season(catalog.crunchyroll()?).await?;
+    info!("{} seasons found in the watch history", watched.len());
+
+    let writer = MalWriter::new().await?;
+    let mut matcher = Matcher::new(catalog, config).await?;
+    let mut treated_ids = HashSet::<String>::new();
+
+    for elt in read_mal_entries(false, config.filters.nsfw).await? {
+        let (node, status) = (elt.node, elt.list_status.unwrap());
+        let mal_entry = matcher.entry(node);
+        let (anime_id, title) = (mal_entry.mal_id, &mal_entry.title);
+
+        info!("Querying {}", title);
+        let season = match matcher.find(&mal_entry, &treated_ids).await? {
+            MatchOutcome::Matched { season, .. } => season,
+            MatchOutcome::Unmatched { .. } => continue,
+        };
+        treated_ids.insert(season.id.clone());
+
+        let offset = numbering_offset(&catalog.episodes(&season.id).await?);
+        let episodes = season_progress(&watched, &season, offset);
+        if episodes == 0 {
+            continue;
+        }
+        let season_watched = is_season_watched(&season, episodes);
+        let already_completed = status.status == Some(UserAnimeListStatus::Completed);
+
+        if season_watched && !already_completed {
+            info!("Completing {} ({} episodes)", &title, episodes);
+            if let Err(e) = writer.complete(anime_id, episodes).await {
+                failed(title, e, fail_fast)?;
+            }
+            continue;
+        }
+        if episodes <= status.num_episodes_watched {
+            continue;
+        }
+
+        info!(
+            "Updating {} from {} to {} episodes",
+            &title, status.num_episodes_watched, episodes
+        );
+        if let Err(e) = writer.set_watched_episodes(anime_id, episodes).await {
+            failed(title, e, fail_fast)?;
+        }
+    }
+
+    matcher.save_mappings()?;
+    Ok(())
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum RatingRounding {
+    /// N stars become 2N (5 stars => 10)
+    Up,
+    /// N stars become 2N - 1 (5 stars => 9)
+    Down,
+}
+
+impl RatingRounding {
+    pub fn score(self, stars: u8) -> u8 {
+        match self {
+            Self::Up => 2 * stars,
+            Self::Down => 2 * stars - 1,
+        }
+    }
+}
+
+fn stars(rating: &RatingStar) -> u8 {
+    match rating {
+        RatingStar::OneStar => 1,
+        RatingStar::TwoStars => 2,
+        RatingStar::ThreeStars => 3,
+        RatingStar::FourStars => 4,
+        RatingStar::FiveStars => 5,
+    }
+}
+
+/// Copies the star rating of every matched series to the MAL score
+pub async fn import_ratings(
+    catalog: &Catalog<'_>,
+    config: &Config,
+    rounding: RatingRounding,
+    fail_fast: bool,
+) -> Result<()> {
+    let writer = MalWriter::new().await?;
+    let mut matcher = Matcher::new(catalog, config).await?;
+    let mut treated_ids = HashSet::<String>::new();
+
+    for elt in read_mal_entries(false, config.filters.nsfw).await? {
+        let (node, status) = (elt.node, elt.list_status.unwrap());
+        let mal_entry = matcher.entry(node);
+        let (anime_id, title) = (mal_entry.mal_id, &mal_entry.title);
+
+        info!("Querying {}", title);
+        let series = match matcher.find(&mal_entry, &treated_ids).await? {
+            MatchOutcome::Matched { series, season, .. } => {
+                treated_ids.insert(season.id);
+                series
+            }
+            MatchOutcome::Unmatched { .. } => continue,
+        };
+
+        let series = catalog.live_series(&series.id).await?;
+        let Some(rating) = profile::timed("rating", series.rating()).await?.rating else {
+            continue;
+        };
+        let score = rounding.score(stars(&rating));
+        if score == status.score {
+            continue;
+        }
+
+        info!("Scoring {} {} (was {})", &title, score, status.score);
+        if let Err(e) = writer.set_score(anime_id, score).await {
+            failed(title, e, fail_fast)?;
+        }
+    }
+
+    matcher.save_mappings()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::SeasonVersionInfo;
+
+    /// Second season of a show numbered from 13 to 24, with a dub
+    fn second_season() -> SeasonInfo {
+        SeasonInfo {
+            id: "S2".to_string(),
+            series_id: "SERIES".to_string(),
+            title: "Season 2".to_string(),
+            season_number: 2,
+            number_of_episodes: 12,
+            audio_locales: vec!["ja-JP".to_string()],
+            versions: vec![SeasonVersionInfo {
+                id: "S2-DUB".to_string(),
+                audio_locale: "en-US".to_string(),
+                original: false,
+            }],
+        }
+    }
+
+    #[test]
+    fn counts_from_the_first_episode_of_the_season() {
+        let season = second_season();
+        let watched = HashMap::from([("S2".to_string(), 13)]);
+        let progress = season_progress(&watched, &season, 12);
+        assert_eq!(progress, 1);
+        assert!(!is_season_watched(&season, progress));
+    }
+
+    #[test]
+    fn counts_every_version_of_the_season() {
+        let season = second_season();
+        let watched = HashMap::from([
+            ("S2".to_string(), 15),
+            ("S2-DUB".to_string(), 24),
+            ("S3".to_string(), 30),
+        ]);
+        let progress = season_progress(&watched, &season, 12);
+        assert_eq!(progress, 12);
+        assert!(is_season_watched(&season, progress));
+        assert_eq!(season_progress(&HashMap::new(), &season, 12), 0);
+    }
+}
@@ -0,0 +1,44 @@
+pub mod actions;
+pub mod availability;
+pub mod cassette;
+pub mod catalog;
+pub mod config;
+pub mod credentials;
+pub mod crunchylist;
+pub mod exclude;
+pub mod exit;
+pub mod franchise;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod i18n;
+pub mod import;
+pub mod logfile;
+pub mod mal;
+pub mod mapping;
+pub mod marker;
+pub mod matcher;
+pub mod metrics;
+pub mod missing;
+pub mod netflix;
+pub mod notify;
+pub mod notify_new;
+pub mod output;
+pub mod pause;
+pub mod ping;
+pub mod plan;
+pub mod profile;
+pub mod ratelimit;
+pub mod refresh;
+pub mod retry;
+pub mod schedule;
+pub mod script;
+pub mod search;
+pub mod source;
+pub mod state;
+pub mod sync;
+pub mod systemd;
+pub mod telemetry;
+pub mod transliterate;
+pub mod trigger;
+pub mod watchlist;
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::{Local, NaiveDate};
+use std::fmt::Arguments;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::LogConfig;
+use crate::output::{self, Level};
+
+/// Log file receiving every message, whatever the console verbosity.
+/// Once too large, or on a new day when rotating daily, it is renamed
+/// to `<file>.1`, the previous `<file>.1` to `<file>.2`, and so on.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+    max_bytes: u64,
+    daily: bool,
+    keep: usize,
+}
+
+static LOG_FILE: OnceLock<Mutex<LogFile>> = OnceLock::new();
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn open(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+impl LogFile {
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        let today = Local::now().date_naive();
+        let full = self.max_bytes > 0 && self.size + line.len() as u64 > self.max_bytes;
+        if self.size > 0 && (full || (self.daily && today != self.opened_on)) {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Starts copying the messages to `path`
+pub fn init(path: &Path, config: &LogConfig) -> Result<()> {
+    let file = open(path)?;
+    let metadata = file.metadata()?;
+    let opened_on = metadata
+        .modified()
+        .map(|x| chrono::DateTime::<Local>::from(x).date_naive())
+        .unwrap_or_else(|_| Local::now().date_naive());
+    let log_file = LogFile {
+        path: path.to_path_buf(),
+        file,
+        size: metadata.len(),
+        opened_on,
+        max_bytes: config.max_size_mb * 1024 * 1024,
+        daily: config.daily,
+        keep: config.keep,
+    };
+    let _ = LOG_FILE.set(Mutex::new(log_file));
+    Ok(())
+}
+
+/// Appends a timestamped message, if a log file is open.
+/// Failing to write the log never fails the run.
+pub fn write(level: Level, args: Arguments) {
+    let Some(log_file) = LOG_FILE.get() else {
+        return;
+    };
+    let line = format!(
+        "{} {:<7} {}\n",
+        Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+        level.name(),
+        args
+    );
+    let res = match log_file.lock() {
+        Ok(mut x) => x.write(&line),
+        Err(_) => return,
+    };
+    if let Err(e) = res {
+        output::notify(
+            Level::Warn,
+            format_args!("Could not write the log file: {:#}", e),
+        );
+    }
+}
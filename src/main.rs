@@ -1,327 +1,612 @@
-use anyhow::Result;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+mod cli;
+mod review;
+mod terminal;
+
+use anyhow::{Result, bail};
+use chrono::TimeDelta;
+use clap::{CommandFactory, Parser};
+use cli::{CatalogCommand, Cli, Command, ConfigCommand, ExcludeCommand, MappingsCommand};
 use crunchyroll_rs::common::StreamExt;
 use crunchyroll_rs::{Crunchyroll, Locale};
 use dotenvy;
-use mal_api::prelude::*;
-use reqwest::Response;
-use std::{collections::HashSet, env, thread, time::Duration};
-
-fn get_node_title(node: AnimeFields) -> String {
-    match node.alternative_titles {
-        Some(x) => match x.en {
-            Some(x) if x.len() > 0 => x,
-            _ => node.title,
-        },
-        None => node.title,
+use mal_2_crunchyroll::cassette::Cassette;
+use mal_2_crunchyroll::catalog::Catalog;
+use mal_2_crunchyroll::config::{self, Config};
+use mal_2_crunchyroll::exclude::ExcludeList;
+use mal_2_crunchyroll::exit::{AuthError, ConfigError, ExitStatus};
+use mal_2_crunchyroll::history::{self, RunHistory, RunRecord};
+use mal_2_crunchyroll::hooks::Hooks;
+use mal_2_crunchyroll::mapping::MappingStore;
+use mal_2_crunchyroll::marker::{MarkAsWatch, Marker};
+use mal_2_crunchyroll::matcher::{MalEntry, Matcher};
+use mal_2_crunchyroll::missing::KnownMissing;
+use mal_2_crunchyroll::plan::Plan;
+use mal_2_crunchyroll::profile::PhaseTimes;
+use mal_2_crunchyroll::schedule::{self, Schedule};
+use mal_2_crunchyroll::state::{self, Lock, PathOverrides};
+use mal_2_crunchyroll::sync::{EntryReport, SyncEngine, SyncEvent};
+use mal_2_crunchyroll::trigger::Trigger;
+use mal_2_crunchyroll::{
+    availability, credentials, debug, error, http, i18n, import, info, logfile, mal, metrics,
+    notify_new, output, pause, ping, profile, ratelimit, refresh, retry, search, source, success,
+    systemd, t, telemetry, trigger, warn,
+};
+use mal_api::prelude::AnimeListNode;
+use review::Verdict;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::{env, io, pin::pin, process::ExitCode, time::Duration};
+use terminal::{OutputFormat, Terminal};
+use tokio::sync::mpsc::Receiver;
+
+/// Prints the progress of a sync, returning the error that aborted it
+async fn run_sync(
+    cli: &Cli,
+    config: &Config,
+    catalog: &Catalog<'_>,
+    marker: Option<Marker<'_>>,
+    entries: Option<Vec<AnimeListNode>>,
+    record: &mut RunRecord,
+) -> Result<()> {
+    record.sync = true;
+    let mut engine = SyncEngine::new(cli.sync_options(config), config, catalog, marker);
+    if let Some(entries) = entries {
+        engine = engine.with_entries(entries);
     }
-}
-
-async fn read_mal_entries() -> Result<Vec<AnimeListNode>> {
-    let mal_username =
-        env::var("MAL_USERNAME").expect("'MAL_USERNAME' environment variable not found");
-
-    let client_id = MalClientId::try_from_env()?;
-    let api_client = AnimeApiClient::from(&client_id);
-
-    let mut output: Vec<AnimeListNode> = vec![];
-    let max_page_size = 1000;
-    let mut offset = 0;
-    let mut done = false;
+    let mut events = pin!(engine.run());
+    let started = PhaseTimes::now();
+    // Entry being processed, and the phase times when it started
+    let mut current: Option<(String, PhaseTimes)> = None;
+    let entry_times = |current: &Option<(String, PhaseTimes)>| {
+        if let Some((title, times)) = current {
+            debug!("{}: {}", title, PhaseTimes::now().since(times));
+        }
+    };
+    // Result of the entry being processed, printed once the next starts
+    let mut report: Option<EntryReport> = None;
+    let print_report = |report: Option<EntryReport>| {
+        if let (Some(x), OutputFormat::Jsonl) = (report, cli.output) {
+            terminal::json_line(&x);
+        }
+    };
 
-    while !done {
-        eprintln!("Reading");
-        thread::sleep(Duration::from_secs(2));
-        let query = GetUserAnimeList::builder(mal_username.as_str())
-            .enable_nsfw()
-            .offset(offset)
-            .limit(max_page_size)
-            .fields(&AnimeCommonFields(vec![
-                AnimeField::list_status,
-                AnimeField::title,
-                AnimeField::alternative_titles,
-                AnimeField::start_date,
-            ]))
-            .sort(UserAnimeListSort::AnimeStartDate)
-            .build()?;
-        let res = api_client.get_user_anime_list(&query).await;
-        match res {
-            Err(e) => {
-                eprintln!("Error while retrieving the list: {}", e);
-                done = true;
+    while let Some(event) = events.next().await {
+        record.observe(&event);
+        if let Some(x) = report.as_mut() {
+            x.observe(&event);
+        }
+        match event {
+            SyncEvent::EntryStarted {
+                mal_id,
+                title,
+                index,
+                total,
+            } => {
+                print_report(report.replace(EntryReport::new(mal_id, &title)));
+                entry_times(&current);
+                current = Some((title.clone(), PhaseTimes::now()));
+                systemd::status(&format!("Syncing entry {}/{}", index, total));
+                info!("{}", t!("querying", title = title))
             }
-            Ok(r) => {
-                done = r.data.len() != (max_page_size as usize);
-                output.extend(r.data.into_iter().filter(|elt| {
-                    let status = &elt.list_status;
-                    if status.is_none() {
-                        return false;
-                    }
-                    let status = status.as_ref().unwrap();
-                    if status.num_episodes_watched == 0 {
-                        return false;
-                    }
-
-                    true
-                }));
+            SyncEvent::Matched { season_title, .. } => {
+                success!("{}", t!("found", season = season_title))
+            }
+            SyncEvent::Marked { .. } => (),
+            SyncEvent::Skipped { title, reason, .. } => {
+                warn!("{}", t!("unmatched", title = title, reason = reason.name()))
+            }
+            SyncEvent::Failed {
+                mal_id: Some(mal_id),
+                error,
+            } => error!("{}: {}", mal_id, error),
+            SyncEvent::Failed {
+                mal_id: None,
+                error,
+            } => {
+                print_report(report.take());
+                bail!(error)
             }
         }
-
-        offset += max_page_size as u32;
     }
-    eprintln!("{} elements read", output.len());
+    print_report(report.take());
+    entry_times(&current);
+    let stats = &record.stats;
+    output::summary(format_args!(
+        "{}",
+        t!(
+            "run-summary",
+            matched = stats.matched,
+            marked = stats.marked,
+            unmatched = stats.unmatched,
+            failures = stats.failures
+        )
+    ));
+    info!(
+        "{}",
+        t!(
+            "phase-times",
+            times = PhaseTimes::now().since(&started).to_string()
+        )
+    );
+    Ok(())
+}
 
-    // We need to reverse the vector so the older seasons
-    // appear first
-    output.reverse();
-    Ok(output)
+/// Waits for `duration`, returning the entries named by a trigger
+/// received meanwhile, none when the time is up
+async fn wait_next(
+    triggers: &mut Option<Receiver<Trigger>>,
+    duration: Duration,
+) -> Option<Vec<u32>> {
+    let sleep = tokio::time::sleep(duration);
+    match triggers.as_mut() {
+        Some(triggers) => tokio::select! {
+            _ = sleep => None,
+            Some(x) = triggers.recv() => Some(x.ids()),
+        },
+        None => {
+            sleep.await;
+            None
+        }
+    }
 }
 
-fn same_title(p: &str, s: &str) -> bool {
-    let n = p.len();
-    if s.len() < n || n == 0 {
-        return false;
+/// Syncs the entries updated on MAL every `interval` seconds, or at
+/// the times of the configured schedule, until interrupted, and the
+/// ones named by the triggers received meanwhile. A failed poll
+/// doesn't stop the next ones.
+async fn watch<'a>(
+    cli: &Cli,
+    config: &Config,
+    catalog: &Catalog<'_>,
+    new_marker: impl AsyncFn() -> Result<Marker<'a>>,
+    interval: u64,
+    listen: Option<SocketAddr>,
+    record: &mut RunRecord,
+) -> Result<()> {
+    let mut triggers = match listen {
+        Some(addr) => {
+            let token = env_var("TRIGGER_TOKEN")?;
+            // An empty token would accept the requests without any
+            if token.trim().is_empty() {
+                return Err(ConfigError(t!("env-missing", name = "TRIGGER_TOKEN")).into());
+            }
+            Some(trigger::listen(addr, token).await?)
+        }
+        None => None,
+    };
+    let schedule = match &config.schedule {
+        Some(x) => Some(x.parse::<Schedule>()?),
+        None => None,
+    };
+    match &config.schedule {
+        Some(x) => info!("{}", t!("watch-scheduled", schedule = x.as_str())),
+        None => info!("{}", t!("watch-started", interval = interval)),
     }
-    /*
-       We need the minimal edit distance here because there is
-       discrepancies between MAL's naming & CR's naming.
-       Ex.:
-           - hitoribocchi no marumaru seikatsu vs. hitoribocchi no marumaruseikatsu
-           - ...
-       And the 0.125 value is just a guess. For a 20 letters title,
-       the maximum distance is 2.
-    */
-    let score = (levenshtein::levenshtein(p, &s[..n]) as f32) / (n as f32);
+    systemd::ready();
+    systemd::spawn_watchdog();
+    // Entries named by a trigger, none when woken up by the timer. The
+    // polls start at once, the scheduled syncs wait for their time.
+    let mut triggered = match &schedule {
+        Some(x) => {
+            systemd::status("Waiting for the next scheduled sync");
+            wait_next(&mut triggers, schedule::until_next(x)?).await
+        }
+        None => None,
+    };
+    loop {
+        let ids = triggered.clone().unwrap_or_default();
+        systemd::status("Polling the MAL list");
+        let res = async {
+            let entries = match ids.is_empty() {
+                true => mal::read_changed_entries(config.filters.nsfw && !cli.no_nsfw).await?,
+                false => mal::read_mal_list_entries(&ids).await?,
+            };
+            if entries.is_empty() {
+                return Ok(());
+            }
+            run_sync(
+                cli,
+                config,
+                catalog,
+                Some(new_marker().await?),
+                Some(entries),
+                record,
+            )
+            .await?;
+            catalog.save()
+        }
+        .await;
+        if let Err(e) = res {
+            error!("{:#}", e);
+        }
+        if schedule.is_some()
+            && triggered.is_none()
+            && let Err(e) = schedule::record_run()
+        {
+            warn!("Could not save the time of the scheduled sync: {:#}", e);
+        }
 
-    if score >= 0.01 {
-        eprintln!(
-            "[WARNING] {} => {} ({} {})",
-            s,
-            p,
-            score,
-            levenshtein::levenshtein(p, &s[..n])
-        );
+        systemd::status("Waiting for the next poll");
+        let duration = match &schedule {
+            Some(x) => schedule::until_next(x)?,
+            None => Duration::from_secs(interval),
+        };
+        triggered = wait_next(&mut triggers, duration).await;
     }
+}
 
-    score <= 0.125
+/// Reviews a plan file interactively, saving the result. Returns
+/// the plan when the reviewer wants it applied.
+async fn review_plan(catalog: &Catalog<'_>, path: &Path) -> Result<Option<Plan>> {
+    let mut plan = Plan::read(path)?;
+    match review::review(catalog, &mut plan).await? {
+        Verdict::Discard => Ok(None),
+        Verdict::Save { apply } => {
+            plan.write(path)?;
+            Ok(apply.then_some(plan))
+        }
+    }
 }
 
-struct MarkAsWatch<'a> {
-    crunchyroll: &'a Crunchyroll,
-    account_uuid: String,
-    current_bearer_token: String,
-    preferred_audio: String,
-    locale: String,
+/// Runs the matcher alone on a MAL ID or a title
+async fn explain_match(catalog: &Catalog<'_>, config: &Config, query: &str) -> Result<()> {
+    let mut matcher = Matcher::new(catalog, config).await?;
+    let entry = match query.parse::<u32>() {
+        Ok(anime_id) => {
+            let node = match catalog.is_offline() && !http::is_replaying() {
+                true => mal::read_cached_mal_entry(anime_id)?,
+                false => mal::read_mal_entry(anime_id).await?,
+            };
+            matcher.entry(node)
+        }
+        Err(_) => MalEntry::from_title(query),
+    };
+    print!("{}", matcher.explain(&entry).await?);
+    Ok(())
 }
 
-impl<'a> MarkAsWatch<'a> {
-    async fn new(
-        crunchyroll: &'a Crunchyroll,
-        preferred_audio: Locale,
-        locale: Locale,
-    ) -> Result<Self> {
-        let account = crunchyroll.account().await?;
-        let mut output = Self {
-            crunchyroll: &crunchyroll,
-            account_uuid: account.account_id,
-            current_bearer_token: "".to_string(),
-            preferred_audio: preferred_audio.to_string(),
-            locale: locale.to_string(),
-        };
+/// Required environment variable
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| ConfigError(t!("env-missing", name = name)).into())
+}
 
-        output.update_token().await?;
-        Ok(output)
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Some(Command::Completions { shell }) = cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        return ExitCode::SUCCESS;
     }
-
-    async fn update_token(&mut self) -> Result<()> {
-        self.current_bearer_token = self.crunchyroll.access_token().await;
-        Ok(())
+    output::set_sink(Box::new(Terminal::new(
+        cli.quiet,
+        cli.verbose,
+        cli.no_color,
+        cli.output,
+    )));
+    state::set_overrides(PathOverrides {
+        config: cli.config.clone(),
+        state_dir: cli.state_dir.clone(),
+        cache_dir: cli.cache_dir.clone(),
+    });
+    dotenvy::dotenv().ok();
+    if let Some(path) = state::env_file() {
+        dotenvy::from_path(path).ok();
     }
 
-    async fn _mark_internal(&mut self, content_id: &String) -> Result<Response> {
-        let query = self.crunchyroll.client().post(
-            format!("https://www.crunchyroll.com/content/v2/discover/{}/mark_as_watched/{}?preferred_audio_language={}&locale={}",
-                self.account_uuid,
-                content_id,
-                self.preferred_audio,
-                self.locale
-            )
-        )
-            .bearer_auth(&self.current_bearer_token)
-            .build()?;
-
-        Ok(self.crunchyroll.client().execute(query).await?)
+    if let Err(e) = telemetry::init() {
+        warn!("Could not start the telemetry export: {:#}", e);
     }
 
-    async fn mark(&mut self, content_id: &String) -> Result<()> {
-        let res = self._mark_internal(content_id).await?;
-
-        if res.status().as_u16() == 401 {
-            self.update_token().await?;
+    let res = run(&cli).await;
+    telemetry::shutdown();
+    if cli.timings {
+        eprint!("{}", profile::render());
+    }
+    if let Err(e) = &res {
+        logfile::write(output::Level::Error, format_args!("{:#}", e));
+        eprintln!("Error: {:?}", e);
+    }
+    ExitStatus::of(&res).into()
+}
 
-            self._mark_internal(content_id).await?.error_for_status()?;
-        } else {
-            res.error_for_status()?;
+/// Runs the command, recording it in the run history
+async fn run(cli: &Cli) -> Result<()> {
+    let _lock = Lock::acquire(cli.force)?;
+    match &cli.command {
+        Some(Command::History { limit }) => {
+            print!("{}", RunHistory::load()?.render(*limit)?);
+            return Ok(());
         }
-        Ok(())
+        Some(Command::Diff { runs, against }) => {
+            print!("{}", history::diff(runs, *against)?);
+            return Ok(());
+        }
+        _ => (),
     }
-}
 
-fn parse_date(x: &String) -> NaiveDate {
-    let mut year: i32 = 0;
-    let mut month: u32 = 0;
-    let mut day: u32 = 0;
+    let mut record = RunRecord::start(env::args().skip(1).collect::<Vec<_>>().join(" "));
+    let res = run_command(cli, &mut record).await;
+    record.finish(ExitStatus::of(&res));
+    ping::finish(&res).await;
+    telemetry::record_run(&record);
+    metrics::push(&record).await;
+    if let Err(e) = RunHistory::record(record) {
+        warn!("Could not save the run history: {:#}", e);
+    }
+    res
+}
 
-    let mut txt = x.chars();
+/// The settings of the run, the command line flags replacing the
+/// resolved ones
+fn resolve_config(cli: &Cli) -> Result<Config> {
+    let mut config = Config::resolve(cli.profile.as_deref())?;
+    if cli.locale.is_some() {
+        config.locale = cli.locale.clone();
+    }
+    if cli.no_nsfw {
+        config.filters.nsfw = false;
+    }
+    if !cli.audio.is_empty() {
+        config.preferred_audio = cli.audio.clone();
+    }
+    if let Some(days) = cli.date_tolerance {
+        config.matcher.date_tolerance_days = days;
+    }
+    Ok(config)
+}
 
-    for c in &mut txt {
-        if c.is_digit(10) {
-            year = 10 * year + c.to_digit(10).unwrap() as i32;
-            continue;
+/// Prints config.toml, or the settings the run would use
+fn show_config(cli: &Cli, resolved: bool) -> Result<()> {
+    if !resolved {
+        let path = state::config_file();
+        println!("# {}", path.display());
+        match std::fs::read_to_string(&path) {
+            Ok(x) => print!("{}", config::redact_file(&x)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => println!("# (missing)"),
+            Err(e) => return Err(e.into()),
         }
-        if c == '-' {
-            break;
+        return Ok(());
+    }
+    let mut config = resolve_config(cli)?;
+    config.redact();
+    print!("{}", toml::to_string_pretty(&config)?);
+    println!();
+    println!("# Environment");
+    for (name, value) in config::environment() {
+        match value {
+            Some(x) => println!("# {}={}", name, x),
+            None => println!("# {} (unset)", name),
         }
-        panic!("Invalid character in year {}", x);
     }
+    Ok(())
+}
 
-    for c in &mut txt {
-        if c.is_digit(10) {
-            month = 10 * month + c.to_digit(10).unwrap();
-            continue;
-        }
-        if c == '-' {
-            break;
+async fn run_command(cli: &Cli, record: &mut RunRecord) -> Result<()> {
+    let config = resolve_config(cli)?;
+    let language = config.language.clone().or(config.locale.clone());
+    i18n::init(&language.unwrap_or_default());
+    if let Some(Command::Config {
+        action: ConfigCommand::Show { resolved },
+    }) = &cli.command
+    {
+        return show_config(cli, *resolved);
+    }
+    if let Some(path) = cli.log_file.as_ref().or(config.log.file.as_ref()) {
+        logfile::init(path, &config.log)?;
+    }
+    record.config_hash = history::config_hash(&config).ok();
+    http::configure(&config.http);
+    if cli.record.is_some() {
+        http::record();
+    }
+    ratelimit::configure(&config.max_concurrency, &config.request_delay_ms);
+    metrics::configure(&config.metrics);
+    ping::configure(config.ping_url.as_ref());
+    ping::start().await;
+    pause::listen_signals();
+
+    if let Some(Command::Mappings { action }) = &cli.command {
+        let mut store = MappingStore::load()?;
+        match action {
+            MappingsCommand::Export { output, format } => {
+                let content = store.export(*format)?;
+                match output {
+                    Some(path) => fs::write(path, content)?,
+                    None => println!("{}", content),
+                }
+            }
+            MappingsCommand::Import { path, overwrite } => {
+                let count = store.import(path, *overwrite)?;
+                store.save()?;
+                info!("{}", t!("mappings-imported", count = count));
+            }
         }
-        panic!("Invalid character in month: {}", x);
+        return Ok(());
     }
 
-    for c in &mut txt {
-        if c.is_digit(10) {
-            day = 10 * day + c.to_digit(10).unwrap();
-            continue;
+    if let Some(Command::Exclude { action }) = &cli.command {
+        let mut list = ExcludeList::load(config.filters.exclude_file.as_deref())?;
+        let path = list.path().display().to_string();
+        match action {
+            ExcludeCommand::Add { mal_id, comment } => {
+                match list.add(*mal_id, comment.as_deref()) {
+                    true => success!("{}", t!("exclude-added", id = *mal_id, path = path)),
+                    false => info!("{}", t!("exclude-present", id = *mal_id)),
+                }
+            }
+            ExcludeCommand::Remove { mal_id } => match list.remove(*mal_id) {
+                true => success!("{}", t!("exclude-removed", id = *mal_id)),
+                false => warn!("{}", t!("exclude-absent", id = *mal_id)),
+            },
+            ExcludeCommand::List => {
+                for line in list.entries() {
+                    println!("{}", line);
+                }
+            }
         }
-        panic!("Invalid character in day: {}", x);
+        return match action {
+            ExcludeCommand::List => Ok(()),
+            _ => list.save(),
+        };
     }
 
-    NaiveDate::from_ymd_opt(year, month.max(1), day.max(1)).unwrap()
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenvy::dotenv().ok();
-
-    let email = env::var("EMAIL").expect("'EMAIL' environment variable not found");
-    let password = env::var("PASSWORD").expect("'PASSWORD' environment variable not found");
-
-    let preferred_audio = Locale::from(
-        env::var("PREFERRED_AUDIO").expect("'PREFERRED_AUDIO' environment variable not found"),
-    );
-    let locale =
-        Locale::from(env::var("CLOCALE").expect("'CLOCALE' environment variable not found"));
-
-    let crunchyroll = Crunchyroll::builder()
-        .preferred_audio_locale(preferred_audio.clone())
-        .login_with_credentials(email, password)
-        .await?;
-
-    let mut mark_as_watcher = MarkAsWatch::new(&crunchyroll, preferred_audio, locale).await?;
-
-    let mut treated_ids = HashSet::<String>::new();
-    let animes = read_mal_entries().await?;
-    let max_date_difference = chrono::TimeDelta::days(2 * 30);
-
-    for elt in animes {
-        let (node, status) = (elt.node, elt.list_status);
-        let air_start_date: Option<DateTime<Utc>> = match node.start_date.as_ref() {
-            None => None,
-            Some(x) => Utc
-                .from_local_datetime(&NaiveDateTime::new(parse_date(x), NaiveTime::default()))
-                .single(),
+    if cli.offline || cli.replay.is_some() {
+        let catalog = match &cli.replay {
+            Some(path) => {
+                let cassette = Cassette::read(path)?;
+                http::replay(cassette.requests);
+                Catalog::replaying(cassette.catalog)
+            }
+            None => Catalog::offline()?,
         };
-        // We can do it, the status-less entries
-        // have been filtered
-        let status = status.unwrap();
-
-        let title = get_node_title(node).to_lowercase();
-
-        eprintln!("Querying {}", &title);
-        let mut found = false;
-
-        let mut query_result = crunchyroll.query(&title);
-        if let Some(s) = query_result.series.next().await {
-            let series = s?;
-            eprintln!("Result '{}' '{}'", &series.title.to_lowercase(), &title);
-
-            if same_title(&series.title.to_lowercase(), &title) {
-                let seasons: Vec<crunchyroll_rs::Season> = series.seasons().await?;
-                'SEASON: for season in seasons {
-                    if treated_ids.contains(&season.id) {
-                        continue;
-                    }
-
-                    if season.title.to_lowercase().as_str() != title.as_str() {
-                        let mut valid_season = false;
-
-                        if let Some(date) = air_start_date {
-                            for episode in season.episodes().await? {
-                                if (episode.episode_air_date - date).abs() < max_date_difference {
-                                    valid_season = true;
-                                    break;
-                                }
-
-                                if episode.episode_air_date >= (date + max_date_difference) {
-                                    break 'SEASON;
-                                }
-                            }
-                        } else {
-                            eprintln!("[WARNING] No date has been found");
-                        }
-
-                        if !valid_season {
-                            continue;
-                        }
-                    }
+        return match &cli.command {
+            Some(Command::Match { query }) => explain_match(&catalog, &config, query).await,
+            Some(Command::Sync { .. }) | Some(Command::Plan { .. }) | None => {
+                run_sync(cli, &config, &catalog, None, None, record).await
+            }
+            Some(Command::Review { path }) => review_plan(&catalog, path).await.map(|_| ()),
+            _ => bail!(t!("offline-unsupported")),
+        };
+    }
 
-                    found = true;
-                    eprintln!("Found {}", &season.title);
-                    if status.num_episodes_watched == season.number_of_episodes {
-                        match mark_as_watcher.mark(&season.id).await {
-                            Ok(()) => (),
-                            Err(e) => {
-                                dbg!(e);
-                            }
-                        }
-                    } else {
-                        for episode in season.episodes().await? {
-                            if let Some(episode_number) = episode.episode_number {
-                                if episode_number > status.num_episodes_watched {
-                                    continue;
-                                }
-                                if episode_number == 0 {
-                                    // TODO: Check if this is necessary
-                                    println!("Found an episode 0 for {}", &season.title);
-                                    continue;
-                                }
-                            }
-                            match mark_as_watcher.mark(&episode.id).await {
-                                Ok(()) => (),
-                                Err(e) => {
-                                    dbg!(e);
-                                }
-                            }
-                        }
-                    }
-                    treated_ids.insert(season.title);
-                    break;
-                }
+    // Matching only reads the catalog, which users can try before
+    // trusting the tool with their credentials
+    let read_only = matches!(
+        &cli.command,
+        Some(Command::Match { .. })
+            | Some(Command::Plan { .. })
+            | Some(Command::Availability)
+            | Some(Command::Search { .. })
+    );
+    if cli.anonymous && !read_only {
+        bail!(t!("anonymous-unsupported"));
+    }
+    let anonymous = read_only
+        && (cli.anonymous
+            || credentials::stored("EMAIL").is_none()
+            || credentials::stored("PASSWORD").is_none());
+
+    let preferred_audio = match config.preferred_audio.first() {
+        Some(x) => Locale::from(x.clone()),
+        None => return Err(ConfigError(t!("env-missing", name = "PREFERRED_AUDIO")).into()),
+    };
+    let locale = Locale::from(match &config.locale {
+        Some(x) => x.clone(),
+        None => return Err(ConfigError(t!("env-missing", name = "CLOCALE")).into()),
+    });
+
+    let builder = Crunchyroll::builder()
+        .client(http::client()?)
+        .preferred_audio_locale(preferred_audio.clone());
+    let crunchyroll = match anonymous {
+        true => {
+            info!("{}", t!("anonymous-session"));
+            profile::timed("login", builder.login_anonymously()).await
+        }
+        false => {
+            let login = builder.login_with_credentials(
+                credentials::get("EMAIL", false)?,
+                credentials::get("PASSWORD", true)?,
+            );
+            profile::timed("login", login).await
+        }
+    }
+    .map_err(|e| AuthError(format!("Crunchyroll login failed: {}", e)))?;
+    let catalog = match (&cli.record, &cli.command) {
+        (Some(_), _) => Catalog::recording(&crunchyroll),
+        (None, Some(Command::Catalog { .. })) => Catalog::refreshing(&crunchyroll)?,
+        (None, _) => Catalog::online(&crunchyroll, TimeDelta::days(config.catalog.max_age_days))?,
+    };
+
+    let res = match &cli.command {
+        Some(Command::ImportPlayheads) => {
+            import::import_playheads(&catalog, &config, cli.fail_fast).await
+        }
+        Some(Command::ImportRatings { rounding }) => {
+            import::import_ratings(&catalog, &config, *rounding, cli.fail_fast).await
+        }
+        Some(Command::NotifyNew) => notify_new::notify_new(&catalog, &config, cli.fail_fast).await,
+        Some(Command::Availability) => {
+            print!("{}", availability::report(&catalog, &config).await?);
+            Ok(())
+        }
+        Some(Command::Match { query }) => explain_match(&catalog, &config, query).await,
+        Some(Command::Search { query, json }) => {
+            print!("{}", search::search(&crunchyroll, query, *json).await?);
+            Ok(())
+        }
+        Some(Command::Plan { .. }) => run_sync(cli, &config, &catalog, None, None, record).await,
+        Some(Command::Review { path }) => match review_plan(&catalog, path).await? {
+            Some(plan) => {
+                let mark_as_watcher =
+                    MarkAsWatch::new(&crunchyroll, preferred_audio, locale).await?;
+                let mut marker = Marker::new(mark_as_watcher, Hooks::new(&config.hooks))?;
+                plan.apply(&mut marker, cli.fail_fast).await
             }
+            None => Ok(()),
+        },
+        Some(Command::Apply { path, allow_stale }) => {
+            let plan = Plan::read(path)?;
+            plan.check(&catalog, *allow_stale).await?;
+            let mark_as_watcher = MarkAsWatch::new(&crunchyroll, preferred_audio, locale).await?;
+            let mut marker = Marker::new(mark_as_watcher, Hooks::new(&config.hooks))?;
+            plan.apply(&mut marker, cli.fail_fast).await
         }
-
-        if !found {
-            println!("{}", title);
+        Some(Command::Watch { interval, listen }) => {
+            let new_marker = async || {
+                let mark_as_watcher =
+                    MarkAsWatch::new(&crunchyroll, preferred_audio.clone(), locale.clone()).await?;
+                Marker::new(mark_as_watcher, Hooks::new(&config.hooks))
+            };
+            watch(
+                cli, &config, &catalog, new_marker, *interval, *listen, record,
+            )
+            .await
         }
+        Some(Command::Retry) => {
+            let mark_as_watcher = MarkAsWatch::new(&crunchyroll, preferred_audio, locale).await?;
+            let mut marker = Marker::new(mark_as_watcher, Hooks::new(&config.hooks))?;
+            let known_missing = KnownMissing::load()?.mal_ids(
+                config.locale.as_deref().unwrap_or_default(),
+                TimeDelta::days(config.catalog.missing_ttl_days),
+            );
+            retry::retry(&mut marker, &known_missing).await
+        }
+        Some(Command::Catalog {
+            action: CatalogCommand::Refresh,
+        }) => refresh::refresh(&catalog, &config).await,
+        Some(Command::Sync { .. })
+        | Some(Command::Mappings { .. })
+        | Some(Command::Exclude { .. })
+        | Some(Command::Config { .. })
+        | Some(Command::History { .. })
+        | Some(Command::Diff { .. })
+        | Some(Command::Completions { .. })
+        | None => {
+            let mark_as_watcher = MarkAsWatch::new(&crunchyroll, preferred_audio, locale).await?;
+            let mut marker = Marker::new(mark_as_watcher, Hooks::new(&config.hooks))?;
+            match cli.source_entries()? {
+                Some(entries) => {
+                    source::mark_entries(&catalog, &config, &mut marker, entries, cli.fail_fast)
+                        .await
+                        .map(|x| record.stats = x)
+                }
+                None => run_sync(cli, &config, &catalog, Some(marker), None, record).await,
+            }
+        }
+    };
+
+    catalog.save()?;
+    if let Some(path) = &cli.record {
+        let cassette = Cassette {
+            mal_list: mal::cached_list()?,
+            catalog: catalog.snapshot(),
+            requests: http::recorded(),
+        };
+        cassette.write(path)?;
     }
-
-    Ok(())
+    res
 }
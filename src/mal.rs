@@ -0,0 +1,629 @@
+use anyhow::{Context, Result, anyhow};
+use chrono::{Datelike, Local, NaiveDate};
+use clap::ValueEnum;
+use mal_api::oauth::{Authenticated, OauthClient, RedirectResponse};
+use mal_api::prelude::*;
+use reqwest::header::{ETAG, HeaderName, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{env, time::Duration};
+
+use crate::credentials;
+use crate::exit::{AuthError, ConfigError};
+use crate::http;
+use crate::output;
+use crate::ratelimit::{self, Service};
+use crate::state::{self, Persisted, state_dir};
+use crate::{info, warn};
+
+const MAL_OAUTH_FILE: &str = "mal-oauth.toml";
+
+const SETUP_GUIDE: &str = "\
+To give access to MyAnimeList:
+  1. Log in and open https://myanimelist.net/apiconfig, then \"Create ID\"
+  2. Pick the App Type \"other\" and any App Redirect URL,
+     e.g. http://localhost:8080/callback
+  3. Set in .env or in the environment:
+       MAL_CLIENT_ID=<the Client ID>
+       MAL_CLIENT_SECRET=<the Client Secret, for the import commands>
+       MAL_REDIRECT_URL=<the App Redirect URL>
+       MAL_USERNAME=<your MAL user name>";
+
+fn env_or_guide(name: &str) -> Result<String> {
+    match env::var(name) {
+        Ok(x) if x.len() > 0 => Ok(x),
+        _ => Err(ConfigError(format!("'{}' isn't set\n\n{}", name, SETUP_GUIDE)).into()),
+    }
+}
+
+/// The MAL user name, asked when missing on a terminal
+fn username() -> Result<String> {
+    credentials::get("MAL_USERNAME", false)
+        .map_err(|_| ConfigError(format!("'MAL_USERNAME' isn't set\n\n{}", SETUP_GUIDE)).into())
+}
+
+pub fn client_id() -> Result<MalClientId> {
+    env_or_guide("MAL_CLIENT_ID")?;
+    MalClientId::try_from_env()
+        .map_err(|e| ConfigError(format!("Invalid MAL client ID: {}\n\n{}", e, SETUP_GUIDE)).into())
+}
+
+/// Status of the MAL answer the error comes from, if it got one
+fn status_of(e: &anyhow::Error) -> Option<StatusCode> {
+    e.chain()
+        .find_map(|x| x.downcast_ref::<reqwest::Error>()?.status())
+}
+
+/// Explanation of the MAL answers telling the credentials are wrong
+fn auth_hint(e: &anyhow::Error) -> Option<&'static str> {
+    match status_of(e) {
+        // Error code of the OAuth token exchange, not an HTTP status
+        _ if e.to_string().contains("invalid_client") => {
+            Some("MAL doesn't know this client, check MAL_CLIENT_ID and MAL_CLIENT_SECRET")
+        }
+        Some(StatusCode::UNAUTHORIZED) => Some(
+            "MAL rejected the credentials (401), check MAL_CLIENT_ID or delete mal-oauth.toml to log in again",
+        ),
+        Some(StatusCode::FORBIDDEN) => Some(
+            "MAL refused the access (403), the list of MAL_USERNAME may be private or the client revoked",
+        ),
+        _ => None,
+    }
+}
+
+/// Turns authentication failures into actionable errors,
+/// passing the other ones through
+pub fn auth_error(e: anyhow::Error) -> anyhow::Error {
+    match auth_hint(&e) {
+        Some(hint) => AuthError(format!("{}: {}\n\n{}", hint, e, SETUP_GUIDE)).into(),
+        None => e,
+    }
+}
+
+/// Attempts of a request whose answer could not be read
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Answers cut short, the failed requests being already retried
+/// by `http::send`
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|x| {
+        x.downcast_ref::<reqwest::Error>()
+            .is_some_and(|x| x.status().is_none() && (x.is_timeout() || x.is_body()))
+    })
+}
+
+/// Runs a MAL request, reading its answer again when it got cut
+/// short, with an exponential backoff. The other errors, HTTP ones
+/// included, are returned at once.
+pub async fn with_retries<T, F, Fut>(what: &str, mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Ok(x) => return Ok(x),
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                let delay = Duration::from_secs(2u64.pow(attempt));
+                warn!(
+                    "{} failed ({}), attempt {}/{} in {}s",
+                    what,
+                    e,
+                    attempt + 1,
+                    MAX_ATTEMPTS,
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) if attempt > 1 => {
+                return Err(
+                    auth_error(e).context(format!("{} failed after {} attempts", what, attempt))
+                );
+            }
+            Err(e) => return Err(auth_error(e)),
+        }
+    }
+}
+
+/// Entries of each page of the list, the most MAL allows
+const PAGE_SIZE: u16 = 1000;
+
+const LIST_URL: &str = "https://api.myanimelist.net/v2/users";
+
+/// Fields of the list entries. The tags are not part of the default
+/// list status, so its fields are all spelled out.
+const LIST_FIELDS: &str = concat!(
+    "list_status{status,score,num_episodes_watched,is_rewatching,",
+    "updated_at,start_date,finish_date,tags},title,alternative_titles,start_date,start_season,status,",
+    "num_episodes,nsfw,genres,media_type,average_episode_duration"
+);
+
+/// A page of the list with the validators MAL sent along,
+/// sent back when reading the page again
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedPage {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    data: Vec<AnimeListNode>,
+}
+
+/// Pages of the last full read, by URL
+#[derive(Serialize, Deserialize, Default)]
+struct PageCache {
+    pages: BTreeMap<String, CachedPage>,
+}
+
+impl Persisted for PageCache {
+    const FILE_NAME: &'static str = "mal-list-pages.json";
+    const CACHE: bool = true;
+}
+
+#[derive(Deserialize)]
+struct ListPage {
+    data: Vec<AnimeListNode>,
+}
+
+fn list_page_url(
+    mal_username: &str,
+    nsfw: bool,
+    sort: &str,
+    offset: u32,
+    limit: u16,
+) -> Result<Url> {
+    let mut url = Url::parse_with_params(
+        &format!("{}/{}/animelist", LIST_URL, mal_username),
+        &[
+            ("fields", LIST_FIELDS.to_string()),
+            ("sort", sort.to_string()),
+            ("offset", offset.to_string()),
+            ("limit", limit.to_string()),
+        ],
+    )?;
+    if nsfw {
+        url.query_pairs_mut().append_pair("nsfw", "true");
+    }
+    Ok(url)
+}
+
+/// Reads a page of the list, sending the validators of the previous
+/// copy so that an unchanged page comes back empty (304) rather than
+/// being parsed again. Pages without validators are always read.
+async fn read_list_page(
+    client: &Client,
+    url: &Url,
+    cached: Option<&CachedPage>,
+) -> Result<CachedPage> {
+    let mut request = client
+        .get(url.clone())
+        .header("X-MAL-CLIENT-ID", env_or_guide("MAL_CLIENT_ID")?);
+    if let Some(x) = cached.and_then(|x| x.etag.as_ref()) {
+        request = request.header(IF_NONE_MATCH, x);
+    }
+    if let Some(x) = cached.and_then(|x| x.last_modified.as_ref()) {
+        request = request.header(IF_MODIFIED_SINCE, x);
+    }
+
+    let response = http::send("mal list", request).await?;
+    Ok(match (response.status(), cached) {
+        (StatusCode::NOT_MODIFIED, Some(cached)) => cached.clone(),
+        _ => {
+            let response = response.error_for_status()?;
+            let header = |name: HeaderName| {
+                let value = response.headers().get(name)?;
+                value.to_str().ok().map(String::from)
+            };
+            let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+            let page: ListPage = response.json().await?;
+            CachedPage {
+                etag,
+                last_modified,
+                data: page.data,
+            }
+        }
+    })
+}
+
+/// Entries read by each poll of `read_changed_entries`
+const RECENT_PAGE_SIZE: u16 = 100;
+
+/// Copy of the list as of the last online run
+#[derive(Serialize, Deserialize, Default)]
+struct MalListCache {
+    entries: Vec<AnimeListNode>,
+}
+
+impl Persisted for MalListCache {
+    const FILE_NAME: &'static str = "mal-list.json";
+    const CACHE: bool = true;
+}
+
+/// Drops the status-less entries, and the unwatched
+/// ones when `watched_only` is set
+pub fn filter_entries(entries: Vec<AnimeListNode>, watched_only: bool) -> Vec<AnimeListNode> {
+    entries
+        .into_iter()
+        .filter(|elt| keep_entry(elt, watched_only))
+        .collect()
+}
+
+fn keep_entry(elt: &AnimeListNode, watched_only: bool) -> bool {
+    let status = &elt.list_status;
+    if status.is_none() {
+        return false;
+    }
+    let status = status.as_ref().unwrap();
+    if watched_only && status.num_episodes_watched == 0 {
+        return false;
+    }
+
+    true
+}
+
+/// Tags the user put on the entry, lowercased
+pub fn tags(elt: &AnimeListNode) -> Vec<String> {
+    elt.list_status
+        .as_ref()
+        .and_then(|x| x.tags.as_ref())
+        .map(|x| x.iter().map(|x| x.trim().to_lowercase()).collect())
+        .unwrap_or_default()
+}
+
+/// Genres and themes of the anime, lowercased
+pub fn genres(node: &AnimeFields) -> Vec<String> {
+    node.genres
+        .as_ref()
+        .map(|x| x.iter().map(|x| x.name.to_lowercase()).collect())
+        .unwrap_or_default()
+}
+
+/// Entries rated gray or black by MAL, the ones read before the
+/// rating was fetched never are
+pub fn is_nsfw(node: &AnimeFields) -> bool {
+    !matches!(node.nsfw, None | Some(NSFW::White))
+}
+
+/// Airing status of an anime on MAL
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AiringStatus {
+    #[value(name = "finished")]
+    Finished,
+    #[value(name = "currently_airing")]
+    CurrentlyAiring,
+    #[value(name = "not_yet_aired")]
+    NotYetAired,
+}
+
+impl AiringStatus {
+    /// Entries read before the status was fetched never match
+    pub fn matches(self, node: &AnimeFields) -> bool {
+        let expected = match self {
+            Self::Finished => AnimeStatus::FinishedAiring,
+            Self::CurrentlyAiring => AnimeStatus::CurrentlyAiring,
+            Self::NotYetAired => AnimeStatus::NotYetAired,
+        };
+        node.status == Some(expected)
+    }
+}
+
+/// Position of a broadcast season, counting from year 0
+fn season_index(year: u32, season: &Season) -> i64 {
+    let quarter = match season {
+        Season::Winter => 0,
+        Season::Spring => 1,
+        Season::Summer => 2,
+        Season::Fall => 3,
+    };
+    4 * year as i64 + quarter
+}
+
+/// Crunchyroll tag of the simulcast season the anime started in,
+/// e.g. "fall-2023"
+pub fn simulcast_id(node: &AnimeFields) -> Option<String> {
+    let start_season = node.start_season.as_ref()?;
+    let season = match start_season.season {
+        Season::Winter => "winter",
+        Season::Spring => "spring",
+        Season::Summer => "summer",
+        Season::Fall => "fall",
+    };
+    Some(format!("{}-{}", season, start_season.year))
+}
+
+/// Whether an anime is airing, or started airing last season and may
+/// have just finished. Entries read before these fields were fetched
+/// are never considered airing.
+pub fn is_airing(node: &AnimeFields, today: NaiveDate) -> bool {
+    if node.status == Some(AnimeStatus::CurrentlyAiring) {
+        return true;
+    }
+    let Some(start_season) = node.start_season.as_ref() else {
+        return false;
+    };
+    let current = 4 * today.year() as i64 + (today.month0() / 3) as i64;
+    let started = season_index(start_season.year, &start_season.season);
+    node.status != Some(AnimeStatus::NotYetAired) && (0..=1).contains(&(current - started))
+}
+
+/// Reads the user's list. When `watched_only` is set, entries
+/// without any watched episode are dropped, and the NSFW ones
+/// are only included with `nsfw`.
+pub async fn read_mal_entries(watched_only: bool, nsfw: bool) -> Result<Vec<AnimeListNode>> {
+    let mal_username = username()?;
+
+    client_id()?;
+    let client = http::client()?;
+    let previous_pages: PageCache = state::load()?;
+    let mut pages = PageCache::default();
+
+    let mut output: Vec<AnimeListNode> = vec![];
+    let mut offset = 0;
+    let mut done = false;
+
+    while !done {
+        info!("Reading");
+        let url = list_page_url(&mal_username, nsfw, "anime_start_date", offset, PAGE_SIZE)?;
+        let cached = previous_pages.pages.get(url.as_str());
+        let page = with_retries("Reading the list", || read_list_page(&client, &url, cached))
+            .await
+            .with_context(|| format!("Could not read the list after {} entries", output.len()))?;
+        done = page.data.len() != (PAGE_SIZE as usize);
+        output.extend(page.data.iter().cloned());
+        if page.etag.is_some() || page.last_modified.is_some() {
+            pages.pages.insert(url.to_string(), page);
+        }
+        offset += PAGE_SIZE as u32;
+    }
+
+    // We need to reverse the vector so the older seasons
+    // appear first
+    output.reverse();
+
+    let cache = MalListCache { entries: output };
+    state::save(&cache)?;
+    state::save(&pages)?;
+    let output = filter_entries(cache.entries, watched_only);
+    info!("{} elements read", output.len());
+    Ok(output)
+}
+
+fn updated_at(elt: &AnimeListNode) -> Option<&str> {
+    elt.list_status.as_ref().map(|x| x.updated_at.as_str())
+}
+
+/// Watched entries updated on MAL since the copy saved by the last
+/// online run, which gets updated. Only the most recently updated
+/// page is read, for polling to stay cheap. Without any copy yet,
+/// the whole list is saved and nothing is returned.
+pub async fn read_changed_entries(nsfw: bool) -> Result<Vec<AnimeListNode>> {
+    let mut cache: MalListCache = state::load()?;
+    if cache.entries.is_empty() {
+        read_mal_entries(false, nsfw).await?;
+        return Ok(vec![]);
+    }
+
+    let mal_username = username()?;
+    client_id()?;
+    let client = http::client()?;
+    let url = list_page_url(&mal_username, nsfw, "list_updated_at", 0, RECENT_PAGE_SIZE)?;
+    let recent = with_retries("Reading the list", || read_list_page(&client, &url, None)).await?;
+
+    let mut changed = vec![];
+    for elt in recent.data {
+        match cache.entries.iter_mut().find(|x| x.node.id == elt.node.id) {
+            Some(x) if updated_at(x) == updated_at(&elt) => continue,
+            Some(x) => *x = elt.clone(),
+            None => cache.entries.push(elt.clone()),
+        }
+        changed.push(elt);
+    }
+    if !changed.is_empty() {
+        state::save(&cache)?;
+    }
+    Ok(filter_entries(changed, true))
+}
+
+/// Same as `read_mal_entries`, from the copy saved by the last online run
+pub fn read_cached_mal_entries(watched_only: bool) -> Result<Vec<AnimeListNode>> {
+    let output = filter_entries(cached_list()?, watched_only);
+    info!("{} elements read from the cache", output.len());
+    Ok(output)
+}
+
+/// The whole list saved by the last online run
+pub fn cached_list() -> Result<Vec<AnimeListNode>> {
+    let cache: MalListCache = state::load()?;
+    Ok(cache.entries)
+}
+
+pub fn read_cached_mal_entry(anime_id: u32) -> Result<AnimeFields> {
+    let cache: MalListCache = state::load()?;
+    cache
+        .entries
+        .into_iter()
+        .map(|x| x.node)
+        .find(|x| x.id == anime_id)
+        .ok_or_else(|| anyhow!("{} isn't in the cached list", anime_id))
+}
+
+const ANIME_URL: &str = "https://api.myanimelist.net/v2/anime";
+
+/// Same fields as the list, without the user's progress
+const DETAIL_FIELDS: &str = concat!(
+    "title,alternative_titles,start_date,start_season,status,",
+    "num_episodes,nsfw,genres,media_type,average_episode_duration"
+);
+
+/// Fetches the `fields` of an anime through `http::send`, for the
+/// answer to be recorded with the run. The user's own fields take
+/// the OAuth `token`.
+pub(crate) async fn anime_details(
+    endpoint: &'static str,
+    anime_id: u32,
+    fields: &str,
+    token: Option<&str>,
+) -> Result<AnimeDetails> {
+    let url = Url::parse_with_params(
+        &format!("{}/{}", ANIME_URL, anime_id),
+        &[("fields", fields)],
+    )?;
+    let mut request = http::client()?
+        .get(url)
+        .header("X-MAL-CLIENT-ID", env_or_guide("MAL_CLIENT_ID")?);
+    if let Some(x) = token {
+        request = request.bearer_auth(x);
+    }
+    let response = http::send(endpoint, request).await?.error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// Fetches a single anime, with the same fields as the list
+pub async fn read_mal_entry(anime_id: u32) -> Result<AnimeFields> {
+    client_id()?;
+    let details = with_retries("Reading the anime", || {
+        anime_details("mal details", anime_id, DETAIL_FIELDS, None)
+    })
+    .await?;
+    Ok(details.shown)
+}
+
+/// Fetches single entries of the user's list, with the same fields
+/// as the list. Reading the user's progress takes the OAuth client.
+pub async fn read_mal_list_entries(anime_ids: &[u32]) -> Result<Vec<AnimeListNode>> {
+    let client = oauth_client().await?;
+    let token = client.get_access_token_secret();
+    let fields = format!("{},my_list_status", DETAIL_FIELDS);
+
+    // Independent lookups, as many at once as MAL allows
+    let concurrency = ratelimit::concurrency(Service::Mal);
+    let details = ratelimit::lookup_all(concurrency, anime_ids.to_vec(), |anime_id| {
+        let (fields, token) = (&fields, token.as_str());
+        with_retries("Reading the anime", move || {
+            anime_details("mal details", anime_id, fields, Some(token))
+        })
+    })
+    .await;
+
+    let mut output = vec![];
+    for (&anime_id, details) in anime_ids.iter().zip(details) {
+        let node = details?.shown;
+        if node.my_list_status.is_none() {
+            warn!("{} isn't in the list", anime_id);
+        }
+        output.push(AnimeListNode {
+            list_status: node.my_list_status.clone(),
+            node,
+        });
+    }
+    Ok(filter_entries(output, true))
+}
+
+const JIKAN_URL: &str = "https://api.jikan.moe/v4/anime";
+
+#[derive(Deserialize)]
+struct JikanEpisodes {
+    data: Vec<JikanEpisode>,
+}
+
+#[derive(Deserialize)]
+struct JikanEpisode {
+    title: String,
+}
+
+/// Title of the first episode of an anime, read from Jikan since
+/// the MAL API doesn't list the episodes
+pub async fn first_episode_title(anime_id: u32) -> Result<Option<String>> {
+    let url = format!("{}/{}/episodes", JIKAN_URL, anime_id);
+    let response = http::send("jikan episodes", http::client()?.get(url)).await?;
+    let episodes: JikanEpisodes = response.error_for_status()?.json().await?;
+    Ok(episodes.data.into_iter().next().map(|x| x.title))
+}
+
+/// Returns an OAuth client allowed to modify the user's list.
+/// The tokens are kept in the state directory, the first call
+/// walks the user through the authorization flow.
+pub async fn oauth_client() -> Result<OauthClient<Authenticated>> {
+    let path = state_dir()?.join(MAL_OAUTH_FILE);
+
+    if let Ok(client) = OauthClient::load_from_config(&path) {
+        match client.refresh().await {
+            Ok(client) => {
+                client.save_to_config(&path)?;
+                return Ok(client);
+            }
+            Err(e) => warn!("Could not refresh the MAL token: {}", e),
+        }
+    }
+
+    client_id()?;
+    env_or_guide("MAL_CLIENT_SECRET")?;
+    env_or_guide("MAL_REDIRECT_URL")?;
+    let mut client = OauthClient::new()?;
+    let answer = output::ask(
+        &format!(
+            "Authorize the application by visiting:\n{}\nThen paste the URL you have been redirected to:",
+            client.generate_auth_url()
+        ),
+        false,
+    )?;
+    let response = RedirectResponse::try_from(answer)?;
+
+    let client = client
+        .authenticate(response)
+        .await
+        .map_err(|e| auth_error(anyhow!("{}", e)))?;
+    client.save_to_config(&path)?;
+    Ok(client)
+}
+
+pub struct MalWriter {
+    token: String,
+}
+
+impl MalWriter {
+    pub async fn new() -> Result<Self> {
+        let client = oauth_client().await?;
+        Ok(Self {
+            token: client.get_access_token_secret().to_string(),
+        })
+    }
+
+    /// Changes the given fields of the list status of the anime
+    async fn update(&self, anime_id: u32, fields: &[(&str, String)]) -> Result<()> {
+        let url = format!("{}/{}/my_list_status", ANIME_URL, anime_id);
+        with_retries("Updating the list", || async {
+            let request = http::client()?
+                .patch(&url)
+                .bearer_auth(&self.token)
+                .form(fields);
+            http::send("mal update", request)
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_watched_episodes(&self, anime_id: u32, episodes: u32) -> Result<()> {
+        let fields = [("num_watched_episodes", episodes.to_string())];
+        self.update(anime_id, &fields).await
+    }
+
+    pub async fn set_score(&self, anime_id: u32, score: u8) -> Result<()> {
+        self.update(anime_id, &[("score", score.to_string())]).await
+    }
+
+    /// Sets the entry as completed, finished today
+    pub async fn complete(&self, anime_id: u32, episodes: u32) -> Result<()> {
+        let today = Local::now().date_naive().format("%Y-%m-%d").to_string();
+        let fields = [
+            ("status", "completed".to_string()),
+            ("num_watched_episodes", episodes.to_string()),
+            ("finish_date", today),
+        ];
+        self.update(anime_id, &fields).await
+    }
+}
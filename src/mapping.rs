@@ -0,0 +1,346 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use crate::config::{Comparison, MatcherConfig};
+use crate::http;
+use crate::state::{self, Persisted};
+use crate::warn;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Provenance {
+    /// Found by the matching heuristics
+    Matcher,
+    /// Written by hand
+    Manual,
+    /// Merged from a mapping file shared by someone else
+    Imported,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mapping {
+    pub mal_id: u32,
+    /// Named as in the anime-lists format too
+    #[serde(alias = "crunchyroll_id")]
+    pub series_id: String,
+    #[serde(alias = "crunchyroll_season_id")]
+    pub season_id: String,
+    /// Between 0 and 1, 1 meaning the titles were identical
+    pub confidence: f32,
+    pub provenance: Provenance,
+    /// When the mapping was found, written or imported
+    #[serde(default)]
+    pub resolved_at: Option<DateTime<Utc>>,
+    /// File or URL an imported mapping comes from
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ExportFormat {
+    /// The mappings as stored
+    #[default]
+    Native,
+    /// anime-lists style JSON, keyed by `mal_id` like their lists
+    AnimeListsJson,
+}
+
+/// One mapping in the anime-lists JSON format
+#[derive(Serialize)]
+struct AnimeListsEntry<'a> {
+    mal_id: u32,
+    crunchyroll_id: &'a str,
+    crunchyroll_season_id: &'a str,
+    provenance: Provenance,
+    confidence: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+}
+
+impl<'a> From<&'a Mapping> for AnimeListsEntry<'a> {
+    fn from(x: &'a Mapping) -> Self {
+        Self {
+            mal_id: x.mal_id,
+            crunchyroll_id: &x.series_id,
+            crunchyroll_season_id: &x.season_id,
+            provenance: x.provenance,
+            confidence: x.confidence,
+            resolved_at: x.resolved_at,
+            source: x.source.as_deref(),
+        }
+    }
+}
+
+/// Matcher settings replaced for a single MAL entry
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MatcherOverride {
+    pub date_tolerance_days: Option<i64>,
+    pub similarity_threshold: Option<f32>,
+    pub comparison: Option<Comparison>,
+    pub check_dates: Option<bool>,
+    /// Searched when the series isn't found, see `episode_fallback`
+    pub episode_title: Option<String>,
+}
+
+impl MatcherOverride {
+    pub fn apply(&self, config: &MatcherConfig) -> MatcherConfig {
+        let mut output = config.clone();
+        if let Some(x) = self.date_tolerance_days {
+            output.date_tolerance_days = x;
+        }
+        if let Some(x) = self.similarity_threshold {
+            output.similarity_threshold = x;
+        }
+        if let Some(x) = self.comparison {
+            output.comparison = x;
+        }
+        if let Some(x) = self.check_dates {
+            output.check_dates = x;
+        }
+        output
+    }
+}
+
+/// MAL ID => Crunchyroll season resolutions
+#[derive(Serialize, Deserialize, Default)]
+pub struct MappingStore {
+    mappings: BTreeMap<u32, Mapping>,
+    /// Written by hand for the entries the global settings don't suit
+    #[serde(default)]
+    overrides: BTreeMap<u32, MatcherOverride>,
+    /// Community mappings, preferred to the ones of the matcher
+    /// but not to the ones written by hand
+    #[serde(skip)]
+    fallback: BTreeMap<u32, Mapping>,
+}
+
+impl Persisted for MappingStore {
+    const FILE_NAME: &'static str = "mappings.json";
+}
+
+impl MappingStore {
+    pub fn load() -> Result<Self> {
+        state::load()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        state::save(self)
+    }
+
+    /// The mapping written by hand, else the community one,
+    /// else the local one found, imported or otherwise
+    pub fn get(&self, mal_id: u32) -> Option<&Mapping> {
+        let local = self.mappings.get(&mal_id);
+        local
+            .filter(|x| x.provenance == Provenance::Manual)
+            .or_else(|| self.fallback.get(&mal_id))
+            .or(local)
+    }
+
+    /// The MAL entries resolved to a season of the series
+    pub fn mapped_to_series(&self, series_id: &str) -> Vec<u32> {
+        let ids: BTreeSet<u32> = self
+            .mappings
+            .keys()
+            .chain(self.fallback.keys())
+            .copied()
+            .collect();
+        ids.into_iter()
+            .filter_map(|x| self.get(x))
+            .filter(|x| x.series_id == series_id)
+            .map(|x| x.mal_id)
+            .collect()
+    }
+
+    pub fn matcher_override(&self, mal_id: u32) -> Option<&MatcherOverride> {
+        self.overrides.get(&mal_id)
+    }
+
+    pub fn set_fallback(&mut self, community: &CommunityMappings) {
+        self.fallback.clear();
+        // The first URL of the configuration wins
+        for mapping in community.lists.iter().rev().flat_map(|(_, x)| x) {
+            self.fallback.insert(mapping.mal_id, mapping.clone());
+        }
+    }
+
+    pub fn insert(&mut self, mapping: Mapping) {
+        self.mappings.insert(mapping.mal_id, mapping);
+    }
+
+    /// The mappings in the given format
+    pub fn export(&self, format: ExportFormat) -> Result<String> {
+        let mappings: Vec<&Mapping> = self.mappings.values().collect();
+        let content = match format {
+            ExportFormat::Native => serde_json::to_string_pretty(&mappings)?,
+            ExportFormat::AnimeListsJson => {
+                let entries: Vec<AnimeListsEntry> =
+                    mappings.iter().map(|x| AnimeListsEntry::from(*x)).collect();
+                serde_json::to_string_pretty(&entries)?
+            }
+        };
+        Ok(content)
+    }
+
+    /// Merges a shared mapping file, in any export format. Existing mappings are kept
+    /// unless `overwrite` is set, manual ones are never replaced.
+    pub fn import(&mut self, path: &Path, overwrite: bool) -> Result<usize> {
+        let mappings: Vec<Mapping> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let mut count = 0;
+
+        for mut mapping in mappings {
+            match self.mappings.get(&mapping.mal_id) {
+                Some(x) if x.provenance == Provenance::Manual => continue,
+                Some(_) if !overwrite => continue,
+                _ => (),
+            }
+            mapping.provenance = Provenance::Imported;
+            mapping.resolved_at.get_or_insert_with(Utc::now);
+            mapping.source = Some(path.display().to_string());
+            self.insert(mapping);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Last downloaded version of every community mapping list
+#[derive(Serialize, Deserialize, Default)]
+pub struct CommunityMappings {
+    // Kept in the configuration order
+    lists: Vec<(String, Vec<Mapping>)>,
+}
+
+impl Persisted for CommunityMappings {
+    const FILE_NAME: &'static str = "community-mappings.json";
+    const CACHE: bool = true;
+}
+
+impl CommunityMappings {
+    /// Downloads the configured lists, falling back to the cached
+    /// copy of the ones that can't be fetched (or all of them offline).
+    pub async fn fetch(urls: &[String], offline: bool) -> Result<Self> {
+        let cached: Self = state::load()?;
+        if offline {
+            return Ok(cached);
+        }
+
+        let mut output = Self::default();
+        let client = http::client()?;
+
+        for url in urls {
+            if !url.starts_with("https://") {
+                warn!("Ignoring non-HTTPS mapping list {}", url);
+                continue;
+            }
+
+            let res = async {
+                let mappings: Vec<Mapping> = http::send("mapping list", client.get(url))
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                anyhow::Ok(mappings)
+            }
+            .await;
+
+            let mut mappings = match res {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Could not download {}: {}", url, e);
+                    match cached.lists.iter().find(|(x, _)| x == url) {
+                        Some((_, x)) => x.clone(),
+                        None => continue,
+                    }
+                }
+            };
+            for mapping in &mut mappings {
+                mapping.provenance = Provenance::Imported;
+                mapping.source = Some(url.clone());
+            }
+            output.lists.push((url.clone(), mappings));
+        }
+
+        state::save(&output)?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(mal_id: u32, season_id: &str, provenance: Provenance) -> Mapping {
+        Mapping {
+            mal_id,
+            series_id: "SERIES".to_string(),
+            season_id: season_id.to_string(),
+            confidence: 1.0,
+            provenance,
+            resolved_at: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn prefers_manual_then_community_mappings() {
+        let mut store = MappingStore::default();
+        store.insert(mapping(1, "MANUAL", Provenance::Manual));
+        store.insert(mapping(2, "MATCHER", Provenance::Matcher));
+        store.insert(mapping(3, "MATCHER", Provenance::Matcher));
+        let community = CommunityMappings {
+            lists: vec![(
+                "https://example.com/mappings.json".to_string(),
+                vec![
+                    mapping(1, "COMMUNITY", Provenance::Imported),
+                    mapping(2, "COMMUNITY", Provenance::Imported),
+                ],
+            )],
+        };
+        store.set_fallback(&community);
+
+        let season = |mal_id| store.get(mal_id).map(|x| x.season_id.as_str());
+        assert_eq!(season(1), Some("MANUAL"));
+        assert_eq!(season(2), Some("COMMUNITY"));
+        assert_eq!(season(3), Some("MATCHER"));
+        assert_eq!(season(4), None);
+        assert_eq!(store.mapped_to_series("SERIES"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn imports_the_exports() {
+        let mut store = MappingStore::default();
+        store.insert(mapping(1, "MATCHER", Provenance::Matcher));
+        store.insert(mapping(2, "MANUAL", Provenance::Manual));
+
+        for format in [ExportFormat::Native, ExportFormat::AnimeListsJson] {
+            let path = std::env::temp_dir().join(format!(
+                "mal-2-crunchyroll-mappings-{}-{:?}.json",
+                std::process::id(),
+                format
+            ));
+            fs::write(&path, store.export(format).unwrap()).unwrap();
+            let mut imported = MappingStore::default();
+            let count = imported.import(&path, false);
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(count.unwrap(), 2, "{:?}", format);
+            for mal_id in [1, 2] {
+                let (before, after) = (store.get(mal_id).unwrap(), imported.get(mal_id).unwrap());
+                assert_eq!(after.series_id, before.series_id);
+                assert_eq!(after.season_id, before.season_id);
+                assert_eq!(after.confidence, before.confidence);
+                assert_eq!(after.provenance, Provenance::Imported);
+            }
+        }
+    }
+}
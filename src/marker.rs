@@ -0,0 +1,135 @@
+use anyhow::Result;
+use crunchyroll_rs::{Crunchyroll, Locale};
+use reqwest::StatusCode;
+
+use crate::actions::{ActionKind, ActionLog};
+use crate::hooks::{HookEntry, Hooks};
+use crate::http::{self, Auth};
+use crate::retry::RetryQueue;
+use crate::{profile, warn};
+
+pub struct MarkAsWatch<'a> {
+    crunchyroll: &'a Crunchyroll,
+    account_uuid: String,
+    current_bearer_token: String,
+    default_audio: String,
+    preferred_audio: String,
+    locale: String,
+}
+
+impl<'a> MarkAsWatch<'a> {
+    pub async fn new(
+        crunchyroll: &'a Crunchyroll,
+        preferred_audio: Locale,
+        locale: Locale,
+    ) -> Result<Self> {
+        let account = profile::timed("account", crunchyroll.account()).await?;
+        let mut output = Self {
+            crunchyroll: &crunchyroll,
+            account_uuid: account.account_id,
+            current_bearer_token: "".to_string(),
+            default_audio: preferred_audio.to_string(),
+            preferred_audio: preferred_audio.to_string(),
+            locale: locale.to_string(),
+        };
+
+        output.bearer_token(true).await?;
+        Ok(output)
+    }
+
+    /// Audio of the next marks, the first preferred one by default
+    pub fn set_audio(&mut self, audio: Option<&str>) {
+        self.preferred_audio = audio.unwrap_or(&self.default_audio).to_string();
+    }
+
+    pub fn audio(&self) -> &str {
+        &self.preferred_audio
+    }
+
+    pub async fn mark(&mut self, content_id: &String) -> Result<StatusCode> {
+        let query = self.crunchyroll.client().post(
+            format!("https://www.crunchyroll.com/content/v2/discover/{}/mark_as_watched/{}?preferred_audio_language={}&locale={}",
+                self.account_uuid,
+                content_id,
+                self.preferred_audio,
+                self.locale
+            )
+        );
+        let res = http::send_authed("mark", query, self).await?;
+        Ok(res.error_for_status()?.status())
+    }
+}
+
+impl Auth for MarkAsWatch<'_> {
+    async fn bearer_token(&mut self, refresh: bool) -> Result<String> {
+        if refresh || self.current_bearer_token.is_empty() {
+            self.current_bearer_token = self.crunchyroll.access_token().await;
+        }
+        Ok(self.current_bearer_token.clone())
+    }
+}
+
+/// Marks contents on behalf of MAL entries, running the
+/// configured hooks around and logging every action.
+/// Failed marks are kept in the retry queue.
+pub struct Marker<'a> {
+    mark_as_watcher: MarkAsWatch<'a>,
+    hooks: Hooks,
+    actions: ActionLog,
+    retry_queue: RetryQueue,
+}
+
+impl<'a> Marker<'a> {
+    pub fn new(mark_as_watcher: MarkAsWatch<'a>, hooks: Hooks) -> Result<Self> {
+        Ok(Self {
+            mark_as_watcher,
+            hooks,
+            actions: ActionLog::open()?,
+            retry_queue: RetryQueue::load()?,
+        })
+    }
+
+    pub fn actions(&self) -> &ActionLog {
+        &self.actions
+    }
+
+    pub fn retry_queue(&self) -> &RetryQueue {
+        &self.retry_queue
+    }
+
+    /// Audio of the next marks, see `MarkAsWatch::set_audio`
+    pub fn set_audio(&mut self, audio: Option<&str>) {
+        self.mark_as_watcher.set_audio(audio);
+    }
+
+    pub async fn mark(&mut self, entry: &HookEntry<'_>, content_id: &String) -> Result<()> {
+        self.hooks.pre_mark(entry, content_id).await?;
+        let res = self.mark_as_watcher.mark(content_id).await;
+        self.actions.record(
+            ActionKind::Mark,
+            content_id,
+            Some(entry.mal_id),
+            &res,
+            res.as_ref().ok().map(|x| x.as_u16()),
+        );
+        if let Err(e) = self.hooks.post_mark(entry, content_id, res.is_ok()).await {
+            warn!("{}", e);
+        }
+
+        // The queue is saved right away, so an interrupted run loses nothing
+        let queue_changed = match &res {
+            Ok(_) => self.retry_queue.remove(content_id),
+            Err(e) => {
+                let audio = self.mark_as_watcher.audio();
+                self.retry_queue.push(entry, content_id, audio, e);
+                true
+            }
+        };
+        if queue_changed {
+            if let Err(e) = self.retry_queue.save() {
+                warn!("Could not save the retry queue: {}", e);
+            }
+        }
+        res.map(|_| ())
+    }
+}
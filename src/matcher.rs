@@ -0,0 +1,1358 @@
+use anyhow::{Context, Result};
+use chrono::{
+    DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc,
+};
+use mal_api::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::catalog::{Catalog, SeasonInfo, SeriesInfo};
+use crate::config::{Comparison, Config, MatcherConfig, SimilarityCurve};
+use crate::franchise::Chain;
+use crate::mal;
+use crate::mapping::{CommunityMappings, Mapping, MappingStore, Provenance};
+use crate::missing::KnownMissing;
+use crate::script::{Decision, EntryInfo, MatchScript};
+use crate::transliterate::romanize;
+use crate::{info, success, warn};
+
+pub fn get_node_title(node: AnimeFields) -> String {
+    match node.alternative_titles {
+        Some(x) => match x.en {
+            Some(x) if x.len() > 0 => x,
+            _ => node.title,
+        },
+        None => node.title,
+    }
+}
+
+/// Languages Crunchyroll appends to the titles of its dubs, e.g.
+/// "(English Dub)" or "(Russian)", and their audio locale
+const DUB_LANGUAGES: [(&str, &str); 15] = [
+    ("english", "en-US"),
+    ("german", "de-DE"),
+    ("french", "fr-FR"),
+    ("spanish", "es-419"),
+    ("latin american spanish", "es-419"),
+    ("castilian", "es-ES"),
+    ("european spanish", "es-ES"),
+    ("portuguese", "pt-BR"),
+    ("brazilian portuguese", "pt-BR"),
+    ("italian", "it-IT"),
+    ("russian", "ru-RU"),
+    ("arabic", "ar-SA"),
+    ("hindi", "hi-IN"),
+    ("tamil", "ta-IN"),
+    ("telugu", "te-IN"),
+];
+
+/// Splits a Crunchyroll title into the title without its dub suffix,
+/// lowercased and romanized, and the audio locale the suffix names. "(OmU)", the
+/// original with German subtitles, is the Japanese audio.
+pub fn split_dub_suffix(title: &str) -> (String, Option<&'static str>) {
+    let title = romanize(title.trim()).to_lowercase();
+    let suffix = title
+        .strip_suffix(')')
+        .and_then(|x| x.rsplit_once('('))
+        .map(|(rest, inner)| (rest.trim_end(), inner.trim()));
+    let Some((rest, inner)) = suffix else {
+        return (title, None);
+    };
+    let language = inner
+        .strip_suffix(" dubbed")
+        .or(inner.strip_suffix(" dub"))
+        .unwrap_or(inner);
+    let locale = match language {
+        "dub" | "dubbed" => None,
+        "omu" => Some("ja-JP"),
+        _ => match DUB_LANGUAGES.iter().find(|(x, _)| *x == language) {
+            Some((_, locale)) => Some(*locale),
+            None => return (title, None),
+        },
+    };
+    (rest.to_string(), locale)
+}
+
+/// Crunchyroll title compared with the MAL ones
+fn cr_title(title: &str) -> String {
+    split_dub_suffix(title).0
+}
+
+/// What the descriptors ending a season title tell about it, e.g.
+/// "Part 2", "Cour 2", "Season 3", "2nd Season" or "(Sub)"
+#[derive(Debug, Default, PartialEq)]
+pub struct SeasonTitle {
+    /// Title without the descriptors
+    pub base: String,
+    pub season: Option<u32>,
+    /// Part or cour
+    pub part: Option<u32>,
+    pub subtitled: bool,
+}
+
+fn descriptor_number(x: &str) -> Option<u32> {
+    match x {
+        "i" => Some(1),
+        "ii" => Some(2),
+        "iii" => Some(3),
+        "iv" => Some(4),
+        "v" => Some(5),
+        "vi" => Some(6),
+        _ => x.parse().ok(),
+    }
+}
+
+/// Parses the descriptors at the end of a lowercased title
+pub fn parse_season_title(title: &str) -> SeasonTitle {
+    let mut output = SeasonTitle::default();
+    let mut rest = title.trim();
+    loop {
+        rest = rest.trim_end_matches([' ', ':', '-', ',', '–']);
+        // Parenthesized descriptors, "(sub)" or "(part 2)"
+        if let Some((before, inner)) = rest.strip_suffix(')').and_then(|x| x.rsplit_once('(')) {
+            let inner = parse_season_title(inner);
+            match inner.base.as_str() {
+                "sub" | "subbed" | "subtitled" => output.subtitled = true,
+                "" => {
+                    output.season = output.season.or(inner.season);
+                    output.part = output.part.or(inner.part);
+                }
+                _ => break,
+            }
+            rest = before;
+            continue;
+        }
+
+        let mut words = rest.rsplitn(3, ' ');
+        let (last, previous) = (words.next().unwrap_or_default(), words.next());
+        let before = |n: usize| {
+            let mut words = rest.rsplitn(n + 1, ' ').skip(n);
+            words.next().unwrap_or_default()
+        };
+        match (previous, descriptor_number(last)) {
+            (Some("part" | "cour"), Some(n)) if output.part.is_none() => {
+                output.part = Some(n);
+                rest = before(2);
+            }
+            (Some("season"), Some(n)) if output.season.is_none() => {
+                output.season = Some(n);
+                rest = before(2);
+            }
+            _ if last == "season" && output.season.is_none() => {
+                let number = previous.and_then(|x| {
+                    let digits = x.trim_end_matches(|c: char| c.is_alphabetic());
+                    digits.parse().ok().filter(|_| digits.len() < x.len())
+                });
+                match number {
+                    Some(n) => {
+                        output.season = Some(n);
+                        rest = before(2);
+                    }
+                    None => break,
+                }
+            }
+            _ => break,
+        }
+    }
+    output.base = rest.to_string();
+    output
+}
+
+/// Whether a Crunchyroll season title names the same season as the
+/// MAL title, once the descriptors are understood: "Part 2" is the
+/// same as "(Part 2)", and no part the same as "Part 1"
+fn same_season_title(season_title: &str, title: &str) -> bool {
+    let (season, entry) = (
+        parse_season_title(&cr_title(season_title)),
+        parse_season_title(title),
+    );
+    season.base == entry.base
+        && season.part.unwrap_or(1) == entry.part.unwrap_or(1)
+        && season.season.unwrap_or(1) == entry.season.unwrap_or(1)
+}
+
+/// Title length at which the scaled threshold equals the configured one
+const REFERENCE_LENGTH: f32 = 20.0;
+
+/// The first `n` characters of `s`, the titles not always being ASCII
+fn head(s: &str, n: usize) -> String {
+    s.chars().take(n).collect()
+}
+
+/// Edit distance between `p` and the beginning of `s`, divided by the length of `p`.
+/// With the scaled curve, short titles are compared with the whole of `s`
+/// so that "k" doesn't match every title starting with a k.
+fn title_distance(p: &str, s: &str, config: &MatcherConfig) -> Option<f32> {
+    let (n, length) = (p.chars().count(), s.chars().count());
+    if length < n || n == 0 {
+        return None;
+    }
+    if config.similarity_curve == SimilarityCurve::Scaled
+        && (n as f32) < REFERENCE_LENGTH / 4.0
+        && length != n
+    {
+        return None;
+    }
+    Some((levenshtein::levenshtein(p, &head(s, n)) as f32) / (n as f32))
+}
+
+/// Highest normalized distance accepted for a title of `n` characters
+fn similarity_threshold(n: usize, config: &MatcherConfig) -> f32 {
+    match config.similarity_curve {
+        SimilarityCurve::Fixed => config.similarity_threshold,
+        SimilarityCurve::Scaled => {
+            config.similarity_threshold * (n as f32 / REFERENCE_LENGTH).sqrt()
+        }
+    }
+}
+
+fn tokens(title: &str) -> Vec<&str> {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Added to the token distance for every pair of words in reverse order
+const ORDER_PENALTY: f32 = 0.05;
+
+/// One minus the Dice coefficient of the words of both titles, words
+/// a typo apart counting as the same, plus a small penalty for the
+/// words in another order
+fn token_distance(p: &str, s: &str) -> Option<f32> {
+    let (p, s) = (tokens(p), tokens(s));
+    if p.is_empty() || s.is_empty() {
+        return None;
+    }
+    let same = |x: &str, y: &str| {
+        x == y || (x.len() > 4 && y.len() > 4 && levenshtein::levenshtein(x, y) <= 1)
+    };
+    let positions: Vec<usize> = p
+        .iter()
+        .filter_map(|x| s.iter().position(|y| same(x, y)))
+        .collect();
+    let dice = 2.0 * positions.len() as f32 / (p.len() + s.len()) as f32;
+    let inversions = positions.windows(2).filter(|x| x[0] > x[1]).count();
+    Some((1.0 - dice + ORDER_PENALTY * inversions as f32).min(1.0))
+}
+
+/// Returns the normalized distance when both titles are deemed the same
+fn same_title(p: &str, s: &str, config: &MatcherConfig) -> Option<f32> {
+    let prefix = same_prefix(p, s, config);
+    match (config.comparison, prefix) {
+        (Comparison::Prefix, _) | (_, Some(_)) => prefix,
+        (Comparison::Tokens, None) => {
+            let score = token_distance(p, s)?;
+            if score < 1.0 {
+                warn!("{} => {} ({} on the words)", s, p, score);
+            }
+            (score <= config.token_threshold).then_some(score)
+        }
+    }
+}
+
+/// Normalized edit distance with the beginning of `s`, when close enough
+fn same_prefix(p: &str, s: &str, config: &MatcherConfig) -> Option<f32> {
+    /*
+       We need the minimal edit distance here because there is
+       discrepancies between MAL's naming & CR's naming.
+       Ex.:
+           - hitoribocchi no marumaru seikatsu vs. hitoribocchi no marumaruseikatsu
+           - ...
+       And the 0.125 value is just a guess. For a 20 letters title,
+       the maximum distance is 2.
+    */
+    let score = title_distance(p, s, config)?;
+
+    if score >= 0.01 {
+        warn!(
+            "{} => {} ({} {})",
+            s,
+            p,
+            score,
+            levenshtein::levenshtein(p, &head(s, p.chars().count()))
+        );
+    }
+
+    (score <= similarity_threshold(p.chars().count(), config)).then_some(score)
+}
+
+/// Similarity bar below which a search result isn't
+/// even considered as a plausible candidate
+const PLAUSIBLE_THRESHOLD: f32 = 0.5;
+
+fn plausible_title(p: &str, s: &str) -> bool {
+    if p.contains(s) || s.contains(p) {
+        return true;
+    }
+    let n = p.len().max(s.len());
+    n > 0 && (levenshtein::levenshtein(p, s) as f32) / (n as f32) <= PLAUSIBLE_THRESHOLD
+}
+
+fn parse_date(x: &String) -> NaiveDate {
+    let mut year: i32 = 0;
+    let mut month: u32 = 0;
+    let mut day: u32 = 0;
+
+    let mut txt = x.chars();
+
+    for c in &mut txt {
+        if c.is_digit(10) {
+            year = 10 * year + c.to_digit(10).unwrap() as i32;
+            continue;
+        }
+        if c == '-' {
+            break;
+        }
+        panic!("Invalid character in year {}", x);
+    }
+
+    for c in &mut txt {
+        if c.is_digit(10) {
+            month = 10 * month + c.to_digit(10).unwrap();
+            continue;
+        }
+        if c == '-' {
+            break;
+        }
+        panic!("Invalid character in month: {}", x);
+    }
+
+    for c in &mut txt {
+        if c.is_digit(10) {
+            day = 10 * day + c.to_digit(10).unwrap();
+            continue;
+        }
+        panic!("Invalid character in day: {}", x);
+    }
+
+    NaiveDate::from_ymd_opt(year, month.max(1), day.max(1)).unwrap()
+}
+
+/// Start of the MAL start date in the given zone, as an instant
+/// comparable with the Crunchyroll air dates
+pub fn air_start_date(node: &AnimeFields, offset: FixedOffset) -> Option<DateTime<Utc>> {
+    match node.start_date.as_ref() {
+        None => None,
+        Some(x) => offset
+            .from_local_datetime(&NaiveDateTime::new(parse_date(x), NaiveTime::default()))
+            .single()
+            .map(|x| x.to_utc()),
+    }
+}
+
+/// The parts of a MAL entry the matcher looks at
+pub struct MalEntry {
+    /// 0 for a bare title, MAL IDs start at 1
+    pub mal_id: u32,
+    /// Lowercased
+    pub title: String,
+    pub air_start_date: Option<DateTime<Utc>>,
+    /// 0 when unknown
+    pub num_episodes: u32,
+    pub chain: Option<Chain>,
+    /// Season number given by sources other than MAL
+    pub season_number: Option<usize>,
+    /// Crunchyroll simulcast season of the MAL start season
+    pub simulcast: Option<String>,
+    pub media_type: Option<AnimeMediaType>,
+    /// Average episode duration in seconds, 0 when unknown
+    pub episode_duration: u32,
+    /// When the user started and finished watching, now if not
+    /// finished yet
+    pub watch_window: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl MalEntry {
+    pub fn from_title(title: &str) -> Self {
+        Self {
+            mal_id: 0,
+            title: romanize(title).to_lowercase(),
+            air_start_date: None,
+            num_episodes: 0,
+            chain: None,
+            season_number: None,
+            simulcast: None,
+            media_type: None,
+            episode_duration: 0,
+            watch_window: None,
+        }
+    }
+}
+
+pub enum MatchOutcome {
+    Matched {
+        series: SeriesInfo,
+        season: SeasonInfo,
+        confidence: f32,
+    },
+    // The candidate is the closest series returned by the search, if any
+    Unmatched {
+        candidate: Option<SeriesInfo>,
+        reason: FailureReason,
+    },
+}
+
+/// Why an entry couldn't be matched, as written in the reports
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// Crunchyroll returned nothing for the title
+    NoSearchResults,
+    /// The closest series is titled too differently
+    TitleMismatch,
+    /// No season aired around the MAL start date
+    DateMismatch,
+    /// The episodes of the movie or special last too long or too short
+    DurationMismatch,
+    /// Every season of the series went to other entries
+    NoSeasonLeft,
+    /// Recently found missing from the catalog of the region
+    RegionLocked,
+    /// Not searched, Crunchyroll having had nothing for the title
+    /// in the region recently
+    KnownMissing,
+    /// Vetoed by the match script
+    Vetoed,
+    /// Crunchyroll or MAL failed to answer
+    ApiError,
+}
+
+impl FailureReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoSearchResults => "no_search_results",
+            Self::TitleMismatch => "title_mismatch",
+            Self::DateMismatch => "date_mismatch",
+            Self::DurationMismatch => "duration_mismatch",
+            Self::NoSeasonLeft => "no_season_left",
+            Self::RegionLocked => "region_locked",
+            Self::KnownMissing => "known_missing",
+            Self::Vetoed => "vetoed",
+            Self::ApiError => "api_error",
+        }
+    }
+}
+
+/// Relative difference tolerated between the MAL episode duration
+/// and the Crunchyroll one, for the movies and specials
+const DURATION_TOLERANCE: f32 = 0.5;
+
+/// First locale of `audio` the season is available in
+fn preferred_audio<'b>(season: &SeasonInfo, audio: &'b [String]) -> Option<&'b str> {
+    audio
+        .iter()
+        .find(|x| season.audio_locales.contains(x))
+        .map(|x| x.as_str())
+}
+
+/// Audio in which the season gets marked, the one of the season
+/// itself when it isn't available in any preferred audio
+pub fn audio_for<'b>(season: &'b SeasonInfo, audio: &'b [String]) -> Option<&'b str> {
+    preferred_audio(season, audio).or(season.audio_locales.first().map(|x| x.as_str()))
+}
+
+/// The version of the season in the first preferred audio it exists
+/// in, the original one when there is none. Seasons in a preferred
+/// audio already, or without versions, are kept.
+pub async fn available_version(
+    catalog: &Catalog<'_>,
+    season: SeasonInfo,
+    audio: &[String],
+) -> Result<SeasonInfo> {
+    if audio.is_empty() || preferred_audio(&season, audio).is_some() {
+        return Ok(season);
+    }
+    let version = audio
+        .iter()
+        .find_map(|locale| season.versions.iter().find(|x| &x.audio_locale == locale))
+        .or_else(|| season.versions.iter().find(|x| x.original));
+    match version {
+        Some(x) if x.id != season.id => {
+            info!(
+                "'{}' isn't available in {}, marking its {} version",
+                &season.title,
+                audio.join("/"),
+                &x.audio_locale
+            );
+            catalog.season(&x.id).await
+        }
+        _ => Ok(season),
+    }
+}
+
+/// Keeps, among the versions of every season number, the ones in the
+/// first audio locale of `audio` that any of them offers. The seasons
+/// in none of these locales are only dropped when another version is.
+/// Seasons without audio locales are assumed in the one of their
+/// dub suffix, if any.
+fn prefer_audio(seasons: Vec<SeasonInfo>, audio: &[String]) -> Vec<SeasonInfo> {
+    let rank = |season: &SeasonInfo| {
+        let dub = split_dub_suffix(&season.title).1;
+        audio
+            .iter()
+            .position(|x| match season.audio_locales.is_empty() {
+                true => dub == Some(x.as_str()),
+                false => season.audio_locales.contains(x),
+            })
+            .unwrap_or(audio.len())
+    };
+    let mut best = HashMap::<u32, usize>::new();
+    for season in &seasons {
+        let x = best.entry(season.season_number).or_insert(usize::MAX);
+        *x = (*x).min(rank(season));
+    }
+    seasons
+        .into_iter()
+        .filter(|x| rank(x) == best[&x.season_number])
+        .collect()
+}
+
+pub struct Matcher<'a> {
+    catalog: &'a Catalog<'a>,
+    mappings: MappingStore,
+    rewrites: Vec<(Regex, String)>,
+    script: Option<MatchScript>,
+    config: MatcherConfig,
+    start_date_offset: FixedOffset,
+    season_designation: Regex,
+    /// Audio locales by order of preference
+    audio: Vec<String>,
+    /// Series found for every franchise, so that its other
+    /// entries don't search Crunchyroll again
+    franchises: Mutex<HashMap<String, SeriesInfo>>,
+    missing: KnownMissing,
+    missing_ttl: TimeDelta,
+    /// Crunchyroll locale, the catalog depending on the region
+    region: String,
+    /// Steps of the matching being explained, see `record`
+    steps: Mutex<Option<Vec<String>>>,
+}
+
+impl<'a> Matcher<'a> {
+    pub async fn new(catalog: &'a Catalog<'a>, config: &Config) -> Result<Self> {
+        let mut mappings = MappingStore::load()?;
+        if config.mapping_urls.len() > 0 {
+            let community =
+                CommunityMappings::fetch(&config.mapping_urls, catalog.is_offline()).await?;
+            mappings.set_fallback(&community);
+        }
+
+        let mut rewrites = vec![];
+        for rule in &config.rewrite {
+            let regex = Regex::new(&rule.pattern)
+                .with_context(|| format!("Invalid rewrite pattern '{}'", rule.pattern))?;
+            rewrites.push((regex, rule.replacement.clone()));
+        }
+
+        let script = match &config.match_script {
+            Some(path) => Some(MatchScript::load(path)?),
+            None => None,
+        };
+
+        let start_date_offset = config
+            .matcher
+            .start_date_offset
+            .parse::<FixedOffset>()
+            .with_context(|| {
+                format!(
+                    "Invalid start date offset '{}'",
+                    config.matcher.start_date_offset
+                )
+            })?;
+
+        Ok(Self {
+            catalog,
+            mappings,
+            rewrites,
+            script,
+            config: config.matcher.clone(),
+            start_date_offset,
+            season_designation: Regex::new(
+                r"\s+(?:(?:season|part|cour)\s*\d+|\d+(?:st|nd|rd|th) season|\d+|ii|iii|iv|v|vi)$",
+            )?,
+            audio: config.preferred_audio.clone(),
+            franchises: Mutex::new(HashMap::new()),
+            missing: KnownMissing::load()?,
+            missing_ttl: TimeDelta::days(config.catalog.missing_ttl_days),
+            region: config.locale.clone().unwrap_or_default(),
+            steps: Mutex::new(None),
+        })
+    }
+
+    pub fn entry(&self, node: AnimeFields) -> MalEntry {
+        MalEntry {
+            mal_id: node.id,
+            air_start_date: air_start_date(&node, self.start_date_offset),
+            num_episodes: node.num_episodes.unwrap_or(0),
+            chain: None,
+            season_number: None,
+            simulcast: mal::simulcast_id(&node),
+            media_type: node.media_type.clone(),
+            episode_duration: node.average_episode_duration.unwrap_or(0),
+            watch_window: None,
+            title: romanize(&get_node_title(node)).to_lowercase(),
+        }
+    }
+
+    /// Dates between which the user watched the entry, as
+    /// recorded on MAL
+    pub fn watch_window(
+        &self,
+        status: &UserAnimeListStatus,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let to_utc = |x: &String| {
+            self.start_date_offset
+                .from_local_datetime(&NaiveDateTime::new(parse_date(x), NaiveTime::default()))
+                .single()
+                .map(|x| x.to_utc())
+        };
+        let start = to_utc(status.start_date.as_ref()?)?;
+        let finish = match status.finish_date.as_ref() {
+            // Up to the end of the day
+            Some(x) => to_utc(x)? + TimeDelta::days(1),
+            None => Utc::now(),
+        };
+        Some((start, finish))
+    }
+
+    /// Saves the mappings, and the titles found missing
+    pub fn save_mappings(&self) -> Result<()> {
+        self.mappings.save()?;
+        self.missing.save()
+    }
+
+    /// The MAL entries resolved to a season of the series, this run
+    /// or a previous one
+    pub fn mapped_to_series(&self, series_id: &str) -> Vec<u32> {
+        self.mappings.mapped_to_series(series_id)
+    }
+
+    /// Whether Crunchyroll recently had nothing for the entry
+    pub fn is_known_missing(&self, entry: &MalEntry) -> bool {
+        self.missing
+            .contains(&self.region, &self.rewrite(&entry.title), self.missing_ttl)
+    }
+
+    /// Notes a step of the matching, when `explain` listens
+    fn record(&self, step: impl FnOnce() -> String) {
+        if let Some(steps) = self.steps.lock().unwrap().as_mut() {
+            steps.push(step());
+        }
+    }
+
+    /// Settings of the entry, with its overrides applied
+    fn settings(&self, mal_id: u32) -> MatcherConfig {
+        match self.mappings.matcher_override(mal_id) {
+            Some(x) => x.apply(&self.config),
+            None => self.config.clone(),
+        }
+    }
+
+    /// Looks for the Crunchyroll season corresponding to a MAL entry,
+    /// skipping the seasons in `treated_ids`. Known mappings take
+    /// precedence over the heuristics, and new matches are remembered.
+    pub async fn find(
+        &mut self,
+        entry: &MalEntry,
+        treated_ids: &HashSet<String>,
+    ) -> Result<MatchOutcome> {
+        let mal_id = entry.mal_id;
+        if let Some(mapping) = self.mappings.get(mal_id) {
+            self.record(|| {
+                format!(
+                    "Mapped to series {} season {} ({:?})",
+                    mapping.series_id, mapping.season_id, mapping.provenance
+                )
+            });
+            let series = self.catalog.series(&mapping.series_id).await?;
+            let season = self.catalog.season(&mapping.season_id).await?;
+            success!("Mapped {}", &season.title);
+            return Ok(MatchOutcome::Matched {
+                series,
+                season,
+                confidence: mapping.confidence,
+            });
+        }
+
+        let title = self.rewrite(&entry.title);
+        self.record(|| format!("Searched '{}'", &title));
+        if self
+            .missing
+            .contains(&self.region, &title, self.missing_ttl)
+        {
+            info!("'{}' is known to be missing from Crunchyroll", &title);
+            self.record(|| "Known to be missing from Crunchyroll".to_string());
+            return Ok(MatchOutcome::Unmatched {
+                candidate: None,
+                reason: FailureReason::KnownMissing,
+            });
+        }
+        let settings = self.settings(mal_id);
+        let mut outcome = self.search(entry, &title, treated_ids, &settings).await?;
+        if matches!(outcome, MatchOutcome::Unmatched { .. })
+            && let Some(x) = self
+                .search_by_episode(entry, &title, treated_ids, &settings)
+                .await?
+        {
+            outcome = x;
+        }
+        if matches!(outcome, MatchOutcome::Unmatched { .. })
+            && let Some(x) = self
+                .search_by_simulcast(entry, &title, treated_ids, &settings)
+                .await?
+        {
+            outcome = x;
+        }
+        // Offline searches only know the series crawled before
+        if !self.catalog.is_offline() {
+            match &outcome {
+                MatchOutcome::Unmatched {
+                    candidate: None, ..
+                } => self.missing.insert(&self.region, &title, mal_id),
+                _ => self.missing.remove(&self.region, &title),
+            }
+        }
+
+        let decision = match (&self.script, &outcome) {
+            (
+                Some(script),
+                MatchOutcome::Matched {
+                    series,
+                    season,
+                    confidence,
+                },
+            ) => {
+                let info = EntryInfo {
+                    mal_id,
+                    title: &title,
+                    start_date: entry.air_start_date,
+                };
+                Some(script.decide(&info, series, season, *confidence)?)
+            }
+            _ => None,
+        };
+        match decision {
+            Some(Decision::Confidence(x)) => {
+                self.record(|| format!("Confidence set to {:.3} by the script", x));
+                if let MatchOutcome::Matched { confidence, .. } = &mut outcome {
+                    *confidence = x;
+                }
+            }
+            Some(Decision::Veto) => {
+                if let MatchOutcome::Matched { series, season, .. } = outcome {
+                    info!("Match with {} vetoed by the script", &season.title);
+                    self.record(|| format!("'{}' vetoed by the script", &season.title));
+                    outcome = MatchOutcome::Unmatched {
+                        candidate: Some(series),
+                        reason: FailureReason::Vetoed,
+                    };
+                }
+            }
+            Some(Decision::Keep) | None => (),
+        }
+
+        if let MatchOutcome::Matched {
+            series,
+            season,
+            confidence,
+        } = &outcome
+            && mal_id != 0
+        {
+            self.mappings.insert(Mapping {
+                mal_id,
+                series_id: series.id.clone(),
+                season_id: season.id.clone(),
+                confidence: *confidence,
+                provenance: Provenance::Matcher,
+                resolved_at: Some(Utc::now()),
+                source: None,
+            });
+        }
+        Ok(outcome)
+    }
+
+    fn rewrite(&self, title: &str) -> String {
+        let mut output = title.to_string();
+        for (regex, replacement) in &self.rewrites {
+            output = regex
+                .replace_all(&output, replacement.as_str())
+                .into_owned();
+        }
+        let output = output.trim().to_string();
+        if output != title {
+            info!("Rewritten '{}' => '{}'", title, output);
+        }
+        output
+    }
+
+    /// Key shared by the entries of a franchise: the root of the prequel
+    /// chain when known, the title without its season designation otherwise
+    fn franchise_key(&self, entry: &MalEntry, title: &str) -> String {
+        match entry.chain {
+            Some(chain) => format!("#{}", chain.root),
+            None => self.season_designation.replace(title, "").into_owned(),
+        }
+    }
+
+    /// Series of the franchise when already found, searched otherwise
+    async fn find_series(
+        &self,
+        key: &str,
+        title: &str,
+        settings: &MatcherConfig,
+    ) -> Result<Option<SeriesInfo>> {
+        let known = self.franchises.lock().unwrap().get(key).cloned();
+        if let Some(series) = known {
+            let normalized = cr_title(&series.title);
+            if title_distance(&normalized, title, settings)
+                .is_some_and(|x| x <= similarity_threshold(normalized.chars().count(), settings))
+            {
+                success!("Same franchise as '{}'", &series.title);
+                self.record(|| format!("Same franchise as '{}'", &series.title));
+                return Ok(Some(series));
+            }
+        }
+        Ok(self.catalog.search(title).await?.into_iter().next())
+    }
+
+    async fn search(
+        &self,
+        entry: &MalEntry,
+        title: &str,
+        treated_ids: &HashSet<String>,
+        settings: &MatcherConfig,
+    ) -> Result<MatchOutcome> {
+        let key = self.franchise_key(entry, title);
+        let series = match self.find_series(&key, title, settings).await? {
+            Some(s) => s,
+            None => {
+                self.record(|| "No search result".to_string());
+                return Ok(MatchOutcome::Unmatched {
+                    candidate: None,
+                    reason: FailureReason::NoSearchResults,
+                });
+            }
+        };
+        info!("Result '{}' '{}'", cr_title(&series.title), &title);
+
+        let score = series
+            .titles()
+            .into_iter()
+            .filter_map(|x| same_title(&cr_title(&x), title, settings))
+            .min_by(f32::total_cmp);
+        let Some(score) = score else {
+            self.record(|| {
+                format!(
+                    "'{}' [{}] titled too differently",
+                    &series.title, &series.id
+                )
+            });
+            return Ok(MatchOutcome::Unmatched {
+                candidate: Some(series),
+                reason: FailureReason::TitleMismatch,
+            });
+        };
+        self.record(|| {
+            format!(
+                "'{}' [{}] titled alike (distance {:.3})",
+                &series.title, &series.id, score
+            )
+        });
+        self.franchises.lock().unwrap().insert(key, series.clone());
+        self.select_season(entry, title, series, score, treated_ids, settings)
+            .await
+    }
+
+    /// Looks for the series through the title of one of the episodes
+    /// of the entry, for the series titled very differently on both
+    /// services
+    async fn search_by_episode(
+        &self,
+        entry: &MalEntry,
+        title: &str,
+        treated_ids: &HashSet<String>,
+        settings: &MatcherConfig,
+    ) -> Result<Option<MatchOutcome>> {
+        let known = self
+            .mappings
+            .matcher_override(entry.mal_id)
+            .and_then(|x| x.episode_title.clone());
+        let episode_title = match known {
+            Some(x) => x,
+            None if settings.episode_fallback
+                && entry.mal_id != 0
+                && !self.catalog.is_offline() =>
+            {
+                match mal::first_episode_title(entry.mal_id).await {
+                    Ok(Some(x)) => x,
+                    Ok(None) => return Ok(None),
+                    Err(e) => {
+                        warn!("Could not read the episodes of '{}' on Jikan: {}", title, e);
+                        self.record(|| format!("Episode fallback: Jikan failed ({})", e));
+                        return Ok(None);
+                    }
+                }
+            }
+            None => return Ok(None),
+        };
+        let Some(series) = self.catalog.search_episode(&episode_title).await? else {
+            self.record(|| format!("Episode fallback: no series has '{}'", &episode_title));
+            return Ok(None);
+        };
+        success!(
+            "'{}' found through its episode '{}'",
+            &series.title,
+            &episode_title
+        );
+        self.record(|| {
+            format!(
+                "Episode fallback: '{}' has '{}'",
+                &series.title, &episode_title
+            )
+        });
+        let outcome = self
+            .select_season(
+                entry,
+                title,
+                series,
+                PLAUSIBLE_THRESHOLD,
+                treated_ids,
+                settings,
+            )
+            .await?;
+        Ok(Some(outcome))
+    }
+
+    /// Looks for the series among the simulcasts of the MAL start
+    /// season, for the shows renamed on Crunchyroll. The candidates
+    /// being few, the titles only have to be plausible, but the
+    /// episodes must air around the MAL start date.
+    async fn search_by_simulcast(
+        &self,
+        entry: &MalEntry,
+        title: &str,
+        treated_ids: &HashSet<String>,
+        settings: &MatcherConfig,
+    ) -> Result<Option<MatchOutcome>> {
+        let (Some(simulcast), Some(_)) = (&entry.simulcast, entry.air_start_date) else {
+            return Ok(None);
+        };
+        if !settings.simulcast_fallback {
+            return Ok(None);
+        }
+        let distance = |x: &str| {
+            let n = x.len().max(title.len());
+            let edits = (levenshtein::levenshtein(x, title) as f32) / (n.max(1) as f32);
+            token_distance(x, title).map_or(edits, |y| y.min(edits))
+        };
+        let best = self
+            .catalog
+            .simulcast(simulcast)
+            .await?
+            .into_iter()
+            .filter_map(|series| {
+                let score = series
+                    .titles()
+                    .iter()
+                    .map(|x| distance(&cr_title(x)))
+                    .min_by(f32::total_cmp)?;
+                (score <= PLAUSIBLE_THRESHOLD).then_some((series, score))
+            })
+            .min_by(|x, y| x.1.total_cmp(&y.1));
+        let Some((series, score)) = best else {
+            self.record(|| format!("Simulcast fallback: no {} simulcast alike", simulcast));
+            return Ok(None);
+        };
+        info!(
+            "'{}' is the closest {} simulcast ({})",
+            &series.title, simulcast, score
+        );
+        self.record(|| {
+            format!(
+                "Simulcast fallback: '{}' is the closest {} simulcast ({:.3})",
+                &series.title, simulcast, score
+            )
+        });
+        let settings = MatcherConfig {
+            check_dates: true,
+            ..settings.clone()
+        };
+        let outcome = self
+            .select_season(entry, title, series, score, treated_ids, &settings)
+            .await?;
+        // A rejected candidate is no better than the search one
+        Ok(matches!(outcome, MatchOutcome::Matched { .. }).then_some(outcome))
+    }
+
+    /// Picks the season of `series` matching the entry, the series
+    /// title being `score` away from the MAL one
+    async fn select_season(
+        &self,
+        entry: &MalEntry,
+        title: &str,
+        series: SeriesInfo,
+        score: f32,
+        treated_ids: &HashSet<String>,
+        settings: &MatcherConfig,
+    ) -> Result<MatchOutcome> {
+        let max_date_difference = TimeDelta::days(settings.date_tolerance_days);
+        let mut seasons = prefer_audio(self.catalog.seasons(&series.id).await?, &self.audio);
+        // Of the last season rejected
+        let mut reason = FailureReason::NoSeasonLeft;
+        if matches!(
+            entry.media_type,
+            Some(AnimeMediaType::Movie | AnimeMediaType::Special)
+        ) && entry.episode_duration > 0
+        {
+            let mut kept = vec![];
+            for season in seasons {
+                match self.same_duration(entry, &season).await? {
+                    true => kept.push(season),
+                    false => reason = FailureReason::DurationMismatch,
+                }
+            }
+            seasons = kept;
+        }
+        // The franchise chain is more reliable than the dates
+        let position = entry.chain.map(|x| x.position).or(entry.season_number);
+        if let Some(position) = position {
+            let season = seasons
+                .iter()
+                .find(|x| !treated_ids.contains(&x.id) && x.season_number as usize == position);
+            if let Some(season) = season {
+                success!("Season {} of the franchise", position);
+                self.record(|| {
+                    format!(
+                        "'{}' is season {} of the franchise",
+                        &season.title, position
+                    )
+                });
+                return Ok(MatchOutcome::Matched {
+                    season: season.clone(),
+                    series,
+                    confidence: 1.0 - score,
+                });
+            }
+        }
+
+        let mut check_dates = settings.check_dates;
+        if let (true, Some(date)) = (check_dates, entry.air_start_date) {
+            if self
+                .is_rerelease(&seasons, date, max_date_difference)
+                .await?
+            {
+                warn!(
+                    "'{}' aired long after the MAL start date, probably a re-release, ignoring the dates",
+                    &series.title
+                );
+                self.record(|| "Re-release, the dates are ignored".to_string());
+                check_dates = false;
+            }
+        }
+
+        // Without dates, an untreated season with the episode count of
+        // the MAL entry beats the first untreated one
+        if !check_dates && entry.num_episodes > 0 {
+            let same_count = seasons.iter().find(|x| {
+                !treated_ids.contains(&x.id)
+                    && !same_season_title(&x.title, title)
+                    && x.number_of_episodes == entry.num_episodes
+            });
+            let title_match = seasons
+                .iter()
+                .any(|x| !treated_ids.contains(&x.id) && same_season_title(&x.title, title));
+            if let (Some(season), false) = (same_count, title_match) {
+                self.record(|| {
+                    format!(
+                        "'{}' has the {} episodes of the entry",
+                        &season.title, entry.num_episodes
+                    )
+                });
+                return Ok(MatchOutcome::Matched {
+                    season: season.clone(),
+                    series,
+                    confidence: 1.0 - score,
+                });
+            }
+        }
+
+        // Season rejected on its air dates alone, with its closest episode
+        let mut closest: Option<(String, TimeDelta)> = None;
+        'SEASON: for season in seasons {
+            if treated_ids.contains(&season.id) {
+                self.record(|| format!("'{}' went to another entry", &season.title));
+                continue;
+            }
+
+            if check_dates && !same_season_title(&season.title, title) {
+                let mut valid_season = false;
+                let mut season_closest: Option<TimeDelta> = None;
+
+                if let Some(date) = entry.air_start_date {
+                    for episode in self.catalog.episodes(&season.id).await? {
+                        let difference = episode.air_date - date;
+                        if difference.abs() < max_date_difference {
+                            valid_season = true;
+                            break;
+                        }
+                        // Watched as it aired on Crunchyroll, for the
+                        // seasons added there long after airing in Japan
+                        if let Some((start, finish)) = entry.watch_window
+                            && episode.air_date >= start - max_date_difference
+                            && episode.air_date <= finish
+                        {
+                            info!(
+                                "'{}' aired while the entry was being watched",
+                                &season.title
+                            );
+                            valid_season = true;
+                            break;
+                        }
+                        if closest
+                            .as_ref()
+                            .is_none_or(|(_, x)| difference.abs() < x.abs())
+                        {
+                            closest = Some((season.title.clone(), difference));
+                        }
+                        if season_closest.is_none_or(|x| difference.abs() < x.abs()) {
+                            season_closest = Some(difference);
+                        }
+
+                        if episode.air_date >= (date + max_date_difference)
+                            && entry
+                                .watch_window
+                                .is_none_or(|(_, finish)| episode.air_date > finish)
+                        {
+                            self.record(|| {
+                                format!(
+                                    "'{}' and the next seasons aired after the MAL start date",
+                                    &season.title
+                                )
+                            });
+                            break 'SEASON;
+                        }
+                    }
+                } else {
+                    warn!("No date has been found");
+                }
+
+                if !valid_season {
+                    self.record(|| match season_closest {
+                        Some(x) => format!(
+                            "'{}' rejected, closest episode {:+} days ({} tolerated)",
+                            &season.title,
+                            x.num_days(),
+                            max_date_difference.num_days()
+                        ),
+                        None => format!("'{}' rejected, no episode to date it", &season.title),
+                    });
+                    reason = FailureReason::DateMismatch;
+                    continue;
+                }
+            }
+
+            self.record(|| format!("'{}' accepted", &season.title));
+            return Ok(MatchOutcome::Matched {
+                series,
+                season,
+                confidence: 1.0 - score,
+            });
+        }
+
+        if let Some((season_title, difference)) = closest {
+            warn!(
+                "'{}' only rejected because of its air dates (closest episode {:+} days, {} tolerated)",
+                season_title,
+                difference.num_days(),
+                max_date_difference.num_days()
+            );
+        }
+        Ok(MatchOutcome::Unmatched {
+            candidate: Some(series),
+            reason,
+        })
+    }
+
+    /// Whether the episodes of the season last about as long as the
+    /// MAL ones, to tell a movie from its trailers and recaps
+    async fn same_duration(&self, entry: &MalEntry, season: &SeasonInfo) -> Result<bool> {
+        let durations: Vec<u32> = self
+            .catalog
+            .episodes(&season.id)
+            .await?
+            .into_iter()
+            .map(|x| x.duration)
+            .filter(|x| *x > 0)
+            .collect();
+        // Nothing to compare with in the older caches
+        if durations.is_empty() {
+            return Ok(true);
+        }
+        let average = durations.iter().sum::<u32>() as f32 / durations.len() as f32;
+        let expected = entry.episode_duration as f32;
+        let same = (average - expected).abs() <= DURATION_TOLERANCE * expected;
+        if !same {
+            let message = format!(
+                "'{}' rejected, its episodes last {} min rather than {}",
+                &season.title,
+                (average / 60.0).round(),
+                (expected / 60.0).round()
+            );
+            info!("{}", &message);
+            self.record(|| message);
+        }
+        Ok(same)
+    }
+
+    /// Whether every episode of the series aired well after the MAL
+    /// start date, as with shows (re-)added to Crunchyroll years later
+    async fn is_rerelease(
+        &self,
+        seasons: &[SeasonInfo],
+        date: DateTime<Utc>,
+        max_date_difference: TimeDelta,
+    ) -> Result<bool> {
+        let mut first_air_dates = vec![];
+        for season in seasons {
+            let episodes = self.catalog.episodes(&season.id).await?;
+            first_air_dates.extend(episodes.iter().map(|x| x.air_date).min());
+        }
+        Ok(first_air_dates
+            .into_iter()
+            .min()
+            .is_some_and(|x| x >= date + max_date_difference))
+    }
+
+    /// Fetches everything the matching of the entry may look at
+    pub async fn crawl(&self, entry: &MalEntry) -> Result<()> {
+        let series_id = match self.mappings.get(entry.mal_id) {
+            Some(x) => x.series_id.clone(),
+            None => {
+                let title = self.rewrite(&entry.title);
+                match self.catalog.search(&title).await?.into_iter().next() {
+                    Some(x) => x.id,
+                    None => return Ok(()),
+                }
+            }
+        };
+        for season in self.catalog.seasons(&series_id).await? {
+            self.catalog.episodes(&season.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the first search result loosely resembling the title,
+    /// telling apart shows missing from Crunchyroll from failed matches.
+    pub async fn plausible_series(&self, title: &str) -> Result<Option<SeriesInfo>> {
+        for series in self.catalog.search(title).await? {
+            if series
+                .titles()
+                .into_iter()
+                .any(|x| plausible_title(&cr_title(&x), title))
+            {
+                return Ok(Some(series));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Describes every step of the matching of a single entry, as
+    /// taken by `find`
+    pub async fn explain(&mut self, entry: &MalEntry) -> Result<String> {
+        let mut out = String::new();
+        let mal_id = entry.mal_id;
+        writeln!(out, "MAL title:  {}", &entry.title)?;
+        match entry.air_start_date {
+            Some(x) => writeln!(out, "Start date: {}", x.format("%Y-%m-%d"))?,
+            None => writeln!(out, "Start date: unknown")?,
+        }
+        if entry.num_episodes > 0 {
+            writeln!(out, "Episodes:   {}", entry.num_episodes)?;
+        }
+        if let Some(x) = entry.chain {
+            writeln!(
+                out,
+                "Franchise:  TV series #{} after {}",
+                x.position, x.root
+            )?;
+        }
+        if let Some(x) = self.mappings.matcher_override(mal_id) {
+            writeln!(out, "Override:   {:?}", x)?;
+        }
+
+        *self.steps.lock().unwrap() = Some(vec![]);
+        let outcome = self.find(entry, &HashSet::new()).await;
+        let steps = self.steps.lock().unwrap().take().unwrap_or_default();
+        writeln!(out)?;
+        writeln!(out, "Steps:")?;
+        for step in steps {
+            writeln!(out, "  {}", step)?;
+        }
+
+        writeln!(out)?;
+        match outcome? {
+            MatchOutcome::Matched {
+                season, confidence, ..
+            } => writeln!(
+                out,
+                "Decision: matched '{}' [{}] (confidence {:.3})",
+                season.title, season.id, confidence
+            )?,
+            MatchOutcome::Unmatched { reason, .. } => {
+                writeln!(out, "Decision: unmatched ({})", reason.name())?
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_distance_compares_prefixes() {
+        let config = MatcherConfig::default();
+        assert_eq!(
+            title_distance("naruto", "naruto shippuden", &config),
+            Some(0.0)
+        );
+        assert_eq!(
+            title_distance("narutp", "naruto shippuden", &config),
+            Some(1.0 / 6.0)
+        );
+        assert_eq!(title_distance("naruto shippuden", "naruto", &config), None);
+        assert_eq!(title_distance("", "naruto", &config), None);
+    }
+
+    #[test]
+    fn title_distance_counts_characters() {
+        let config = MatcherConfig::default();
+        assert_eq!(title_distance("shingeki", "進撃の巨人", &config), None);
+        assert_eq!(title_distance("進撃", "進撃の巨人", &config), Some(0.0));
+        assert_eq!(
+            title_distance("shingeki", "進撃の巨人 the final season", &config),
+            Some(1.0)
+        );
+        assert_eq!(same_prefix("shingeki", "進撃の巨人", &config), None);
+    }
+
+    #[test]
+    fn title_distance_scaled_keeps_short_titles_whole() {
+        let config = MatcherConfig {
+            similarity_curve: SimilarityCurve::Scaled,
+            ..Default::default()
+        };
+        assert_eq!(title_distance("k", "k-on", &config), None);
+        assert_eq!(title_distance("k-on", "k-on", &config), Some(0.0));
+        assert_eq!(
+            title_distance("naruto", "naruto shippuden", &config),
+            Some(0.0)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use std::env;
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::config::{MetricsConfig, MetricsFormat};
+use crate::history::RunRecord;
+use crate::{http, profile, warn};
+
+static CONFIG: OnceLock<MetricsConfig> = OnceLock::new();
+
+/// Sets where the metrics go, only the first call has an effect
+pub fn configure(config: &MetricsConfig) {
+    let _ = CONFIG.set(config.clone());
+}
+
+/// Escapes the spaces, commas and equal signs of an InfluxDB tag
+fn influx_tag(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Makes a Graphite path segment out of an endpoint name
+fn graphite_segment(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+/// The run counters, then the requests of every endpoint, in the
+/// InfluxDB line protocol
+fn influx_lines(prefix: &str, run: &RunRecord) -> String {
+    let timestamp = run.started_at.timestamp_nanos_opt().unwrap_or_default();
+    let stats = &run.stats;
+    let mut output = format!(
+        "{} matched={}i,marked={}i,unmatched={}i,failed={}i,exit_code={}i,duration={} {}\n",
+        prefix,
+        stats.matched,
+        stats.marked,
+        stats.unmatched,
+        stats.failures,
+        run.exit_code,
+        run.duration_secs,
+        timestamp
+    );
+    for (endpoint, count, duration) in profile::calls() {
+        let _ = writeln!(
+            output,
+            "{}_requests,endpoint={} count={}i,duration={} {}",
+            prefix,
+            influx_tag(endpoint),
+            count,
+            duration.as_secs_f64(),
+            timestamp
+        );
+    }
+    output
+}
+
+/// Same metrics in the Graphite plaintext protocol
+fn graphite_lines(prefix: &str, run: &RunRecord) -> String {
+    let timestamp = run.started_at.timestamp();
+    let stats = &run.stats;
+    let mut output = String::new();
+    for (name, value) in [
+        ("matched", stats.matched as f64),
+        ("marked", stats.marked as f64),
+        ("unmatched", stats.unmatched as f64),
+        ("failed", stats.failures as f64),
+        ("exit_code", run.exit_code as f64),
+        ("duration", run.duration_secs),
+    ] {
+        let _ = writeln!(output, "{}.run.{} {} {}", prefix, name, value, timestamp);
+    }
+    for (endpoint, count, duration) in profile::calls() {
+        let endpoint = graphite_segment(endpoint);
+        let _ = writeln!(
+            output,
+            "{0}.requests.{1}.count {2} {4}\n{0}.requests.{1}.duration {3} {4}",
+            prefix,
+            endpoint,
+            count,
+            duration.as_secs_f64(),
+            timestamp
+        );
+    }
+    output
+}
+
+async fn send(config: &MetricsConfig, url: &str, run: &RunRecord) -> Result<()> {
+    match config.format {
+        MetricsFormat::Influx => {
+            let mut request = http::client()?
+                .post(url)
+                .body(influx_lines(&config.prefix, run));
+            if let Ok(token) = env::var("INFLUX_TOKEN") {
+                request = request.header("Authorization", format!("Token {}", token));
+            }
+            http::send("metrics", request).await?.error_for_status()?;
+        }
+        MetricsFormat::Graphite => {
+            let address = url.strip_prefix("tcp://").unwrap_or(url);
+            let mut stream = TcpStream::connect(address).await?;
+            stream
+                .write_all(graphite_lines(&config.prefix, run).as_bytes())
+                .await?;
+            stream.shutdown().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Pushes the counters and durations of the run when a sink is
+/// configured. A sink being down doesn't fail the run.
+pub async fn push(run: &RunRecord) {
+    let Some(config) = CONFIG.get() else {
+        return;
+    };
+    let Some(url) = &config.url else {
+        return;
+    };
+    if let Err(e) = send(config, url, run).await {
+        warn!("Could not push the metrics to {}: {:#}", url, e);
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::state::{self, Persisted};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MissingTitle {
+    pub mal_id: u32,
+    /// Last search that found nothing
+    pub checked_at: DateTime<Utc>,
+}
+
+/// Titles Crunchyroll had nothing for, per region, so that they
+/// aren't searched again on every run
+#[derive(Serialize, Deserialize, Default)]
+pub struct KnownMissing {
+    regions: BTreeMap<String, BTreeMap<String, MissingTitle>>,
+}
+
+impl Persisted for KnownMissing {
+    const FILE_NAME: &'static str = "known-missing.json";
+    const CACHE: bool = true;
+}
+
+impl KnownMissing {
+    pub fn load() -> Result<Self> {
+        state::load()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        state::save(self)
+    }
+
+    /// Whether the title was found missing less than `ttl` ago
+    pub fn contains(&self, region: &str, title: &str, ttl: TimeDelta) -> bool {
+        self.regions
+            .get(region)
+            .and_then(|x| x.get(title))
+            .is_some_and(|x| Utc::now() - x.checked_at < ttl)
+    }
+
+    /// The MAL entries whose title was found missing less than `ttl` ago
+    pub fn mal_ids(&self, region: &str, ttl: TimeDelta) -> BTreeSet<u32> {
+        self.regions
+            .get(region)
+            .into_iter()
+            .flat_map(|x| x.values())
+            .filter(|x| Utc::now() - x.checked_at < ttl)
+            .map(|x| x.mal_id)
+            .collect()
+    }
+
+    pub fn insert(&mut self, region: &str, title: &str, mal_id: u32) {
+        self.regions.entry(region.to_string()).or_default().insert(
+            title.to_string(),
+            MissingTitle {
+                mal_id,
+                checked_at: Utc::now(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, region: &str, title: &str) {
+        if let Some(x) = self.regions.get_mut(region) {
+            x.remove(title);
+        }
+    }
+}
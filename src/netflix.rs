@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::source::{WatchEntry, parse_date};
+
+/// One line of NetflixViewingHistory.csv
+#[derive(Deserialize)]
+struct Activity {
+    #[serde(rename = "Title")]
+    title: String,
+    #[serde(rename = "Date")]
+    date: String,
+}
+
+/// Reads the viewing activity exported from the Netflix account
+/// settings. Titles look like "Show: Season 2: Episode title", the
+/// ones without any season or episode part (movies) are left out.
+pub fn read_history(path: &Path) -> Result<Vec<WatchEntry>> {
+    let season_part = Regex::new(r"(?i)^(?:season|part|volume|collection) (\d+)$")?;
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+
+    // Distinct episodes watched per show and season
+    let mut shows = BTreeMap::<(String, usize), (Vec<String>, Option<DateTime<Utc>>)>::new();
+    for activity in reader.deserialize() {
+        let activity: Activity = activity?;
+        let parts: Vec<&str> = activity.title.split(": ").collect();
+        if parts.len() < 2 {
+            continue;
+        }
+
+        let season_index = parts.iter().position(|x| season_part.is_match(x));
+        let (show, season, episode) = match season_index {
+            Some(i) if i > 0 => {
+                let number = season_part.captures(parts[i]).unwrap()[1].parse()?;
+                (parts[..i].join(": "), number, parts[i + 1..].join(": "))
+            }
+            _ => (parts[0].to_string(), 1, parts[1..].join(": ")),
+        };
+
+        let (episodes, last_watched) = shows.entry((show, season)).or_default();
+        if !episodes.contains(&episode) {
+            episodes.push(episode);
+        }
+        let date = parse_date(&activity.date);
+        *last_watched = (*last_watched).max(date);
+    }
+
+    Ok(shows
+        .into_iter()
+        .map(|((title, season), (episodes, last_watched))| WatchEntry {
+            title,
+            season: Some(season),
+            episodes: episodes.len() as u32,
+            last_watched,
+        })
+        .collect())
+}
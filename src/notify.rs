@@ -0,0 +1,43 @@
+use anyhow::Result;
+use std::env;
+
+use crate::{http, output};
+
+/// Where notifications are delivered. The backend is picked
+/// from the `NOTIFY_URL` environment variable: when set, messages
+/// are POSTed to it (ntfy, gotify-like endpoints...), otherwise
+/// they are shown like the summary of a run, on stderr when stdout
+/// holds the JSON lines.
+pub enum Notifier {
+    Terminal,
+    Webhook {
+        client: reqwest::Client,
+        url: String,
+    },
+}
+
+impl Notifier {
+    pub fn from_env() -> Result<Self> {
+        Ok(match env::var("NOTIFY_URL") {
+            Ok(url) if url.len() > 0 => Self::Webhook {
+                client: http::client()?,
+                url,
+            },
+            _ => Self::Terminal,
+        })
+    }
+
+    pub async fn send(&self, title: &str, message: &str) -> Result<()> {
+        match self {
+            Self::Terminal => output::summary(format_args!("{}: {}", title, message)),
+            Self::Webhook { client, url } => {
+                let request = client
+                    .post(url)
+                    .header("Title", title)
+                    .body(message.to_string());
+                http::send("notify", request).await?.error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
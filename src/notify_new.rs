@@ -0,0 +1,60 @@
+use anyhow::Result;
+use chrono::Utc;
+use mal_api::prelude::*;
+use std::collections::HashSet;
+
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::mal::read_mal_entries;
+use crate::matcher::{MatchOutcome, Matcher, get_node_title};
+use crate::notify::Notifier;
+use crate::{error, info};
+
+/// Sends a notification for every entry being watched on MAL
+/// whose Crunchyroll season has newer episodes available.
+pub async fn notify_new(catalog: &Catalog<'_>, config: &Config, fail_fast: bool) -> Result<()> {
+    let notifier = Notifier::from_env()?;
+    let mut matcher = Matcher::new(catalog, config).await?;
+    let mut treated_ids = HashSet::<String>::new();
+    let now = Utc::now();
+
+    for elt in read_mal_entries(false, config.filters.nsfw).await? {
+        let (node, status) = (elt.node, elt.list_status.unwrap());
+        if status.status != Some(UserAnimeListStatus::Watching) {
+            continue;
+        }
+        let title = get_node_title(node.clone());
+        let mal_entry = matcher.entry(node);
+
+        info!("Querying {}", &title);
+        let season = match matcher.find(&mal_entry, &treated_ids).await? {
+            MatchOutcome::Matched { season, .. } => season,
+            MatchOutcome::Unmatched { .. } => continue,
+        };
+        treated_ids.insert(season.id.clone());
+
+        let new_episodes = catalog
+            .episodes(&season.id)
+            .await?
+            .into_iter()
+            .filter(|x| x.air_date <= now)
+            .filter(|x| matches!(x.episode_number, Some(n) if n > status.num_episodes_watched))
+            .count();
+        if new_episodes == 0 {
+            continue;
+        }
+
+        let message = format!(
+            "{} new episode(s) available after episode {}",
+            new_episodes, status.num_episodes_watched
+        );
+        if let Err(e) = notifier.send(&title, &message).await {
+            if fail_fast {
+                return Err(e.context(title));
+            }
+            error!("{}: {:#}", &title, e);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use anyhow::{Result, bail};
+use std::fmt::Arguments;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::logfile;
+
+static SINK: OnceLock<Box<dyn Sink>> = OnceLock::new();
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    /// Only shown with --verbose, always written to the log file
+    Debug,
+    Info,
+    /// Match results
+    Success,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Debug => "DEBUG",
+            Self::Info => "INFO",
+            Self::Success => "SUCCESS",
+            Self::Warn => "WARNING",
+            Self::Error => "ERROR",
+        }
+    }
+}
+
+/// Where the messages of the library end up, and who answers its
+/// questions. Installed by the binary, the library never prints.
+pub trait Sink: Send + Sync {
+    fn log(&self, level: Level, args: Arguments);
+
+    /// The final line of a run
+    fn summary(&self, args: Arguments);
+
+    /// Asks the user, without echo when `hidden`
+    fn ask(&self, question: &str, hidden: bool) -> Result<String>;
+}
+
+/// Only the first sink installed is kept
+pub fn set_sink(sink: Box<dyn Sink>) {
+    let _ = SINK.set(sink);
+}
+
+/// Number of errors logged so far, quiet or not
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// Hands a message to the sink, without writing it to the log file
+pub(crate) fn notify(level: Level, args: Arguments) {
+    if let Some(sink) = SINK.get() {
+        sink.log(level, args);
+    }
+}
+
+/// Hands a message to the sink, and writes it to the log file
+pub fn print(level: Level, args: Arguments) {
+    if level == Level::Error {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    logfile::write(level, args);
+    #[cfg(feature = "telemetry")]
+    match level {
+        Level::Debug => tracing::debug!("{}", args),
+        Level::Info | Level::Success => tracing::info!("{}", args),
+        Level::Warn => tracing::warn!("{}", args),
+        Level::Error => tracing::error!("{}", args),
+    }
+    notify(level, args);
+}
+
+/// The final line of a run, shown even when quiet
+pub fn summary(args: Arguments) {
+    logfile::write(Level::Info, args);
+    if let Some(sink) = SINK.get() {
+        sink.summary(args);
+    }
+}
+
+/// The answer of the user, trimmed. Fails without a sink able to ask.
+pub fn ask(question: &str, hidden: bool) -> Result<String> {
+    match SINK.get() {
+        Some(sink) => Ok(sink.ask(question, hidden)?.trim().to_string()),
+        None => bail!("Nobody to answer '{}'", question),
+    }
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! success {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Success, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Level::Error, format_args!($($arg)*))
+    };
+}
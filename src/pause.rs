@@ -0,0 +1,56 @@
+use std::sync::LazyLock;
+use tokio::sync::watch;
+
+use crate::{info, warn};
+
+/// Whether the requests are held, shared with the waiting requests
+static PAUSED: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+
+pub fn pause() {
+    if !PAUSED.send_replace(true) {
+        info!("Paused, the next requests wait until resumed");
+    }
+}
+
+pub fn resume() {
+    if PAUSED.send_replace(false) {
+        info!("Resumed");
+    }
+}
+
+pub fn toggle() {
+    let paused = *PAUSED.borrow();
+    match paused {
+        true => resume(),
+        false => pause(),
+    }
+}
+
+/// Returns at once, unless paused until resumed. Everything fetched
+/// so far stays in memory meanwhile.
+pub async fn wait() {
+    let mut paused = PAUSED.subscribe();
+    let _ = paused.wait_for(|x| !x).await;
+}
+
+/// Pauses and resumes on every SIGUSR1
+#[cfg(unix)]
+pub fn listen_signals() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Could not listen to SIGUSR1: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            toggle();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn listen_signals() {}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use std::sync::OnceLock;
+
+use crate::{http, warn};
+
+static URL: OnceLock<String> = OnceLock::new();
+
+/// Sets the URL pinged around the run, only the first call has an
+/// effect
+pub fn configure(url: Option<&String>) {
+    if let Some(x) = url {
+        let _ = URL.set(x.trim_end_matches('/').to_string());
+    }
+}
+
+async fn send(url: String, body: String) {
+    let res = async {
+        http::send("ping", http::client()?.post(&url).body(body))
+            .await?
+            .error_for_status()?;
+        anyhow::Ok(())
+    };
+    // Monitoring being down doesn't fail the run
+    if let Err(e) = res.await {
+        warn!("Could not ping {}: {:#}", url, e);
+    }
+}
+
+/// Tells the monitoring the run started, so that it can time it and
+/// notice when it never ends
+pub async fn start() {
+    if let Some(url) = URL.get() {
+        send(format!("{}/start", url), String::new()).await;
+    }
+}
+
+/// Pings the URL itself on success, `/fail` with the error otherwise
+pub async fn finish(res: &Result<()>) {
+    let Some(url) = URL.get() else {
+        return;
+    };
+    match res {
+        Ok(()) => send(url.clone(), String::new()).await,
+        Err(e) => send(format!("{}/fail", url), format!("{:#}", e)).await,
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::catalog::Catalog;
+use crate::hooks::HookEntry;
+use crate::marker::Marker;
+use crate::{error, info, t, warn};
+
+const PLAN_VERSION: u32 = 1;
+
+/// Marks to perform, decided without touching the account.
+/// The format is described by plan.schema.json, and only changes
+/// along with PLAN_VERSION.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    pub version: u32,
+    pub entries: Vec<PlanEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanEntry {
+    pub mal_id: u32,
+    pub title: String,
+    pub series_id: String,
+    pub season_id: String,
+    pub season_title: String,
+    pub content_ids: Vec<String>,
+    /// Lets the people sharing their plans leave these entries out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub nsfw: bool,
+    /// Confidence of the match, absent from the older plans
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+    /// Why these contents get marked, for the reviewers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub justification: Option<String>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self {
+            version: PLAN_VERSION,
+            entries: vec![],
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        info!(
+            "Plan with {} entries written to {}",
+            self.entries.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let plan: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if plan.version != PLAN_VERSION {
+            bail!("Unsupported plan version {}", plan.version);
+        }
+        Ok(plan)
+    }
+
+    /// Contents of the plan Crunchyroll doesn't know, or not as part
+    /// of the season of their entry, as in the plans edited by hand or
+    /// written before the catalog changed
+    pub async fn stale_contents(&self, catalog: &Catalog<'_>) -> Result<Vec<String>> {
+        let mut output = vec![];
+        for entry in &self.entries {
+            let season = match catalog.season(&entry.season_id).await {
+                Ok(x) => x,
+                Err(e) => {
+                    output.push(format!(
+                        "{}: unknown season {} ({:#})",
+                        entry.mal_id, entry.season_id, e
+                    ));
+                    continue;
+                }
+            };
+            if season.series_id != entry.series_id {
+                output.push(format!(
+                    "{}: season {} isn't part of the series {}",
+                    entry.mal_id, entry.season_id, entry.series_id
+                ));
+            }
+            let episodes: HashSet<String> = catalog
+                .episodes(&season.id)
+                .await?
+                .into_iter()
+                .map(|x| x.id)
+                .collect();
+            for content_id in &entry.content_ids {
+                if *content_id != season.id && !episodes.contains(content_id) {
+                    output.push(format!(
+                        "{}: {} isn't part of the season {}",
+                        entry.mal_id, content_id, entry.season_id
+                    ));
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    /// Refuses the plans with stale contents, unless `allow_stale`
+    pub async fn check(&self, catalog: &Catalog<'_>, allow_stale: bool) -> Result<()> {
+        let stale = self.stale_contents(catalog).await?;
+        for x in &stale {
+            warn!("{}", x);
+        }
+        if !stale.is_empty() && !allow_stale {
+            bail!(
+                "{} contents of the plan don't match Crunchyroll, apply it anyway with --allow-stale",
+                stale.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Marks every content, stopping at the first failure with `fail_fast`
+    pub async fn apply(&self, marker: &mut Marker<'_>, fail_fast: bool) -> Result<()> {
+        for plan_entry in &self.entries {
+            info!("{}", t!("applying", title = plan_entry.title.as_str()));
+            let entry = HookEntry {
+                mal_id: plan_entry.mal_id,
+                title: &plan_entry.title,
+            };
+            for content_id in &plan_entry.content_ids {
+                if let Err(e) = marker.mark(&entry, content_id).await {
+                    if fail_fast {
+                        return Err(e.context(format!("Marking {}", plan_entry.mal_id)));
+                    }
+                    error!("{}: {:#}", plan_entry.mal_id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ratelimit;
+use crate::telemetry;
+
+/// Requests and time spent per endpoint during the run
+static CALLS: Mutex<BTreeMap<&'static str, (usize, Duration)>> = Mutex::new(BTreeMap::new());
+
+/// Stages of a sync the requests belong to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Reading the MAL list and entries, and updating them
+    Mal,
+    Search,
+    /// Enumerating the seasons and episodes
+    Browse,
+    Mark,
+    Other,
+}
+
+impl Phase {
+    fn of(endpoint: &str) -> Self {
+        match endpoint {
+            "mal list" | "mal details" | "mal relations" | "mal update" | "jikan episodes" => {
+                Self::Mal
+            }
+            "search" | "browse" => Self::Search,
+            "series" | "season" | "seasons" | "episodes" | "history" => Self::Browse,
+            "mark" => Self::Mark,
+            _ => Self::Other,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Mal => "MAL",
+            Self::Search => "search",
+            Self::Browse => "seasons/episodes",
+            Self::Mark => "marking",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Time spent per phase, to tell where a run was slow
+#[derive(Clone, Default, Debug)]
+pub struct PhaseTimes(BTreeMap<Phase, Duration>);
+
+static PHASES: Mutex<PhaseTimes> = Mutex::new(PhaseTimes(BTreeMap::new()));
+
+impl PhaseTimes {
+    /// Time spent since the run started
+    pub fn now() -> Self {
+        PHASES.lock().unwrap().clone()
+    }
+
+    /// Time spent between `earlier` and these times
+    pub fn since(&self, earlier: &Self) -> Self {
+        let mut output = self.clone();
+        for (phase, duration) in output.0.iter_mut() {
+            *duration = duration.saturating_sub(earlier.0.get(phase).copied().unwrap_or_default());
+        }
+        output
+    }
+}
+
+impl std::fmt::Display for PhaseTimes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phases: Vec<_> = self
+            .0
+            .iter()
+            .filter(|(_, x)| !x.is_zero())
+            .map(|(phase, x)| format!("{} {:.1}s", phase.name(), x.as_secs_f64()))
+            .collect();
+        match phases.is_empty() {
+            true => write!(f, "no request"),
+            false => write!(f, "{}", phases.join(", ")),
+        }
+    }
+}
+
+/// Counts a request to `endpoint` and the time it takes
+pub async fn timed<F: Future>(endpoint: &'static str, future: F) -> F::Output {
+    #[cfg(feature = "telemetry")]
+    let future = tracing::Instrument::instrument(future, tracing::info_span!("request", endpoint));
+
+    let permit = ratelimit::acquire(ratelimit::Service::of(endpoint)).await;
+    let start = Instant::now();
+    let output = future.await;
+    drop(permit);
+    let elapsed = start.elapsed();
+    telemetry::record_request(endpoint, elapsed);
+
+    *PHASES
+        .lock()
+        .unwrap()
+        .0
+        .entry(Phase::of(endpoint))
+        .or_default() += elapsed;
+    let mut calls = CALLS.lock().unwrap();
+    let (count, duration) = calls.entry(endpoint).or_default();
+    *count += 1;
+    *duration += elapsed;
+    output
+}
+
+/// Requests and time spent per endpoint so far
+pub fn calls() -> Vec<(&'static str, usize, Duration)> {
+    let calls = CALLS.lock().unwrap();
+    calls
+        .iter()
+        .map(|(endpoint, (count, duration))| (*endpoint, *count, *duration))
+        .collect()
+}
+
+/// Requests made so far, to every endpoint
+pub fn request_count() -> usize {
+    CALLS.lock().unwrap().values().map(|(count, _)| count).sum()
+}
+
+/// The requests of the run as a table, the slowest endpoints first
+pub fn render() -> String {
+    let calls = CALLS.lock().unwrap();
+    let mut calls: Vec<_> = calls.iter().collect();
+    calls.sort_by_key(|(_, (_, duration))| std::cmp::Reverse(*duration));
+    let total: Duration = calls.iter().map(|(_, (_, x))| *x).sum();
+
+    let mut out = format!(
+        "{:<20} {:>8} {:>10} {:>10} {:>6}\n",
+        "Endpoint", "Requests", "Total", "Average", "Share"
+    );
+    for (endpoint, (count, duration)) in calls {
+        out += &format!(
+            "{:<20} {:>8} {:>9.1}s {:>8.0}ms {:>5.1}%\n",
+            endpoint,
+            count,
+            duration.as_secs_f64(),
+            duration.as_secs_f64() * 1000. / *count as f64,
+            100. * duration.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON)
+        );
+    }
+    out
+}
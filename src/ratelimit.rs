@@ -0,0 +1,174 @@
+use futures::{StreamExt, stream};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+use tokio::time::{self, Instant};
+
+use crate::config::{ConcurrencyConfig, RequestDelayConfig};
+use crate::pause;
+
+/// Services the requests go to, each with its own limits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+    /// MAL, and Jikan for the episode titles
+    Mal,
+    Crunchyroll,
+    /// Mapping lists, monitoring and notifications, never limited
+    Other,
+}
+
+impl Service {
+    pub fn of(endpoint: &str) -> Self {
+        match endpoint {
+            "mal list" | "mal details" | "mal relations" | "mal update" | "jikan episodes" => {
+                Self::Mal
+            }
+            "search" | "browse" | "series" | "season" | "seasons" | "episodes" | "mark"
+            | "watchlist" | "history" | "rating" | "crunchylist" | "account" | "login" => {
+                Self::Crunchyroll
+            }
+            _ => Self::Other,
+        }
+    }
+}
+
+/// How long the requests stay slowed down after being throttled
+const COOL_DOWN: Duration = Duration::from_secs(60);
+
+/// Delay between the requests while cooling down, at least
+const SLOW_DELAY: Duration = Duration::from_secs(2);
+
+struct Limit {
+    concurrency: usize,
+    in_flight: Semaphore,
+    delay: Duration,
+    /// Earliest start of the next request
+    next_start: Mutex<Instant>,
+    /// End of the cool-down following the last 429
+    slow_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl Limit {
+    fn new(concurrency: usize, delay_ms: u64) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            in_flight: Semaphore::new(concurrency.max(1)),
+            delay: Duration::from_millis(delay_ms),
+            next_start: Mutex::new(Instant::now()),
+            slow_until: std::sync::Mutex::new(None),
+        }
+    }
+
+    fn current_delay(&self) -> Duration {
+        let slow_until = *self.slow_until.lock().unwrap();
+        match slow_until {
+            Some(x) if Instant::now() < x => (self.delay * 4).max(SLOW_DELAY),
+            _ => self.delay,
+        }
+    }
+}
+
+struct Limits {
+    mal: Limit,
+    crunchyroll: Limit,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Sets the limits of the requests, only the first call has an effect
+pub fn configure(concurrency: &ConcurrencyConfig, delay_ms: &RequestDelayConfig) {
+    let _ = LIMITS.set(Limits {
+        mal: Limit::new(concurrency.mal, delay_ms.mal),
+        crunchyroll: Limit::new(concurrency.crunchyroll, delay_ms.crunchyroll),
+    });
+}
+
+/// Waits for a request to the service to be allowed, the returned
+/// permit being held until the response. Unlimited when unconfigured.
+pub async fn acquire(service: Service) -> Option<SemaphorePermit<'static>> {
+    pause::wait().await;
+    let limit = limit(service)?;
+    let permit = limit.in_flight.acquire().await.ok()?;
+    let delay = limit.current_delay();
+    if !delay.is_zero() {
+        let mut next_start = limit.next_start.lock().await;
+        time::sleep_until(*next_start).await;
+        *next_start = Instant::now() + delay;
+    }
+    Some(permit)
+}
+
+fn limit(service: Service) -> Option<&'static Limit> {
+    let limits = LIMITS.get()?;
+    match service {
+        Service::Mal => Some(&limits.mal),
+        Service::Crunchyroll => Some(&limits.crunchyroll),
+        Service::Other => None,
+    }
+}
+
+/// Requests allowed in flight at once to the service, 1 when
+/// unconfigured
+pub fn concurrency(service: Service) -> usize {
+    limit(service).map_or(1, |x| x.concurrency)
+}
+
+/// Runs `lookup` on every item, `concurrency` at a time at most,
+/// returning the results in the order of the items
+pub async fn lookup_all<T, R, F, Fut>(concurrency: usize, items: Vec<T>, lookup: F) -> Vec<R>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    stream::iter(items)
+        .map(lookup)
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Slows the requests to the service down for a while, after it
+/// answered 429
+pub fn throttled(service: Service) {
+    if let Some(limit) = limit(service) {
+        *limit.slow_until.lock().unwrap() = Some(Instant::now() + COOL_DOWN);
+    }
+}
+
+/// Wait before retrying a throttled request for the `attempt`th time:
+/// the Retry-After of the answer when given, an exponential backoff
+/// otherwise, plus up to half of it at random so that the parallel
+/// requests don't all come back at once
+pub fn backoff(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    let base = retry_after.unwrap_or(Duration::from_secs(2u64.pow(attempt)));
+    let random = RandomState::new().build_hasher().finish();
+    base + Duration::from_millis(random % (base.as_millis() as u64 / 2 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn lookup_all_bounds_the_concurrency() {
+        let in_flight = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let output = lookup_all(3, (0..10).collect(), |x: u32| {
+            let (in_flight, most) = (&in_flight, &most);
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(current, Ordering::SeqCst);
+                time::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                x * 2
+            }
+        })
+        .await;
+
+        assert_eq!(output, (0..10).map(|x| x * 2).collect::<Vec<_>>());
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+    }
+}
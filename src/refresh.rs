@@ -0,0 +1,35 @@
+use anyhow::Result;
+use futures::{StreamExt, stream};
+
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::mal::read_mal_entries;
+use crate::matcher::Matcher;
+use crate::ratelimit::{self, Service};
+use crate::{error, info};
+
+/// Fetches again everything the list touches on Crunchyroll, so
+/// that the next syncs can match against the stored snapshot.
+pub async fn refresh(catalog: &Catalog<'_>, config: &Config) -> Result<()> {
+    let matcher = Matcher::new(catalog, config).await?;
+
+    let entries = read_mal_entries(false, config.filters.nsfw).await?;
+    let count = entries.len();
+    // The entries are independent, crawled as much in parallel as
+    // Crunchyroll is allowed requests at once
+    stream::iter(entries.into_iter().enumerate())
+        .for_each_concurrent(ratelimit::concurrency(Service::Crunchyroll), |(i, elt)| {
+            let matcher = &matcher;
+            async move {
+                let entry = matcher.entry(elt.node);
+                info!("[{}/{}] Crawling {}", i + 1, count, &entry.title);
+                if let Err(e) = matcher.crawl(&entry).await {
+                    error!("{}: {:#}", entry.mal_id, e);
+                }
+            }
+        })
+        .await;
+
+    catalog.mark_refreshed();
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::hooks::HookEntry;
+use crate::marker::Marker;
+use crate::state::{self, Persisted};
+use crate::{error, info, output, t};
+
+/// A content whose mark ultimately failed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetryEntry {
+    pub mal_id: u32,
+    pub title: String,
+    pub content_id: String,
+    /// Audio the content was marked in, the default one when missing
+    #[serde(default)]
+    pub audio: Option<String>,
+    pub error: String,
+    pub attempts: u32,
+    pub last_attempt: DateTime<Utc>,
+}
+
+/// Failed marks waiting for `retry`
+#[derive(Serialize, Deserialize, Default)]
+pub struct RetryQueue {
+    entries: Vec<RetryEntry>,
+}
+
+impl Persisted for RetryQueue {
+    const FILE_NAME: &'static str = "retry-queue.json";
+}
+
+impl RetryQueue {
+    pub fn load() -> Result<Self> {
+        state::load()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        state::save(self)
+    }
+
+    pub fn entries(&self) -> &[RetryEntry] {
+        &self.entries
+    }
+
+    pub fn push(
+        &mut self,
+        entry: &HookEntry,
+        content_id: &str,
+        audio: &str,
+        error: &anyhow::Error,
+    ) {
+        let error = format!("{:#}", error);
+        match self.entries.iter_mut().find(|x| x.content_id == content_id) {
+            Some(x) => {
+                x.audio = Some(audio.to_string());
+                x.error = error;
+                x.attempts += 1;
+                x.last_attempt = Utc::now();
+            }
+            None => self.entries.push(RetryEntry {
+                mal_id: entry.mal_id,
+                title: entry.title.to_string(),
+                content_id: content_id.to_string(),
+                audio: Some(audio.to_string()),
+                error,
+                attempts: 1,
+                last_attempt: Utc::now(),
+            }),
+        }
+    }
+
+    /// Returns whether the content was queued
+    pub fn remove(&mut self, content_id: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|x| x.content_id != content_id);
+        len != self.entries.len()
+    }
+}
+
+/// Marks again the queued contents, the ones failing again stay
+/// queued. The entries of `known_missing` are left for later.
+pub async fn retry(marker: &mut Marker<'_>, known_missing: &BTreeSet<u32>) -> Result<()> {
+    let entries = marker.retry_queue().entries().to_vec();
+    if entries.is_empty() {
+        info!("{}", t!("retry-nothing"));
+        return Ok(());
+    }
+
+    let mut failures = 0;
+    for queued in &entries {
+        if known_missing.contains(&queued.mal_id) {
+            info!(
+                "{}",
+                t!("retry-known-missing", title = queued.title.as_str())
+            );
+            continue;
+        }
+        info!(
+            "{}",
+            t!(
+                "retry-attempt",
+                content = queued.content_id.as_str(),
+                title = queued.title.as_str(),
+                attempt = queued.attempts + 1
+            )
+        );
+        let entry = HookEntry {
+            mal_id: queued.mal_id,
+            title: &queued.title,
+        };
+        marker.set_audio(queued.audio.as_deref());
+        if let Err(e) = marker.mark(&entry, &queued.content_id).await {
+            error!("{}: {:#}", queued.mal_id, e);
+            failures += 1;
+        }
+    }
+
+    output::summary(format_args!(
+        "{}",
+        t!("retry-summary", retried = entries.len(), failing = failures)
+    ));
+    Ok(())
+}
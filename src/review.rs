@@ -0,0 +1,157 @@
+use anyhow::Result;
+use std::io::{self, Write};
+
+use mal_2_crunchyroll::catalog::Catalog;
+use mal_2_crunchyroll::plan::{Plan, PlanEntry};
+use mal_2_crunchyroll::sync::contents_to_mark;
+use mal_2_crunchyroll::{info, warn};
+
+/// Asks a question on stdout, returning the trimmed answer
+fn prompt(question: &str) -> Result<String> {
+    print!("{} ", question);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Indices (from 1) picked among `count` items
+fn parse_indices(answer: &str, count: usize) -> Vec<usize> {
+    answer
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|x| x.parse::<usize>().ok())
+        .filter(|x| (1..=count).contains(x))
+        .map(|x| x - 1)
+        .collect()
+}
+
+/// What the reviewer decided for the whole plan
+pub enum Verdict {
+    /// Save the reviewed plan, applying it when asked to
+    Save { apply: bool },
+    /// Leave the plan file untouched
+    Discard,
+}
+
+async fn print_entry(catalog: &Catalog<'_>, entry: &PlanEntry) -> Result<()> {
+    println!();
+    println!("{} {} => {}", entry.mal_id, entry.title, entry.season_title);
+    if let Some(x) = &entry.justification {
+        println!("  {}", x);
+    }
+    let episodes = catalog.episodes(&entry.season_id).await.unwrap_or_default();
+    for (i, content_id) in entry.content_ids.iter().enumerate() {
+        let label = match episodes.iter().find(|x| &x.id == content_id) {
+            Some(x) => format!(
+                "episode {} {}",
+                x.episode_number.map_or("?".to_string(), |n| n.to_string()),
+                x.title
+            ),
+            None if *content_id == entry.season_id => "the whole season".to_string(),
+            None => "unknown content".to_string(),
+        };
+        println!("  {:>3}. {} ({})", i + 1, content_id, label);
+    }
+    Ok(())
+}
+
+/// Drops the contents the reviewer picks
+fn toggle_contents(entry: &mut PlanEntry) -> Result<()> {
+    let answer = prompt("Contents to leave out (e.g. \"1 3\"):")?;
+    let dropped = parse_indices(&answer, entry.content_ids.len());
+    let mut i = 0;
+    entry.content_ids.retain(|_| {
+        i += 1;
+        !dropped.contains(&(i - 1))
+    });
+    Ok(())
+}
+
+/// Matches the entry with another season, picked among the search
+/// results for its title. As many episodes get marked as before.
+async fn rematch(catalog: &Catalog<'_>, entry: &mut PlanEntry) -> Result<()> {
+    let query = prompt(&format!("Search for [{}]:", entry.title))?;
+    let query = match query.is_empty() {
+        true => entry.title.clone(),
+        false => query,
+    };
+    let candidates = catalog.search(&query).await?;
+    if candidates.is_empty() {
+        warn!("Nothing found for '{}'", query);
+        return Ok(());
+    }
+    for (i, series) in candidates.iter().enumerate() {
+        println!("  {:>3}. {}", i + 1, series.title);
+    }
+    let Some(&i) = parse_indices(&prompt("Series:")?, candidates.len()).first() else {
+        return Ok(());
+    };
+    let series = &candidates[i];
+
+    let seasons = catalog.seasons(&series.id).await?;
+    for (i, season) in seasons.iter().enumerate() {
+        println!(
+            "  {:>3}. {} (season {}, {} episodes)",
+            i + 1,
+            season.title,
+            season.season_number,
+            season.number_of_episodes
+        );
+    }
+    let Some(&i) = parse_indices(&prompt("Season:")?, seasons.len()).first() else {
+        return Ok(());
+    };
+    let season = &seasons[i];
+
+    let watched = match entry.content_ids.contains(&entry.season_id) {
+        true => season.number_of_episodes,
+        false => entry.content_ids.len() as u32,
+    };
+    entry.content_ids = contents_to_mark(catalog, season, watched, 0).await?;
+    entry.series_id = series.id.clone();
+    entry.season_id = season.id.clone();
+    entry.season_title = season.title.clone();
+    entry.confidence = Some(1.0);
+    entry.justification = Some(format!("picked during the review, {} episodes", watched));
+    Ok(())
+}
+
+/// Goes through the entries of the plan, letting the reviewer drop
+/// entries or contents and fix the matches
+pub async fn review(catalog: &Catalog<'_>, plan: &mut Plan) -> Result<Verdict> {
+    let mut kept = vec![];
+    let mut entries = std::mem::take(&mut plan.entries).into_iter();
+    while let Some(mut entry) = entries.next() {
+        loop {
+            print_entry(catalog, &entry).await?;
+            let answer = prompt(
+                "[k]eep, [d]rop, [t]oggle contents, [m]atch another season, keep the [r]est, [q]uit:",
+            )?;
+            match answer.as_str() {
+                "" | "k" => {
+                    kept.push(entry);
+                    break;
+                }
+                "d" => break,
+                "t" => toggle_contents(&mut entry)?,
+                "m" => rematch(catalog, &mut entry).await?,
+                "r" => {
+                    kept.push(entry);
+                    kept.extend(entries.by_ref());
+                    break;
+                }
+                "q" => return Ok(Verdict::Discard),
+                _ => warn!("Unknown answer '{}'", answer),
+            }
+        }
+    }
+    kept.retain(|x| !x.content_ids.is_empty());
+    plan.entries = kept;
+
+    info!("{} entries left in the plan", plan.entries.len());
+    let apply = match catalog.is_offline() {
+        true => false,
+        false => prompt("Apply the plan now? [y/N]")?.eq_ignore_ascii_case("y"),
+    };
+    Ok(Verdict::Save { apply })
+}
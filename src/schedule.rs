@@ -0,0 +1,246 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::exit::ConfigError;
+use crate::state::{self, Persisted};
+
+/// Values allowed by one field of a cron expression
+#[derive(Debug)]
+struct Field {
+    allowed: Vec<bool>,
+    /// Written `*`, which matters for the days of the month and week
+    any: bool,
+}
+
+impl Field {
+    /// Parses lists of values, ranges (`1-5`), `*` and steps (`*/15`)
+    fn parse(text: &str, min: u32, max: u32) -> Option<Self> {
+        let mut allowed = vec![false; max as usize + 1];
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().ok().filter(|x| *x > 0)?),
+                None => (part, 1),
+            };
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((a, b)) => (a.parse().ok()?, b.parse().ok()?),
+                    None => {
+                        let x = range.parse().ok()?;
+                        // `5/10` goes from 5 to the maximum
+                        (x, if part.contains('/') { max } else { x })
+                    }
+                },
+            };
+            if start < min || end > max || start > end {
+                return None;
+            }
+            for x in (start..=end).step_by(step as usize) {
+                allowed[x as usize] = true;
+            }
+        }
+        Some(Self {
+            allowed,
+            any: text == "*",
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.allowed.get(value as usize).copied().unwrap_or(false)
+    }
+}
+
+/// Standard cron expression (minute, hour, day of the month, month,
+/// day of the week), evaluated in local time
+#[derive(Debug)]
+pub struct Schedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl FromStr for Schedule {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ConfigError(format!("Invalid schedule '{}'", s));
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(invalid());
+        };
+        let mut weekdays = Field::parse(weekdays, 0, 7).ok_or_else(invalid)?;
+        // Sunday is either 0 or 7
+        if weekdays.allowed[7] {
+            weekdays.allowed[0] = true;
+        }
+        Ok(Self {
+            minutes: Field::parse(minutes, 0, 59).ok_or_else(invalid)?,
+            hours: Field::parse(hours, 0, 23).ok_or_else(invalid)?,
+            days: Field::parse(days, 1, 31).ok_or_else(invalid)?,
+            months: Field::parse(months, 1, 12).ok_or_else(invalid)?,
+            weekdays,
+        })
+    }
+}
+
+impl Schedule {
+    /// Like cron, a day matches either restricted field when both
+    /// the days of the month and of the week are restricted
+    fn matches_day(&self, time: &DateTime<Local>) -> bool {
+        let day = self.days.matches(time.day());
+        let weekday = self.weekdays.matches(time.weekday().num_days_from_sunday());
+        let day = match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.months.matches(time.month())
+    }
+
+    /// First time of the schedule strictly after `after`, none when
+    /// nothing matches within five years (e.g. February 30th)
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        // Stepping in UTC, where every minute exists once whatever the
+        // daylight saving time does to the local midnights
+        let mut time = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = after + Duration::days(5 * 366);
+        while time < limit {
+            let local = time.with_timezone(&Local);
+            if !self.matches_day(&local) {
+                // Close to the next day, an hour short in case the
+                // local time shifts meanwhile
+                let left = i64::from(24 * 60 - local.hour() * 60 - local.minute());
+                time += Duration::minutes((left - 60).max(1));
+                continue;
+            }
+            if self.hours.matches(local.hour()) && self.minutes.matches(local.minute()) {
+                return Some(time);
+            }
+            time += Duration::minutes(1);
+        }
+        None
+    }
+}
+
+/// When the last scheduled sync started, to catch up on the one
+/// missed while the daemon was down
+#[derive(Serialize, Deserialize, Default)]
+struct ScheduleState {
+    last_run: Option<DateTime<Utc>>,
+}
+
+impl Persisted for ScheduleState {
+    const FILE_NAME: &'static str = "schedule.json";
+}
+
+/// Time of the next scheduled sync: now when one was missed since
+/// the last run, so that a single sync catches up after a downtime
+pub fn next_run(schedule: &Schedule) -> Result<Option<DateTime<Utc>>> {
+    let now = Utc::now();
+    let state: ScheduleState = state::load()?;
+    if let Some(last_run) = state.last_run
+        && let Some(missed) = schedule.next_after(last_run)
+        && missed <= now
+    {
+        return Ok(Some(now));
+    }
+    Ok(schedule.next_after(now))
+}
+
+pub fn record_run() -> Result<()> {
+    state::save(&ScheduleState {
+        last_run: Some(Utc::now()),
+    })
+}
+
+/// Time to wait for the next scheduled sync
+pub fn until_next(schedule: &Schedule) -> Result<std::time::Duration> {
+    match next_run(schedule)? {
+        Some(x) => Ok((x - Utc::now()).to_std().unwrap_or_default()),
+        None => Err(ConfigError("The schedule never matches".to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn schedule(s: &str) -> Schedule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let x = schedule("*/15 1-3,5 * * 7");
+        assert!(x.minutes.matches(0) && x.minutes.matches(45) && !x.minutes.matches(10));
+        assert!(x.hours.matches(2) && x.hours.matches(5) && !x.hours.matches(4));
+        assert!(x.days.any && !x.weekdays.any);
+        // Sunday is either 0 or 7
+        assert!(x.weekdays.matches(0));
+        assert!(schedule("5/20 * * * *").minutes.matches(45));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for s in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+        ] {
+            assert!(s.parse::<Schedule>().is_err(), "{}", s);
+        }
+        for s in ["*/0 * * * *", "5-1 * * * *", "a * * * *", "* * * 13 *"] {
+            assert!(s.parse::<Schedule>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn finds_the_next_minute() {
+        let after = Utc.with_ymd_and_hms(2024, 5, 1, 10, 7, 30).unwrap();
+        let next = schedule("30 * * * *").next_after(after).unwrap();
+        assert!(next > after && next - after <= Duration::hours(1));
+        assert_eq!(next.with_timezone(&Local).minute(), 30);
+        assert_eq!(next.second(), 0);
+
+        // Strictly after
+        let at = schedule("* * * * *").next_after(next).unwrap();
+        assert_eq!(at - next, Duration::minutes(1));
+    }
+
+    #[test]
+    fn finds_the_next_day() {
+        let after = Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        let next = schedule("0 0 * * 1").next_after(after).unwrap();
+        let local = next.with_timezone(&Local);
+        assert_eq!((local.hour(), local.minute()), (0, 0));
+        assert_eq!(local.weekday().num_days_from_sunday(), 1);
+        assert!(next - after <= Duration::days(8));
+    }
+
+    #[test]
+    fn finds_midnight_across_the_transitions() {
+        // Every local midnight of a year, whatever the time zone does
+        let mut time = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let x = schedule("0 0 * * *");
+        for _ in 0..366 {
+            let next = x.next_after(time).unwrap();
+            let local = next.with_timezone(&Local);
+            assert_eq!((local.hour(), local.minute()), (0, 0));
+            assert!(next > time);
+            time = next;
+        }
+    }
+
+    #[test]
+    fn never_matching() {
+        let after = Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        assert!(schedule("0 0 30 2 *").next_after(after).is_none());
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::path::Path;
+
+use crate::catalog::{SeasonInfo, SeriesInfo};
+
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub enum Decision {
+    Keep,
+    Veto,
+    Confidence(f32),
+}
+
+/// What the script knows about the MAL entry being matched
+pub struct EntryInfo<'a> {
+    pub mal_id: u32,
+    pub title: &'a str,
+    pub start_date: Option<DateTime<Utc>>,
+}
+
+/// User script called on every heuristic match through its
+/// `decide(entry, candidate)` function. Returning `false` vetoes
+/// the match, a number replaces its confidence, anything else keeps it.
+#[cfg(feature = "scripting")]
+pub struct MatchScript {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+}
+
+#[cfg(feature = "scripting")]
+impl MatchScript {
+    pub fn load(path: &Path) -> Result<Self> {
+        let engine = rhai::Engine::new();
+        let ast = engine.compile_file(path.to_path_buf())?;
+        Ok(Self { engine, ast })
+    }
+
+    pub fn decide(
+        &self,
+        entry: &EntryInfo,
+        series: &SeriesInfo,
+        season: &SeasonInfo,
+        confidence: f32,
+    ) -> Result<Decision> {
+        let mut mal = rhai::Map::new();
+        mal.insert("id".into(), (entry.mal_id as i64).into());
+        mal.insert("title".into(), entry.title.into());
+        mal.insert(
+            "start_date".into(),
+            match entry.start_date {
+                Some(x) => x.format("%Y-%m-%d").to_string().into(),
+                None => rhai::Dynamic::UNIT,
+            },
+        );
+
+        let mut candidate = rhai::Map::new();
+        candidate.insert("series_id".into(), series.id.clone().into());
+        candidate.insert("series_title".into(), series.title.clone().into());
+        candidate.insert("season_id".into(), season.id.clone().into());
+        candidate.insert("season_title".into(), season.title.clone().into());
+        candidate.insert("season_number".into(), (season.season_number as i64).into());
+        candidate.insert("episodes".into(), (season.number_of_episodes as i64).into());
+        candidate.insert("confidence".into(), (confidence as f64).into());
+
+        let res: rhai::Dynamic = self.engine.call_fn(
+            &mut rhai::Scope::new(),
+            &self.ast,
+            "decide",
+            (mal, candidate),
+        )?;
+
+        if let Ok(x) = res.as_bool() {
+            return Ok(if x { Decision::Keep } else { Decision::Veto });
+        }
+        if let Ok(x) = res.as_float() {
+            return Ok(Decision::Confidence(x as f32));
+        }
+        if let Ok(x) = res.as_int() {
+            return Ok(Decision::Confidence(x as f32));
+        }
+        Ok(Decision::Keep)
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+pub struct MatchScript;
+
+#[cfg(not(feature = "scripting"))]
+impl MatchScript {
+    pub fn load(_path: &Path) -> Result<Self> {
+        anyhow::bail!("Match scripts require building with the 'scripting' feature")
+    }
+
+    pub fn decide(
+        &self,
+        _entry: &EntryInfo,
+        _series: &SeriesInfo,
+        _season: &SeasonInfo,
+        _confidence: f32,
+    ) -> Result<Decision> {
+        Ok(Decision::Keep)
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use crunchyroll_rs::Crunchyroll;
+use crunchyroll_rs::common::StreamExt;
+use serde::Serialize;
+use std::fmt::Write;
+
+use crate::profile;
+
+const MAX_RESULTS: usize = 5;
+
+#[derive(Serialize)]
+struct SeasonResult {
+    id: String,
+    title: String,
+    season_number: u32,
+    episodes: u32,
+    audio_locales: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SeriesResult {
+    id: String,
+    title: String,
+    audio_locales: Vec<String>,
+    seasons: Vec<SeasonResult>,
+}
+
+#[derive(Serialize)]
+struct MovieListingResult {
+    id: String,
+    title: String,
+    movies: Vec<(String, String)>,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    series: Vec<SeriesResult>,
+    movie_listings: Vec<MovieListingResult>,
+}
+
+/// Dumps what Crunchyroll returns for a search, to help writing mappings
+pub async fn search(crunchyroll: &Crunchyroll, query: &str, json: bool) -> Result<String> {
+    let mut query_result = crunchyroll.query(query);
+    let mut output = SearchResult {
+        series: vec![],
+        movie_listings: vec![],
+    };
+
+    while output.series.len() < MAX_RESULTS {
+        let Some(s) = profile::timed("search", query_result.series.next()).await else {
+            break;
+        };
+        let series = s?;
+        let mut seasons = vec![];
+        for season in profile::timed("seasons", series.seasons()).await? {
+            seasons.push(SeasonResult {
+                id: season.id,
+                title: season.title,
+                season_number: season.season_number,
+                episodes: season.number_of_episodes,
+                audio_locales: season.audio_locales.iter().map(|x| x.to_string()).collect(),
+            });
+        }
+        output.series.push(SeriesResult {
+            id: series.id,
+            title: series.title,
+            audio_locales: series.audio_locales.iter().map(|x| x.to_string()).collect(),
+            seasons,
+        });
+    }
+
+    while output.movie_listings.len() < MAX_RESULTS {
+        let Some(s) = profile::timed("search", query_result.movie_listing.next()).await else {
+            break;
+        };
+        let listing = s?;
+        let movies = profile::timed("seasons", listing.movies())
+            .await?
+            .into_iter()
+            .map(|x| (x.id, x.title))
+            .collect();
+        output.movie_listings.push(MovieListingResult {
+            id: listing.id,
+            title: listing.title,
+            movies,
+        });
+    }
+
+    if json {
+        return Ok(serde_json::to_string_pretty(&output)? + "\n");
+    }
+
+    let mut out = String::new();
+
+    for series in output.series {
+        writeln!(
+            out,
+            "Series {:<12} {} [{}]",
+            series.id,
+            series.title,
+            series.audio_locales.join(", ")
+        )?;
+        for season in series.seasons {
+            writeln!(
+                out,
+                "  S{:<3} {:<12} {:>4} ep. {} [{}]",
+                season.season_number,
+                season.id,
+                season.episodes,
+                season.title,
+                season.audio_locales.join(", ")
+            )?;
+        }
+    }
+    for listing in output.movie_listings {
+        writeln!(out, "Movies {:<12} {}", listing.id, listing.title)?;
+        for (id, title) in listing.movies {
+            writeln!(out, "       {:<12} {}", id, title)?;
+        }
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::history::RunStats;
+use crate::hooks::HookEntry;
+use crate::marker::Marker;
+use crate::matcher::{MalEntry, MatchOutcome, Matcher, audio_for, available_version};
+use crate::sync::contents_to_mark;
+use crate::{error, info, output, success, t, warn};
+
+/// Progress read from a source other than MAL
+#[derive(Debug, Clone)]
+pub struct WatchEntry {
+    pub title: String,
+    /// 1 when the source doesn't tell
+    pub season: Option<usize>,
+    /// Number of episodes watched
+    pub episodes: u32,
+    pub last_watched: Option<DateTime<Utc>>,
+}
+
+/// Parses the common date formats, the time of day is dropped
+pub fn parse_date(x: &str) -> Option<DateTime<Utc>> {
+    if let Ok(x) = DateTime::parse_from_rfc3339(x) {
+        return Some(x.to_utc());
+    }
+    ["%m/%d/%y", "%d/%m/%Y", "%Y-%m-%d", "%d.%m.%y"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(x, format).ok())
+        .map(|x| x.and_time(Default::default()).and_utc())
+}
+
+/// Columns (or JSON keys) holding the fields of a `WatchEntry`,
+/// written as `title=col1,episodes=col3,date=col4`
+#[derive(Debug, Clone)]
+pub struct ColumnMap {
+    pub title: String,
+    pub episodes: String,
+    pub season: Option<String>,
+    pub date: Option<String>,
+}
+
+impl Default for ColumnMap {
+    fn default() -> Self {
+        Self {
+            title: "title".to_string(),
+            episodes: "episodes".to_string(),
+            season: None,
+            date: None,
+        }
+    }
+}
+
+impl FromStr for ColumnMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut output = Self::default();
+        for pair in s.split(',') {
+            let Some((field, column)) = pair.split_once('=') else {
+                return Err(format!("expected field=column, got '{}'", pair));
+            };
+            let column = column.trim().to_string();
+            match field.trim() {
+                "title" => output.title = column,
+                "episodes" => output.episodes = column,
+                "season" => output.season = Some(column),
+                "date" => output.date = Some(column),
+                x => return Err(format!("unknown field '{}'", x)),
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl ColumnMap {
+    /// Builds an entry from the values of a row, keyed by column
+    fn entry(&self, row: &HashMap<String, String>) -> Result<WatchEntry> {
+        let get = |column: &str| {
+            row.get(column)
+                .with_context(|| format!("Missing column '{}'", column))
+        };
+        let title = get(&self.title)?.clone();
+        let episodes = get(&self.episodes)?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid episode count for '{}'", title))?;
+        let season = match &self.season {
+            Some(x) => Some(get(x)?.trim().parse()?),
+            None => None,
+        };
+        let last_watched = match &self.date {
+            Some(x) => parse_date(get(x)?.trim()),
+            None => None,
+        };
+        Ok(WatchEntry {
+            title,
+            season,
+            episodes,
+            last_watched,
+        })
+    }
+
+    /// Reads a CSV file with a header line
+    pub fn read_csv(&self, path: &Path) -> Result<Vec<WatchEntry>> {
+        let mut reader = csv::Reader::from_path(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        let mut output = vec![];
+        for row in reader.deserialize() {
+            let row: HashMap<String, String> = row?;
+            output.push(self.entry(&row)?);
+        }
+        Ok(output)
+    }
+
+    /// Reads a JSON array of objects
+    pub fn read_json(&self, path: &Path) -> Result<Vec<WatchEntry>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        let Value::Array(rows) = serde_json::from_str(&content)? else {
+            bail!("{} doesn't hold a JSON array", path.display());
+        };
+
+        let mut output = vec![];
+        for row in rows {
+            let Value::Object(row) = row else {
+                bail!("{} holds something else than objects", path.display());
+            };
+            let row: HashMap<String, String> = row
+                .into_iter()
+                .map(|(k, v)| match v {
+                    Value::String(x) => (k, x),
+                    x => (k, x.to_string()),
+                })
+                .collect();
+            output.push(self.entry(&row)?);
+        }
+        Ok(output)
+    }
+}
+
+/// Marks on Crunchyroll the progress read from another source
+pub async fn mark_entries(
+    catalog: &Catalog<'_>,
+    config: &Config,
+    marker: &mut Marker<'_>,
+    entries: Vec<WatchEntry>,
+    fail_fast: bool,
+) -> Result<RunStats> {
+    let mut matcher = Matcher::new(catalog, config).await?;
+    let mut treated_ids = HashSet::<String>::new();
+    info!("{}", t!("elements-read", count = entries.len()));
+    let mut stats = RunStats::default();
+
+    for watched in entries {
+        let mut entry = MalEntry::from_title(&watched.title);
+        entry.season_number = Some(watched.season.unwrap_or(1));
+
+        info!("{}", t!("querying", title = entry.title.as_str()));
+        let season = match matcher.find(&entry, &treated_ids).await? {
+            MatchOutcome::Matched { season, .. } => {
+                treated_ids.insert(season.id.clone());
+                season
+            }
+            MatchOutcome::Unmatched { reason, .. } => {
+                stats.unmatched += 1;
+                warn!(
+                    "{}",
+                    t!(
+                        "unmatched",
+                        title = entry.title.as_str(),
+                        reason = reason.name()
+                    )
+                );
+                continue;
+            }
+        };
+        stats.matched += 1;
+        success!("{}", t!("found", season = season.title.as_str()));
+
+        let hook_entry = HookEntry {
+            mal_id: 0,
+            title: &entry.title,
+        };
+        let season = available_version(catalog, season, &config.preferred_audio).await?;
+        marker.set_audio(audio_for(&season, &config.preferred_audio));
+        for content_id in contents_to_mark(catalog, &season, watched.episodes, 0).await? {
+            match marker.mark(&hook_entry, &content_id).await {
+                Ok(()) => stats.marked += 1,
+                Err(e) if fail_fast => return Err(e.context(entry.title.clone())),
+                Err(e) => {
+                    stats.failures += 1;
+                    error!("{}: {:#}", &entry.title, e);
+                }
+            }
+        }
+    }
+
+    output::summary(format_args!(
+        "{}",
+        t!(
+            "run-summary",
+            matched = stats.matched,
+            marked = stats.marked,
+            unmatched = stats.unmatched,
+            failures = stats.failures
+        )
+    ));
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_column_maps() {
+        let x: ColumnMap = "title=Name, episodes=Ep,date= Watched".parse().unwrap();
+        assert_eq!(x.title, "Name");
+        assert_eq!(x.episodes, "Ep");
+        assert_eq!(x.season, None);
+        assert_eq!(x.date.as_deref(), Some("Watched"));
+
+        // The fields left out keep their default column
+        let x: ColumnMap = "season=S".parse().unwrap();
+        assert_eq!(x.title, "title");
+        assert_eq!(x.episodes, "episodes");
+        assert_eq!(x.season.as_deref(), Some("S"));
+    }
+
+    #[test]
+    fn rejects_invalid_column_maps() {
+        assert!("title".parse::<ColumnMap>().is_err());
+        assert!("title=a,rating=b".parse::<ColumnMap>().is_err());
+        assert!("".parse::<ColumnMap>().is_err());
+    }
+}
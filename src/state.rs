@@ -0,0 +1,311 @@
+use anyhow::{Context, Result, bail};
+use directories::ProjectDirs;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::warn;
+
+const LOCK_FILE: &str = "mal-2-crunchyroll.lock";
+const CONFIG_FILE: &str = "config.toml";
+
+/// Paths given on the command line, taking precedence over
+/// the environment and the platform directories
+#[derive(Default, Debug)]
+pub struct PathOverrides {
+    pub config: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+}
+
+static OVERRIDES: OnceLock<PathOverrides> = OnceLock::new();
+
+pub fn set_overrides(overrides: PathOverrides) {
+    let _ = OVERRIDES.set(overrides);
+}
+
+fn overrides() -> &'static PathOverrides {
+    OVERRIDES.get_or_init(PathOverrides::default)
+}
+
+/// ~/.config/mal-2-crunchyroll, ~/.local/share/... and ~/.cache/... on Linux
+fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("", "", "mal-2-crunchyroll")
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    env::var(name)
+        .ok()
+        .filter(|x| x.len() > 0)
+        .map(PathBuf::from)
+}
+
+/// State directory set explicitly, or the working directory when
+/// it already holds the state of a version predating the platform
+/// directories
+fn explicit_state_dir() -> Option<PathBuf> {
+    overrides()
+        .state_dir
+        .clone()
+        .or_else(|| env_path("STATE_DIR"))
+        .or_else(|| Path::new(LOCK_FILE).exists().then(|| PathBuf::from(".")))
+}
+
+/// config.toml given by --config or CONFIG_FILE, then the one of the
+/// working directory, then the one of the platform config directory
+pub fn config_file() -> PathBuf {
+    if let Some(x) = overrides()
+        .config
+        .clone()
+        .or_else(|| env_path("CONFIG_FILE"))
+    {
+        return x;
+    }
+    match project_dirs() {
+        Some(dirs) if !Path::new(CONFIG_FILE).exists() => dirs.config_dir().join(CONFIG_FILE),
+        _ => PathBuf::from(CONFIG_FILE),
+    }
+}
+
+/// Credentials file read next to the platform config.toml
+pub fn env_file() -> Option<PathBuf> {
+    project_dirs().map(|x| x.config_dir().join(".env"))
+}
+
+/// Caches that can be fetched again, the state directory
+/// when it is set explicitly
+pub fn cache_dir() -> Result<PathBuf> {
+    let dir = match overrides()
+        .cache_dir
+        .clone()
+        .or_else(|| env_path("CACHE_DIR"))
+    {
+        Some(x) => x,
+        None => match (explicit_state_dir(), project_dirs()) {
+            (None, Some(dirs)) => dirs.cache_dir().to_path_buf(),
+            _ => return state_dir(),
+        },
+    };
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+pub fn state_dir() -> Result<PathBuf> {
+    let dir = match (explicit_state_dir(), project_dirs()) {
+        (Some(x), _) => x,
+        (None, Some(dirs)) => dirs.data_local_dir().to_path_buf(),
+        (None, None) => PathBuf::from("."),
+    };
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Exclusive lock on the state directory, released when dropped.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    pub fn acquire(force: bool) -> Result<Self> {
+        let path = state_dir()?.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => (),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                if !force {
+                    bail!(
+                        "Another instance is already running (lock held on {}), use --force to override",
+                        path.display()
+                    );
+                }
+                warn!("Another instance holds {}, running anyway", path.display());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// A piece of state stored as a versioned JSON file in the state directory.
+pub trait Persisted: Serialize + DeserializeOwned + Default {
+    const FILE_NAME: &'static str;
+    const VERSION: u32 = 1;
+    /// Kept in the cache directory rather than the state directory
+    const CACHE: bool = false;
+
+    fn dir() -> Result<PathBuf> {
+        match Self::CACHE {
+            true => cache_dir(),
+            false => state_dir(),
+        }
+    }
+
+    /// Upgrades data written with schema version `from` to version `from + 1`
+    fn migrate(from: u32, _data: Value) -> Result<Value> {
+        bail!("No migration from version {} of {}", from, Self::FILE_NAME)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+struct RawEnvelope {
+    version: u32,
+    data: Value,
+}
+
+pub fn load<T: Persisted>() -> Result<T> {
+    let path = T::dir()?.join(T::FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let raw: RawEnvelope = serde_json::from_str(&content)
+        .with_context(|| format!("Corrupted state file {}", path.display()))?;
+    if raw.version > T::VERSION {
+        bail!(
+            "{} was written by a newer version (schema {} > {})",
+            path.display(),
+            raw.version,
+            T::VERSION
+        );
+    }
+
+    let mut data = raw.data;
+    for version in raw.version..T::VERSION {
+        data = T::migrate(version, data)?;
+    }
+
+    Ok(serde_json::from_value(data)?)
+}
+
+/// Writes the state through a temporary file and a rename, both
+/// flushed to the disk, so a crash mid-write leaves the previous
+/// version intact.
+pub fn save<T: Persisted>(value: &T) -> Result<()> {
+    let dir = T::dir()?;
+    let path = dir.join(T::FILE_NAME);
+    let tmp_path = dir.join(format!("{}.tmp", T::FILE_NAME));
+
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(
+        &mut file,
+        &Envelope {
+            version: T::VERSION,
+            data: value,
+        },
+    )?;
+    file.flush()?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, &path)?;
+    // The rename itself only lasts once the directory is synced
+    #[cfg(unix)]
+    File::open(&dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Points the state directory to a fresh one, shared by the tests
+    fn test_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("mal-2-crunchyroll-{}", std::process::id()));
+        set_overrides(PathOverrides {
+            state_dir: Some(dir.clone()),
+            ..Default::default()
+        });
+        // Never the real one, should anything have read it before
+        assert_eq!(overrides().state_dir.as_ref(), Some(&dir));
+        state_dir().unwrap()
+    }
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct Counter {
+        count: u32,
+        names: Vec<String>,
+    }
+
+    impl Persisted for Counter {
+        const FILE_NAME: &'static str = "test-counter.json";
+    }
+
+    /// `count` renamed to `total` in the second version
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct Total {
+        total: u32,
+    }
+
+    impl Persisted for Total {
+        const FILE_NAME: &'static str = "test-total.json";
+        const VERSION: u32 = 2;
+
+        fn migrate(from: u32, data: Value) -> Result<Value> {
+            match from {
+                1 => Ok(json!({ "total": data["count"] })),
+                _ => bail!("No migration from version {}", from),
+            }
+        }
+    }
+
+    #[test]
+    fn locks_the_state_directory() {
+        test_dir();
+        let lock = Lock::acquire(false).unwrap();
+        assert!(Lock::acquire(false).is_err());
+        let forced = Lock::acquire(true).unwrap();
+        drop(forced);
+        assert!(Lock::acquire(false).is_err());
+
+        drop(lock);
+        assert!(Lock::acquire(false).is_ok());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = test_dir();
+        assert_eq!(load::<Counter>().unwrap(), Counter::default());
+
+        let counter = Counter {
+            count: 3,
+            names: vec!["a".to_string(), "b".to_string()],
+        };
+        save(&counter).unwrap();
+        assert_eq!(load::<Counter>().unwrap(), counter);
+        assert!(!dir.join("test-counter.json.tmp").exists());
+    }
+
+    #[test]
+    fn migrates_older_versions() {
+        let dir = test_dir();
+        let older = json!({ "version": 1, "data": { "count": 3 } });
+        fs::write(dir.join(Total::FILE_NAME), older.to_string()).unwrap();
+        assert_eq!(load::<Total>().unwrap(), Total { total: 3 });
+
+        let newer = json!({ "version": 3, "data": { "total": 3 } });
+        fs::write(dir.join(Total::FILE_NAME), newer.to_string()).unwrap();
+        assert!(load::<Total>().is_err());
+    }
+}
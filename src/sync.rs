@@ -0,0 +1,1007 @@
+use anyhow::{Result, bail};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::ValueEnum;
+use futures::{FutureExt, Stream, StreamExt, stream};
+use mal_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::actions::ActionKind;
+use crate::cassette::Cassette;
+use crate::catalog::{Catalog, EpisodeInfo, SeasonInfo};
+use crate::config::Config;
+use crate::crunchylist;
+use crate::exclude::ExcludeList;
+use crate::franchise::Franchises;
+use crate::hooks::{HookEntry, Hooks};
+use crate::http;
+use crate::mal::{self, AiringStatus};
+use crate::marker::Marker;
+use crate::matcher::{
+    FailureReason, MatchOutcome, Matcher, audio_for, available_version, get_node_title,
+};
+use crate::plan::{Plan, PlanEntry};
+use crate::profile;
+use crate::state::{self, Persisted};
+use crate::watchlist::Watchlist;
+use crate::{debug, info, warn};
+
+/// Content IDs to mark: the whole season when every episode
+/// has been watched, each watched episode otherwise.
+/// An episode 0 counts as the first MAL episode when the season
+/// only has the `mal_episodes` of the entry (0 if unknown) with it,
+/// it is a prologue MAL doesn't list otherwise.
+/// Number of the episode preceding the season: later seasons of
+/// long-running shows keep counting from the previous ones, e.g.
+/// from 62
+pub fn numbering_offset(episodes: &[EpisodeInfo]) -> u32 {
+    episodes
+        .iter()
+        .filter_map(|x| x.episode_number)
+        .filter(|x| *x > 0)
+        .min()
+        .map_or(0, |x| x - 1)
+}
+
+pub async fn contents_to_mark(
+    catalog: &Catalog<'_>,
+    season: &SeasonInfo,
+    watched: u32,
+    mal_episodes: u32,
+) -> Result<Vec<String>> {
+    if watched == season.number_of_episodes {
+        return Ok(vec![season.id.clone()]);
+    }
+
+    let episodes = catalog.episodes(&season.id).await?;
+    let offset = numbering_offset(&episodes);
+    let numbered = episodes
+        .iter()
+        .filter(|x| x.episode_number.is_some_and(|x| x > 0))
+        .count() as u32;
+    let has_prologue = episodes.iter().any(|x| x.episode_number == Some(0));
+    let prologue_counts = has_prologue && mal_episodes > 0 && numbered + 1 == mal_episodes;
+    if has_prologue {
+        debug!(
+            "Episode 0 of {} {} as the first MAL episode",
+            &season.title,
+            if prologue_counts {
+                "counted"
+            } else {
+                "not counted"
+            }
+        );
+    }
+
+    let mut output = vec![];
+    for episode in episodes {
+        if let Some(episode_number) = episode.episode_number {
+            // Position of the episode in the MAL numbering
+            let position = match (episode_number, prologue_counts) {
+                (0, false) => continue,
+                (0, true) => 1,
+                (x, true) => x - offset + 1,
+                (x, false) => x - offset,
+            };
+            if position > watched {
+                continue;
+            }
+        }
+        output.push(episode.id);
+    }
+    Ok(output)
+}
+
+/// Seasons to mark with their watched episode count. Long-running
+/// shows, a single MAL entry split into many Crunchyroll seasons,
+/// continue on the next seasons (in the same audio) until the
+/// watched count is reached.
+async fn seasons_to_mark(
+    catalog: &Catalog<'_>,
+    season: SeasonInfo,
+    watched: u32,
+    audio: &[String],
+) -> Result<Vec<(SeasonInfo, u32)>> {
+    if season.number_of_episodes == 0 || watched <= season.number_of_episodes {
+        return Ok(vec![(season, watched)]);
+    }
+    let mut following: Vec<SeasonInfo> = catalog
+        .seasons(&season.series_id)
+        .await?
+        .into_iter()
+        .filter(|x| {
+            x.season_number > season.season_number
+                && audio_for(x, audio) == audio_for(&season, audio)
+        })
+        .collect();
+    following.sort_by_key(|x| x.season_number);
+    following.dedup_by_key(|x| x.season_number);
+
+    let mut remaining = watched - season.number_of_episodes;
+    let mut output = vec![(season.clone(), season.number_of_episodes)];
+    for next in following {
+        if remaining == 0 || next.number_of_episodes == 0 {
+            break;
+        }
+        let count = remaining.min(next.number_of_episodes);
+        remaining -= count;
+        output.push((next, count));
+    }
+    if remaining > 0 {
+        warn!(
+            "{} watched episodes of '{}' are beyond its last season",
+            remaining, &season.title
+        );
+    }
+    Ok(output)
+}
+
+/// Lower bound on the last update of the entries to process
+#[derive(Debug, Clone)]
+pub enum Since {
+    Date(DateTime<Utc>),
+    /// End of the last online sync
+    LastRun,
+}
+
+impl FromStr for Since {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "last-run" {
+            return Ok(Self::LastRun);
+        }
+        if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Self::Date(date.to_utc()));
+        }
+        match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            Ok(date) => Ok(Self::Date(date.and_time(Default::default()).and_utc())),
+            Err(_) => Err(format!(
+                "expected 'last-run' or a YYYY-MM-DD date, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// When the last online sync of the whole list started, the entries
+/// updated during it being processed again by the next one
+#[derive(Serialize, Deserialize, Default)]
+struct LastRun {
+    #[serde(alias = "finished_at")]
+    started_at: Option<DateTime<Utc>>,
+}
+
+impl Persisted for LastRun {
+    const FILE_NAME: &'static str = "last-run.json";
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MarkedProgress {
+    /// First season marked, the contents being meaningless once
+    /// the entry is matched with another one
+    season_id: String,
+    /// Contents actually marked, those Crunchyroll didn't have yet
+    /// being marked by a later sync
+    #[serde(default)]
+    content_ids: BTreeSet<String>,
+}
+
+/// Contents marked for every entry by the previous syncs, so that
+/// only the episodes watched since get marked
+#[derive(Serialize, Deserialize, Default)]
+struct MarkedSnapshot {
+    entries: BTreeMap<u32, MarkedProgress>,
+}
+
+impl Persisted for MarkedSnapshot {
+    const FILE_NAME: &'static str = "marked-snapshot.json";
+}
+
+/// Entries processed by the syncs cut short by their API budget
+#[derive(Serialize, Deserialize, Default)]
+struct BudgetCheckpoint {
+    processed: BTreeSet<u32>,
+}
+
+impl Persisted for BudgetCheckpoint {
+    const FILE_NAME: &'static str = "budget-checkpoint.json";
+}
+
+impl MarkedSnapshot {
+    /// Contents already marked for the entry matched with `season_id`
+    fn marked(&self, mal_id: u32, season_id: &str) -> BTreeSet<String> {
+        self.entries
+            .get(&mal_id)
+            .filter(|x| x.season_id == season_id)
+            .map(|x| x.content_ids.clone())
+            .unwrap_or_default()
+    }
+}
+
+fn updated_since(elt: &AnimeListNode, since: DateTime<Utc>) -> bool {
+    let Some(status) = elt.list_status.as_ref() else {
+        return false;
+    };
+    match DateTime::parse_from_rfc3339(&status.updated_at) {
+        Ok(x) => x.to_utc() > since,
+        // Better processed for nothing than skipped
+        Err(_) => true,
+    }
+}
+
+/// What a sync should do besides marking
+#[derive(Default)]
+pub struct SyncOptions {
+    pub add_to_watchlist: bool,
+    pub prune_watchlist: bool,
+    pub unmatched_crunchylist: bool,
+    /// Where offline runs write their marks
+    pub plan: PathBuf,
+    /// Cassette to read the MAL list from
+    pub replay: Option<PathBuf>,
+    /// Number of entries to leave out at the beginning of the list
+    pub skip: usize,
+    /// Maximum number of entries to process
+    pub limit: Option<usize>,
+    /// Only process the entries updated on MAL after that
+    pub since: Option<Since>,
+    /// Leave out the entries MAL rates NSFW
+    pub no_nsfw: bool,
+    /// Only process the entries with one of these MAL tags, when not empty
+    pub tags: Vec<String>,
+    /// Leave out the entries with one of these MAL tags
+    pub exclude_tags: Vec<String>,
+    /// Only process the shows with one of these genres or themes, when not empty
+    pub genres: Vec<String>,
+    /// Leave out the shows with one of these genres or themes
+    pub exclude_genres: Vec<String>,
+    /// Only process the entries scored at least that on MAL
+    pub min_score: Option<u8>,
+    /// Only process the entries scored at most that, unscored included
+    pub max_score: Option<u8>,
+    /// Only process the shows currently airing
+    pub airing_only: bool,
+    /// Only process the shows with one of these statuses, when not empty
+    pub airing_status: Vec<AiringStatus>,
+    pub order: Order,
+    pub seed: u64,
+    /// Follow the MAL prequels to match the Nth series of a
+    /// franchise with the Nth Crunchyroll season
+    pub use_relations: bool,
+    /// Only these MAL entries, fetched one by one and
+    /// bypassing the filters, when not empty
+    pub ids: Vec<u32>,
+    /// Abort once that many errors happened
+    pub max_errors: Option<usize>,
+    /// Abort on the first error
+    pub fail_fast: bool,
+    /// Mark every watched episode again, not only the ones
+    /// watched since the last sync
+    pub no_delta: bool,
+    /// Requests after which the sync stops, checked between the
+    /// entries. The next syncs with a budget skip the entries
+    /// processed until the whole list is done.
+    pub api_budget: Option<usize>,
+}
+
+impl SyncOptions {
+    /// Whether the sync goes through the whole list, `--since last-run`
+    /// being the continuation of the previous full sync
+    fn is_full(&self) -> bool {
+        self.skip == 0
+            && self.limit.is_none()
+            && matches!(self.since, None | Some(Since::LastRun))
+            && !self.no_nsfw
+            && self.tags.is_empty()
+            && self.exclude_tags.is_empty()
+            && self.genres.is_empty()
+            && self.exclude_genres.is_empty()
+            && self.min_score.is_none()
+            && self.max_score.is_none()
+            && !self.airing_only
+            && self.airing_status.is_empty()
+            && self.ids.is_empty()
+    }
+}
+
+/// Order in which the entries are processed
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    /// Oldest start date first, so that earlier seasons get matched first
+    #[default]
+    StartDate,
+    /// Most recently updated on MAL first
+    Updated,
+    Title,
+    /// Deterministic shuffle of the given seed
+    Shuffle,
+}
+
+/// Fisher-Yates shuffle driven by a xorshift generator, so a seed
+/// gives the same order on every platform and version
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed.max(1);
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+fn sort_entries(animes: &mut [AnimeListNode], order: Order, seed: u64) {
+    match order {
+        // The list is read in that order
+        Order::StartDate => (),
+        Order::Updated => animes.sort_by_key(|x| {
+            let status = x.list_status.as_ref();
+            Reverse(status.and_then(|s| DateTime::parse_from_rfc3339(&s.updated_at).ok()))
+        }),
+        Order::Title => {
+            animes.sort_by_cached_key(|x| get_node_title(x.node.clone()).to_lowercase())
+        }
+        Order::Shuffle => shuffle(animes, seed),
+    }
+}
+
+/// Whether `values` hold one of `include` (or `include` is empty)
+/// and none of `exclude`, ignoring the case
+fn matches_any(values: &[String], include: &[String], exclude: &[String]) -> bool {
+    let any = |wanted: &[String]| wanted.iter().any(|x| values.contains(&x.to_lowercase()));
+    (include.is_empty() || any(include)) && !any(exclude)
+}
+
+/// Unscored entries (0) never reach a minimum score
+fn in_score_range(elt: &AnimeListNode, min: Option<u8>, max: Option<u8>) -> bool {
+    let score = elt.list_status.as_ref().map_or(0, |x| x.score);
+    min.is_none_or(|x| score >= x) && max.is_none_or(|x| score <= x)
+}
+
+/// Narrows the filtered list down to the entries the options select
+fn select_entries(
+    animes: Vec<AnimeListNode>,
+    options: &SyncOptions,
+    since: Option<DateTime<Utc>>,
+) -> Vec<AnimeListNode> {
+    let today = Local::now().date_naive();
+    let mut animes: Vec<_> = animes
+        .into_iter()
+        .filter(|x| since.is_none_or(|since| updated_since(x, since)))
+        .filter(|x| !options.no_nsfw || !mal::is_nsfw(&x.node))
+        .filter(|x| matches_any(&mal::tags(x), &options.tags, &options.exclude_tags))
+        .filter(|x| {
+            matches_any(
+                &mal::genres(&x.node),
+                &options.genres,
+                &options.exclude_genres,
+            )
+        })
+        .filter(|x| in_score_range(x, options.min_score, options.max_score))
+        .filter(|x| !options.airing_only || mal::is_airing(&x.node, today))
+        .filter(|x| {
+            options.airing_status.is_empty()
+                || options.airing_status.iter().any(|s| s.matches(&x.node))
+        })
+        .collect();
+    sort_entries(&mut animes, options.order, options.seed);
+
+    animes
+        .into_iter()
+        .skip(options.skip)
+        .take(options.limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub enum SyncEvent {
+    EntryStarted {
+        mal_id: u32,
+        title: String,
+        /// Position of the entry among the `total` processed, from 1
+        index: usize,
+        total: usize,
+    },
+    Matched {
+        mal_id: u32,
+        series_id: String,
+        season_id: String,
+        season_title: String,
+        confidence: f32,
+    },
+    Marked {
+        mal_id: u32,
+        content_id: String,
+    },
+    Skipped {
+        mal_id: u32,
+        title: String,
+        reason: FailureReason,
+    },
+    /// Without a MAL ID, the error aborted the sync
+    Failed {
+        mal_id: Option<u32>,
+        error: String,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct MatchReport {
+    pub series_id: String,
+    pub season_id: String,
+    pub season_title: String,
+    pub confidence: f32,
+}
+
+/// Outcome of an entry, printed by `--output jsonl`
+#[derive(Serialize, Debug)]
+pub struct EntryReport {
+    pub mal_id: u32,
+    pub title: String,
+    #[serde(rename = "match")]
+    pub matched: Option<MatchReport>,
+    /// Why the entry wasn't matched
+    pub unmatched: Option<FailureReason>,
+    /// Contents marked
+    pub marked: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl EntryReport {
+    pub fn new(mal_id: u32, title: &str) -> Self {
+        Self {
+            mal_id,
+            title: title.to_string(),
+            matched: None,
+            unmatched: None,
+            marked: vec![],
+            errors: vec![],
+        }
+    }
+
+    /// Adds an event of the entry
+    pub fn observe(&mut self, event: &SyncEvent) {
+        match event {
+            SyncEvent::Matched {
+                series_id,
+                season_id,
+                season_title,
+                confidence,
+                ..
+            } => {
+                self.matched = Some(MatchReport {
+                    series_id: series_id.clone(),
+                    season_id: season_id.clone(),
+                    season_title: season_title.clone(),
+                    confidence: *confidence,
+                })
+            }
+            SyncEvent::Marked { content_id, .. } => self.marked.push(content_id.clone()),
+            SyncEvent::Skipped { reason, .. } => self.unmatched = Some(*reason),
+            SyncEvent::Failed { error, .. } => self.errors.push(error.clone()),
+            SyncEvent::EntryStarted { .. } => (),
+        }
+    }
+}
+
+/// Mirrors the MAL list onto Crunchyroll. Without a marker
+/// (offline), the marks are written to a plan file instead.
+pub struct SyncEngine<'a> {
+    options: SyncOptions,
+    config: &'a Config,
+    catalog: &'a Catalog<'a>,
+    marker: Option<Marker<'a>>,
+    /// Entries to process instead of the MAL list
+    entries: Option<Vec<AnimeListNode>>,
+}
+
+impl<'a> SyncEngine<'a> {
+    pub fn new(
+        options: SyncOptions,
+        config: &'a Config,
+        catalog: &'a Catalog<'a>,
+        marker: Option<Marker<'a>>,
+    ) -> Self {
+        Self {
+            options,
+            config,
+            catalog,
+            marker,
+            entries: None,
+        }
+    }
+
+    /// Processes these entries rather than reading the list,
+    /// still applying the filters
+    pub fn with_entries(mut self, entries: Vec<AnimeListNode>) -> Self {
+        self.entries = Some(entries);
+        self
+    }
+
+    /// Runs the sync, reporting its progress through the returned stream
+    pub fn run(&mut self) -> impl Stream<Item = SyncEvent> + '_ {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let work = async move {
+            if let Err(e) = self.process(&tx).await {
+                let _ = tx.send(SyncEvent::Failed {
+                    mal_id: None,
+                    error: format!("{:#}", e),
+                });
+            }
+        };
+        let events = stream::unfold(rx, |mut rx| async move {
+            let event = rx.recv().await?;
+            Some((event, rx))
+        });
+
+        // The work never yields anything itself, it just has to be
+        // polled along the events until the sender is dropped
+        stream::select(
+            work.into_stream()
+                .filter_map(|()| async { None::<SyncEvent> }),
+            events,
+        )
+    }
+
+    async fn process(&mut self, tx: &UnboundedSender<SyncEvent>) -> Result<()> {
+        let started_at = Utc::now();
+        // Given by `watch`, only the changes of the list
+        let whole_list = self.entries.is_none() && self.options.is_full();
+        let options = &self.options;
+        let catalog = self.catalog;
+        let errors = Cell::new(0);
+        let emit = |event: SyncEvent| {
+            if let SyncEvent::Failed { .. } = event {
+                errors.set(errors.get() + 1);
+            }
+            let _ = tx.send(event);
+        };
+
+        let mut watchlist = match (
+            options.add_to_watchlist || options.prune_watchlist,
+            &self.marker,
+        ) {
+            (true, Some(_)) => Some(Watchlist::fetch(catalog.crunchyroll()?).await?),
+            _ => None,
+        };
+
+        let hooks = Hooks::new(&self.config.hooks);
+        hooks.run_start().await?;
+
+        let mut matcher = Matcher::new(catalog, self.config).await?;
+        let mut treated_ids = HashSet::<String>::new();
+        let mut unmatched_candidates = vec![];
+        let mut plan = Plan::new();
+        let animes = match (self.entries.take(), &options.replay, catalog.is_offline()) {
+            (Some(x), _, _) => x,
+            (None, Some(path), _) => mal::filter_entries(Cassette::read(path)?.mal_list, true),
+            (None, None, true) => mal::read_cached_mal_entries(true)?,
+            (None, None, false) if !options.ids.is_empty() => {
+                mal::read_mal_list_entries(&options.ids).await?
+            }
+            (None, None, false) => mal::read_mal_entries(true, !options.no_nsfw).await?,
+        };
+        // Whether each entry read is completed, a series being done
+        // with only once every entry mapped to it is
+        let is_completed: HashMap<u32, bool> = animes
+            .iter()
+            .map(|x| {
+                let status = x.list_status.as_ref().and_then(|x| x.status.as_ref());
+                (x.node.id, status == Some(&UserAnimeListStatus::Completed))
+            })
+            .collect();
+        let since = match &options.since {
+            Some(Since::Date(x)) => Some(*x),
+            Some(Since::LastRun) => {
+                let last_run: LastRun = state::load()?;
+                if last_run.started_at.is_none() {
+                    warn!("No previous run recorded, processing every entry");
+                }
+                last_run.started_at
+            }
+            None => None,
+        };
+        let animes = match options.ids.is_empty() {
+            true => {
+                let excluded = ExcludeList::load(self.config.filters.exclude_file.as_deref())?;
+                let animes = animes
+                    .into_iter()
+                    .filter(|x| !excluded.contains(x.node.id))
+                    .collect();
+                select_entries(animes, options, since)
+            }
+            // The offline lists hold every entry
+            false => animes
+                .into_iter()
+                .filter(|x| options.ids.contains(&x.node.id))
+                .collect(),
+        };
+        let mut checkpoint = match options.api_budget {
+            Some(_) => state::load()?,
+            None => BudgetCheckpoint::default(),
+        };
+        let animes: Vec<_> = animes
+            .into_iter()
+            .filter(|x| !checkpoint.processed.contains(&x.node.id))
+            .collect();
+        let resumed = !checkpoint.processed.is_empty();
+        if resumed {
+            info!(
+                "Carrying on the previous syncs, {} entries already processed",
+                checkpoint.processed.len()
+            );
+        }
+        let requests_before = profile::request_count();
+        let mut budget_spent = false;
+        let mut snapshot: MarkedSnapshot = state::load()?;
+        let mut franchises = match options.use_relations {
+            true => Some(Franchises::load(
+                catalog.is_offline() && !http::is_replaying(),
+            )?),
+            false => None,
+        };
+        // Requests made ahead would escape the budget
+        if let Some(franchises) = franchises.as_mut()
+            && options.api_budget.is_none()
+        {
+            let ids: Vec<u32> = animes.iter().map(|x| x.node.id).collect();
+            if let Err(e) = franchises.prefetch(&ids).await {
+                warn!("Could not read the relations ahead: {:#}", e);
+            }
+        }
+
+        let total = animes.len();
+        // Entry being processed, not done when the sync fails on it
+        let mut current = None;
+        let res = async {
+            'ENTRIES: for (index, elt) in animes.into_iter().enumerate() {
+                // Something systemic is going on, the rest of the list
+                // would only fail the same way
+                if let Some(max_errors) = options.max_errors
+                    && errors.get() >= max_errors
+                {
+                    // The last entry was done with
+                    current = None;
+                    bail!(
+                        "Aborted after {} errors (--max-errors), {} entries left",
+                        errors.get(),
+                        total - index
+                    );
+                }
+                if let Some(budget) = options.api_budget
+                    && profile::request_count() - requests_before >= budget
+                {
+                    info!(
+                        "API budget of {} requests spent, {} entries left for the next syncs",
+                        budget,
+                        total - index
+                    );
+                    budget_spent = true;
+                    break;
+                }
+                checkpoint.processed.insert(elt.node.id);
+                current = Some(elt.node.id);
+                let (node, status) = (elt.node, elt.list_status);
+                // We can do it, the status-less entries
+                // have been filtered
+                let status = status.unwrap();
+                let nsfw = mal::is_nsfw(&node);
+
+                let mut mal_entry = matcher.entry(node);
+                mal_entry.watch_window = matcher.watch_window(&status);
+                if let Some(franchises) = franchises.as_mut() {
+                    mal_entry.chain = franchises.chain(mal_entry.mal_id).await?;
+                }
+                let (anime_id, title) = (mal_entry.mal_id, mal_entry.title.clone());
+
+                emit(SyncEvent::EntryStarted {
+                    mal_id: anime_id,
+                    title: title.clone(),
+                    index: index + 1,
+                    total,
+                });
+                let (series, season, confidence) =
+                    match matcher.find(&mal_entry, &treated_ids).await? {
+                        MatchOutcome::Matched {
+                            series,
+                            season,
+                            confidence,
+                        } => {
+                            emit(SyncEvent::Matched {
+                                mal_id: anime_id,
+                                series_id: series.id.clone(),
+                                season_id: season.id.clone(),
+                                season_title: season.title.clone(),
+                                confidence,
+                            });
+                            (series, season, confidence)
+                        }
+                        MatchOutcome::Unmatched { candidate, reason } => {
+                            emit(SyncEvent::Skipped {
+                                mal_id: anime_id,
+                                title,
+                                reason,
+                            });
+                            unmatched_candidates.extend(candidate);
+                            continue;
+                        }
+                    };
+
+                let audio = &self.config.preferred_audio;
+                let season = available_version(catalog, season, audio).await?;
+                // Movies and single episodes are watched as a whole,
+                // however Crunchyroll splits them. A one-episode MAL
+                // entry may still match a whole season.
+                let single = mal_entry.media_type == Some(AnimeMediaType::Movie)
+                    || season.number_of_episodes == 1;
+                let first_season_id = season.id.clone();
+                let parts = match single {
+                    true if status.num_episodes_watched >= 1 => {
+                        let count = season.number_of_episodes;
+                        vec![(season, count)]
+                    }
+                    _ => {
+                        seasons_to_mark(catalog, season, status.num_episodes_watched, audio).await?
+                    }
+                };
+                // The MAL episode count only describes single seasons
+                let mal_episodes = match parts.len() {
+                    1 => mal_entry.num_episodes,
+                    _ => 0,
+                };
+                // Marked by the previous syncs, only online since the
+                // plans aren't known to be applied
+                let already = match self.marker.is_some() && !options.no_delta {
+                    true => snapshot.marked(anime_id, &first_season_id),
+                    false => BTreeSet::new(),
+                };
+                let mut marks = vec![];
+                for (season, watched) in parts {
+                    treated_ids.insert(season.id.clone());
+                    let mut content_ids =
+                        contents_to_mark(catalog, &season, watched, mal_episodes).await?;
+                    content_ids.retain(|x| !already.contains(x));
+                    marks.push((season, watched, content_ids));
+                }
+                if !already.is_empty() && marks.iter().all(|(_, _, x)| x.is_empty()) {
+                    debug!("{}: nothing watched since the last sync", &title);
+                }
+
+                let Some(marker) = self.marker.as_mut() else {
+                    for (season, watched, content_ids) in marks {
+                        let justification = format!(
+                            "matched with confidence {:.3}, {} of its {} episodes watched on MAL",
+                            confidence, watched, season.number_of_episodes
+                        );
+                        plan.entries.push(PlanEntry {
+                            mal_id: anime_id,
+                            title: title.clone(),
+                            series_id: series.id.clone(),
+                            season_id: season.id,
+                            season_title: season.title,
+                            content_ids,
+                            nsfw,
+                            confidence: Some(confidence),
+                            justification: Some(justification),
+                        });
+                    }
+                    continue;
+                };
+
+                let entry = HookEntry {
+                    mal_id: anime_id,
+                    title: &title,
+                };
+                let mut fully_marked = true;
+                let mut marked_ids = already;
+                let mut abort = false;
+                'MARKS: for (season, watched, content_ids) in &marks {
+                    fully_marked &= *watched >= season.number_of_episodes;
+                    marker.set_audio(audio_for(season, audio));
+                    for content_id in content_ids {
+                        match marker.mark(&entry, content_id).await {
+                            Ok(()) => {
+                                marked_ids.insert(content_id.clone());
+                                emit(SyncEvent::Marked {
+                                    mal_id: anime_id,
+                                    content_id: content_id.clone(),
+                                })
+                            }
+                            Err(e) => {
+                                fully_marked = false;
+                                emit(SyncEvent::Failed {
+                                    mal_id: Some(anime_id),
+                                    error: format!("{:#}", e),
+                                });
+                                if options.fail_fast {
+                                    abort = true;
+                                    break 'MARKS;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // The failed marks are left out, to be tried again
+                snapshot.entries.insert(
+                    anime_id,
+                    MarkedProgress {
+                        season_id: first_season_id,
+                        content_ids: marked_ids,
+                    },
+                );
+                if abort {
+                    break 'ENTRIES;
+                }
+
+                if let Some(watchlist) = watchlist.as_mut() {
+                    // The entries out of the list don't hold the series
+                    let completed = status.status == Some(UserAnimeListStatus::Completed)
+                        && matcher
+                            .mapped_to_series(&series.id)
+                            .iter()
+                            .all(|x| is_completed.get(x).copied().unwrap_or(true));
+                    let action = if options.prune_watchlist && completed && fully_marked {
+                        Some(ActionKind::WatchlistRemove)
+                    } else if options.add_to_watchlist {
+                        Some(ActionKind::WatchlistAdd)
+                    } else {
+                        None
+                    };
+                    let res = async {
+                        let live_series = catalog.live_series(&series.id).await?;
+                        match action {
+                            Some(ActionKind::WatchlistRemove) => {
+                                watchlist.remove(&live_series).await
+                            }
+                            Some(_) => watchlist.add(&live_series).await,
+                            None => Ok(false),
+                        }
+                    }
+                    .await;
+                    // Only the actual changes are logged
+                    if let (Some(action), Ok(true) | Err(_)) = (action, &res) {
+                        marker
+                            .actions()
+                            .record(action, &series.id, Some(anime_id), &res, None);
+                    }
+                    if let Err(e) = res {
+                        emit(SyncEvent::Failed {
+                            mal_id: Some(anime_id),
+                            error: format!("{:#}", e),
+                        });
+                        if options.fail_fast {
+                            break 'ENTRIES;
+                        }
+                    }
+                }
+            }
+
+            if options.fail_fast && errors.get() > 0 {
+                bail!("Aborted on the first error (--fail-fast)");
+            }
+            anyhow::Ok(())
+        }
+        .await;
+
+        // Saved however the sync ended, the next ones carrying on
+        // where it stopped
+        if let (Err(_), Some(id)) = (&res, current) {
+            checkpoint.processed.remove(&id);
+        }
+        let saved = async {
+            matcher.save_mappings()?;
+            if let Some(franchises) = &franchises {
+                franchises.save()?;
+            }
+            if options.api_budget.is_some() {
+                // Done with the whole list, the next budgeted sync starts over
+                if res.is_ok() && !budget_spent {
+                    checkpoint.processed.clear();
+                }
+                state::save(&checkpoint)?;
+            }
+            match self.marker.is_some() {
+                true => state::save(&snapshot),
+                false => plan.write(&options.plan),
+            }
+        }
+        .await;
+        let res = match res.and(saved) {
+            Ok(()) if self.marker.is_some() => {
+                async {
+                    if options.unmatched_crunchylist {
+                        crunchylist::update_unmatched(catalog, unmatched_candidates).await?;
+                    }
+                    // The entries left out wouldn't be processed by --since last-run
+                    if whole_list && !resumed && !budget_spent {
+                        state::save(&LastRun {
+                            started_at: Some(started_at),
+                        })?;
+                    }
+                    anyhow::Ok(())
+                }
+                .await
+            }
+            res => res,
+        };
+
+        // Once the run is saved, for the hook to find it done,
+        // whether it succeeded or not
+        let end = hooks.run_end(res.is_ok()).await;
+        res?;
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_since() {
+        assert!(matches!("last-run".parse(), Ok(Since::LastRun)));
+        let Ok(Since::Date(x)) = "2024-05-01".parse() else {
+            panic!("expected a date");
+        };
+        assert_eq!(x.to_rfc3339(), "2024-05-01T00:00:00+00:00");
+        let Ok(Since::Date(x)) = "2024-05-01T12:30:00+02:00".parse() else {
+            panic!("expected a date");
+        };
+        assert_eq!(x.to_rfc3339(), "2024-05-01T10:30:00+00:00");
+    }
+
+    #[test]
+    fn rejects_invalid_since() {
+        for s in ["", "yesterday", "2024-13-01", "01/05/2024"] {
+            assert!(s.parse::<Since>().is_err(), "{}", s);
+        }
+    }
+
+    fn entry(id: u32, tags: &[&str]) -> AnimeListNode {
+        serde_json::from_value(serde_json::json!({
+            "node": { "id": id, "title": format!("Anime {}", id) },
+            "list_status": {
+                "status": "watching",
+                "score": 0,
+                "num_episodes_watched": 3,
+                "is_rewatching": false,
+                "updated_at": "2024-05-01T10:00:00+00:00",
+                "tags": tags,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn filters_by_tags() {
+        let animes = || {
+            vec![
+                entry(1, &["Favorite"]),
+                entry(2, &["dropped "]),
+                entry(3, &[]),
+            ]
+        };
+        let ids = |options: &SyncOptions| -> Vec<u32> {
+            let selected = select_entries(animes(), options, None);
+            selected.iter().map(|x| x.node.id).collect()
+        };
+
+        let options = SyncOptions {
+            tags: vec!["favorite".into()],
+            ..Default::default()
+        };
+        assert_eq!(ids(&options), [1]);
+        let options = SyncOptions {
+            exclude_tags: vec!["Dropped".into()],
+            ..Default::default()
+        };
+        assert_eq!(ids(&options), [1, 3]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{env, io, time::Duration};
+
+use crate::warn;
+
+/// Sends a state to the service manager when running as a
+/// `Type=notify` systemd service, does nothing otherwise
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        warn!("Could not notify systemd: {}", e);
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &str, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &str, _state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// Pings the watchdog at half the interval expected by systemd,
+/// when `WatchdogSec=` is set for this process
+pub fn spawn_watchdog() {
+    let Some(usec) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+    else {
+        return;
+    };
+    match env::var("WATCHDOG_PID") {
+        Ok(pid) if pid != std::process::id().to_string() => return,
+        _ => (),
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::history::RunRecord;
+
+#[cfg(feature = "telemetry")]
+mod otlp {
+    use anyhow::Result;
+    use opentelemetry::metrics::{Counter, Histogram};
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::{KeyValue, global};
+    use opentelemetry_otlp::{MetricExporter, SpanExporter};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::env;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    use crate::history::RunRecord;
+
+    const SERVICE_NAME: &str = "mal-2-crunchyroll";
+
+    struct Instruments {
+        requests: Counter<u64>,
+        request_duration: Histogram<f64>,
+        entries: Counter<u64>,
+        runs: Counter<u64>,
+    }
+
+    struct Providers {
+        tracer: SdkTracerProvider,
+        meter: SdkMeterProvider,
+    }
+
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    static PROVIDERS: OnceLock<Providers> = OnceLock::new();
+
+    pub fn init() -> Result<()> {
+        if env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+            return Ok(());
+        }
+        let resource = Resource::builder().with_service_name(SERVICE_NAME).build();
+
+        let tracer = SdkTracerProvider::builder()
+            .with_batch_exporter(SpanExporter::builder().with_tonic().build()?)
+            .with_resource(resource.clone())
+            .build();
+        let meter = SdkMeterProvider::builder()
+            .with_periodic_exporter(MetricExporter::builder().with_tonic().build()?)
+            .with_resource(resource)
+            .build();
+        global::set_meter_provider(meter.clone());
+
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer.tracer(SERVICE_NAME)))
+            .try_init()?;
+
+        let m = global::meter(SERVICE_NAME);
+        let _ = INSTRUMENTS.set(Instruments {
+            requests: m.u64_counter("requests").build(),
+            request_duration: m.f64_histogram("request_duration").with_unit("s").build(),
+            entries: m.u64_counter("entries").build(),
+            runs: m.u64_counter("runs").build(),
+        });
+        let _ = PROVIDERS.set(Providers { tracer, meter });
+        Ok(())
+    }
+
+    pub fn record_request(endpoint: &'static str, duration: Duration) {
+        if let Some(x) = INSTRUMENTS.get() {
+            let attributes = [KeyValue::new("endpoint", endpoint)];
+            x.requests.add(1, &attributes);
+            x.request_duration
+                .record(duration.as_secs_f64(), &attributes);
+        }
+    }
+
+    pub fn record_run(run: &RunRecord) {
+        let Some(x) = INSTRUMENTS.get() else {
+            return;
+        };
+        x.runs
+            .add(1, &[KeyValue::new("exit_code", run.exit_code as i64)]);
+        for (outcome, count) in [
+            ("matched", run.stats.matched),
+            ("marked", run.stats.marked),
+            ("unmatched", run.stats.unmatched),
+            ("failed", run.stats.failures),
+        ] {
+            x.entries
+                .add(count as u64, &[KeyValue::new("outcome", outcome)]);
+        }
+    }
+
+    pub fn shutdown() {
+        if let Some(x) = PROVIDERS.get() {
+            let _ = x.tracer.shutdown();
+            let _ = x.meter.shutdown();
+        }
+    }
+}
+
+/// Starts exporting the request spans and the run counters over
+/// OTLP. Needs the `telemetry` feature and the standard
+/// `OTEL_EXPORTER_OTLP_*` environment variables.
+pub fn init() -> Result<()> {
+    #[cfg(feature = "telemetry")]
+    otlp::init()?;
+    Ok(())
+}
+
+/// Flushes what hasn't been exported yet
+pub fn shutdown() {
+    #[cfg(feature = "telemetry")]
+    otlp::shutdown();
+}
+
+#[cfg_attr(not(feature = "telemetry"), allow(unused_variables))]
+pub fn record_request(endpoint: &'static str, duration: Duration) {
+    #[cfg(feature = "telemetry")]
+    otlp::record_request(endpoint, duration);
+}
+
+#[cfg_attr(not(feature = "telemetry"), allow(unused_variables))]
+pub fn record_run(run: &RunRecord) {
+    #[cfg(feature = "telemetry")]
+    otlp::record_run(run);
+}
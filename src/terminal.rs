@@ -0,0 +1,102 @@
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use mal_2_crunchyroll::output::{Level, Sink};
+use serde::Serialize;
+use std::env;
+use std::fmt::Arguments;
+use std::io::{self, IsTerminal, Write};
+
+/// What is printed on stdout
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// The final summary
+    #[default]
+    Text,
+    /// A JSON object per entry as soon as processed, the summary
+    /// going to stderr with the logs
+    Jsonl,
+}
+
+fn prefix(level: Level) -> &'static str {
+    match level {
+        Level::Info | Level::Success => "",
+        Level::Debug => "[DEBUG] ",
+        Level::Warn => "[WARNING] ",
+        Level::Error => "[ERROR] ",
+    }
+}
+
+fn color(level: Level) -> &'static str {
+    match level {
+        Level::Debug => "\x1b[2m",
+        Level::Info => "",
+        Level::Success => "\x1b[32m",
+        Level::Warn => "\x1b[33m",
+        Level::Error => "\x1b[31m",
+    }
+}
+
+/// Shows the messages on stderr, keeping stdout for the results
+pub struct Terminal {
+    quiet: bool,
+    verbose: bool,
+    color: bool,
+    format: OutputFormat,
+}
+
+impl Terminal {
+    /// Colors are only used on a terminal, and never with `NO_COLOR` set
+    pub fn new(quiet: bool, verbose: bool, no_color: bool, format: OutputFormat) -> Self {
+        Self {
+            quiet,
+            verbose,
+            color: !no_color && env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal(),
+            format,
+        }
+    }
+}
+
+impl Sink for Terminal {
+    fn log(&self, level: Level, args: Arguments) {
+        if self.quiet || (level == Level::Debug && !self.verbose) {
+            return;
+        }
+        match (self.color, level) {
+            (false, _) | (true, Level::Info) => eprintln!("{}{}", prefix(level), args),
+            (true, _) => eprintln!("{}{}{}\x1b[0m", color(level), prefix(level), args),
+        }
+    }
+
+    /// On stdout, unless it is holding the JSON lines
+    fn summary(&self, args: Arguments) {
+        match self.format {
+            OutputFormat::Jsonl => eprintln!("{}", args),
+            OutputFormat::Text => println!("{}", args),
+        }
+    }
+
+    /// On stderr too, only when there is someone to answer
+    fn ask(&self, question: &str, hidden: bool) -> Result<String> {
+        if !io::stdin().is_terminal() {
+            bail!("Cannot ask '{}' without a terminal", question);
+        }
+        eprint!("{} ", question);
+        io::stderr().flush()?;
+        match hidden {
+            true => Ok(rpassword::read_password()?),
+            false => {
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)?;
+                Ok(line)
+            }
+        }
+    }
+}
+
+/// Prints a JSON object on its own line of stdout
+pub fn json_line<T: Serialize>(value: &T) {
+    match serde_json::to_string(value) {
+        Ok(x) => println!("{}", x),
+        Err(e) => eprintln!("[ERROR] Could not serialize the result: {}", e),
+    }
+}
@@ -0,0 +1,21 @@
+/// Romaji reading of the kana of a title, so that titles written in
+/// Japanese can be compared with the romanized ones. Kanji are kept
+/// as they are. Without the `transliteration` feature, titles are
+/// returned unchanged.
+#[cfg(feature = "transliteration")]
+pub fn romanize(title: &str) -> String {
+    use wana_kana::ConvertJapanese;
+
+    let has_kana = title
+        .chars()
+        .any(|c| matches!(c, '\u{3040}'..='\u{309f}' | '\u{30a0}'..='\u{30ff}'));
+    match has_kana {
+        true => title.to_romaji(),
+        false => title.to_string(),
+    }
+}
+
+#[cfg(not(feature = "transliteration"))]
+pub fn romanize(title: &str) -> String {
+    title.to_string()
+}
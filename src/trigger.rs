@@ -0,0 +1,151 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::pause;
+use crate::{info, warn};
+
+/// Largest body accepted, the payloads only hold a few IDs
+const MAX_BODY: usize = 64 * 1024;
+
+/// Largest request line and headers accepted
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Time a client has to send its request and read the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sync asked through `POST /trigger`
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Trigger {
+    /// Entries to sync, the changes of the list when empty
+    pub mal_ids: Vec<u32>,
+    pub mal_id: Option<u32>,
+}
+
+impl Trigger {
+    pub fn ids(&self) -> Vec<u32> {
+        self.mal_ids.iter().copied().chain(self.mal_id).collect()
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream.take((MAX_HEAD + MAX_BODY) as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let (mut token, mut length) = (None, 0);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_lowercase().as_str() {
+            "authorization" => token = value.strip_prefix("Bearer ").map(String::from),
+            "x-trigger-token" => token = Some(value.to_string()),
+            "content-length" => length = value.parse().unwrap_or(0),
+            _ => (),
+        }
+    }
+
+    let mut body = vec![0; length.min(MAX_BODY)];
+    reader.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        token,
+        body,
+    })
+}
+
+/// Answers a request, forwarding the valid triggers
+async fn handle(mut stream: TcpStream, token: &str, triggers: &Sender<Trigger>) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+    let status = match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/trigger") if request.token.as_deref() != Some(token) => "401 Unauthorized",
+        ("POST", "/trigger") => match request.body.is_empty() {
+            true => Ok(Trigger::default()),
+            false => serde_json::from_slice(&request.body),
+        }
+        .map_or("400 Bad Request", |x| match triggers.try_send(x) {
+            Ok(()) => "202 Accepted",
+            Err(_) => "503 Service Unavailable",
+        }),
+        ("POST", "/pause" | "/resume") if request.token.as_deref() != Some(token) => {
+            "401 Unauthorized"
+        }
+        ("POST", "/pause") => {
+            pause::pause();
+            "204 No Content"
+        }
+        ("POST", "/resume") => {
+            pause::resume();
+            "204 No Content"
+        }
+        (_, "/trigger" | "/pause" | "/resume") => "405 Method Not Allowed",
+        _ => "404 Not Found",
+    };
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Accepts the triggers on `addr`, only with the shared `token` given
+/// as a bearer token or in X-Trigger-Token. The body may be empty, or
+/// name the entries to sync: `{"mal_ids": [52991, 5114]}`.
+/// `POST /pause` and `POST /resume`, with the same token, hold and
+/// release the requests of the running sync.
+pub async fn listen(addr: SocketAddr, token: String) -> Result<Receiver<Trigger>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Accepting triggers on http://{}/trigger", addr);
+    // Triggers arriving during a sync wait for it, a few at most
+    let (tx, rx) = mpsc::channel(8);
+    let token = Arc::new(token);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Could not accept a trigger: {}", e);
+                    continue;
+                }
+            };
+            // A slow client only holds its own connection
+            let (token, tx) = (token.clone(), tx.clone());
+            tokio::spawn(async move {
+                match tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, &token, &tx)).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => warn!("Invalid trigger request: {:#}", e),
+                    Err(_) => warn!("Trigger request timed out"),
+                }
+            });
+        }
+    });
+    Ok(rx)
+}
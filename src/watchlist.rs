@@ -0,0 +1,48 @@
+use anyhow::Result;
+use crunchyroll_rs::common::StreamExt;
+use crunchyroll_rs::list::WatchlistOptions;
+use crunchyroll_rs::{Crunchyroll, Series};
+use std::collections::HashSet;
+
+use crate::crunchylist::media_id;
+use crate::{info, profile};
+
+/// Series IDs currently on the account's watchlist
+pub struct Watchlist {
+    ids: HashSet<String>,
+}
+
+impl Watchlist {
+    pub async fn fetch(crunchyroll: &Crunchyroll) -> Result<Self> {
+        let mut ids = HashSet::<String>::new();
+        let mut entries = crunchyroll.watchlist(WatchlistOptions::default());
+        while let Some(entry) = profile::timed("watchlist", entries.next()).await {
+            if let Some(id) = media_id(&entry?.panel) {
+                ids.insert(id.clone());
+            }
+        }
+        Ok(Self { ids })
+    }
+
+    /// Returns whether the watchlist changed
+    pub async fn add(&mut self, series: &Series) -> Result<bool> {
+        if self.ids.contains(&series.id) {
+            return Ok(false);
+        }
+        info!("Adding {} to the watchlist", &series.title);
+        profile::timed("watchlist", series.add_to_watchlist()).await?;
+        self.ids.insert(series.id.clone());
+        Ok(true)
+    }
+
+    /// Returns whether the watchlist changed
+    pub async fn remove(&mut self, series: &Series) -> Result<bool> {
+        if !self.ids.contains(&series.id) {
+            return Ok(false);
+        }
+        info!("Removing {} from the watchlist", &series.title);
+        profile::timed("watchlist", series.remove_from_watchlist()).await?;
+        self.ids.remove(&series.id);
+        Ok(true)
+    }
+}